@@ -1,7 +1,13 @@
+use crate::modules::fetcher::Fetcher;
+use crate::modules::metrics::METRICS;
 use crate::Error;
 use async_trait::async_trait;
 use serde_json::Value;
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::field::Empty;
+use tracing::Instrument;
 
 #[async_trait]
 pub trait AIFunction: Send + Sync {
@@ -31,9 +37,26 @@ impl AIFunctionParameter {
 pub mod get_weather;
 pub mod website_to_md;
 
-pub fn get_all_functions() -> Vec<Box<dyn AIFunction>> {
+// Run a function in its own span, recording how long it took and whether it worked.
+pub async fn execute_function(
+    function: &dyn AIFunction,
+    args: HashMap<String, Value>,
+) -> Result<Value, Error> {
+    let span = tracing::info_span!("tool", tool = function.name(), success = Empty);
+    let started = Instant::now();
+    let result = function.execute(args).instrument(span.clone()).await;
+    span.record("success", result.is_ok());
+    METRICS.tool_execution(function.name(), result.is_ok(), started.elapsed());
+    result
+}
+
+// Names of the functions `get_all_functions` returns, for checking agent rosters.
+pub const FUNCTION_NAMES: &[&str] = &["get_weather", "website_to_md"];
+
+// Every function, with the fetching ones using the configured fetcher.
+pub fn get_all_functions(fetcher: Arc<dyn Fetcher>) -> Vec<Box<dyn AIFunction>> {
     vec![
         Box::new(get_weather::GetWeatherFunction),
-        Box::new(website_to_md::WebsiteToMdFunction),
+        Box::new(website_to_md::WebsiteToMdFunction { fetcher }),
     ]
 }
@@ -1,24 +1,23 @@
 use super::{AIFunction, AIFunctionParameter};
-use crate::{config::EnvConfig, Error};
+use crate::modules::fetcher::Fetcher;
+use crate::Error;
 use async_trait::async_trait;
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
 use serde_json::{json, Value};
 use std::collections::HashMap;
+use std::sync::Arc;
 
 #[derive(Deserialize)]
 struct WebsiteToMdArgs {
     url: String,
+    #[serde(default)]
+    no_cache: bool,
 }
 
-#[derive(Serialize)]
-struct WebsiteToMdResponse {
-    url: String,
-    markdown: String,
-    title: Option<String>,
+pub struct WebsiteToMdFunction {
+    pub fetcher: Arc<dyn Fetcher>,
 }
 
-pub struct WebsiteToMdFunction;
-
 #[async_trait]
 impl AIFunction for WebsiteToMdFunction {
     fn name(&self) -> &'static str {
@@ -26,7 +25,7 @@ impl AIFunction for WebsiteToMdFunction {
     }
 
     fn description(&self) -> &'static str {
-        "Convert a website to markdown format using Firecrawl"
+        "Fetch a web page and convert its main content to markdown, with its title and metadata"
     }
 
     fn parameters(&self) -> HashMap<String, AIFunctionParameter> {
@@ -39,6 +38,14 @@ impl AIFunction for WebsiteToMdFunction {
                 true,
             ),
         );
+        params.insert(
+            "no_cache".to_string(),
+            AIFunctionParameter::new(
+                "boolean",
+                "Fetch the page again even if a recent copy is cached",
+                false,
+            ),
+        );
         params
     }
 
@@ -46,30 +53,11 @@ impl AIFunction for WebsiteToMdFunction {
         let args: WebsiteToMdArgs =
             serde_json::from_value(serde_json::Value::Object(args.into_iter().collect()))?;
 
-        let config = EnvConfig::from_env();
-        let client = firecrawl::FirecrawlApp::new(&config.firecrawl_key).map_err(|e| {
-            Error::from(format!("Failed to create Firecrawl client: {}", e))
-        })?;
-
-        let options = firecrawl::scrape::ScrapeOptions {
-            formats: Some(vec![firecrawl::scrape::ScrapeFormats::Markdown]),
-            ..Default::default()
-        };
-
-        let scrape_result = client
-            .scrape_url(&args.url, options)
-            .await
-            .map_err(|e| Error::from(format!("Failed to scrape URL: {}", e)))?;
-
-        let markdown = scrape_result.markdown.unwrap_or_else(|| "No content found".to_string());
-        let title = None; // Metadata access might require different approach
-
-        let response = WebsiteToMdResponse {
-            url: args.url,
-            markdown,
-            title,
+        let page = if args.no_cache {
+            self.fetcher.fetch_fresh(&args.url).await?
+        } else {
+            self.fetcher.fetch(&args.url).await?
         };
-
-        Ok(json!(response))
+        Ok(json!(page))
     }
 }
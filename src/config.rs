@@ -1,11 +1,80 @@
-use dotenv;
+use crate::types::tchoir::{StageFallbacks, StageTimeouts};
+use crate::types::tkey::KeyLimits;
+use std::collections::HashMap;
 use std::env;
+use std::str::FromStr;
+use std::time::Duration;
 
 pub struct EnvConfig {
     pub port: i32,
-    pub api_key: String,
-    pub oai_key: String,
-    pub firecrawl_key: String
+    /// Bootstrap admin key. Everything else uses keys from the key store.
+    pub api_key: Option<String>,
+    pub oai_key: Option<String>,
+    /// Only needed with the firecrawl fetcher.
+    pub firecrawl_key: Option<String>,
+    /// `native` or `firecrawl`.
+    pub fetcher: String,
+    pub fetch_timeout_secs: u64,
+    /// Pages are cut off after this many bytes.
+    pub fetch_max_bytes: usize,
+    /// Let the fetcher reach private and loopback addresses, for local testing.
+    pub fetch_allow_private: bool,
+    pub fetch_allowed_schemes: Vec<String>,
+    /// Any port when empty.
+    pub fetch_allowed_ports: Vec<u16>,
+    pub fetch_allowed_domains: Vec<String>,
+    pub fetch_denied_domains: Vec<String>,
+    pub fetch_max_redirects: usize,
+    /// How long a fetched page is served from the cache. The cache is off when zero.
+    pub fetch_cache_ttl: Duration,
+    pub fetch_cache_max_entries: usize,
+    /// Most tokens of fetched content a prompt gets before it's condensed.
+    pub context_max_tokens: u64,
+    /// Per model overrides of `context_max_tokens`, for models with smaller or larger windows.
+    pub model_context_tokens: HashMap<String, u64>,
+    /// Size of the pieces long pages are condensed in.
+    pub chunk_tokens: u64,
+    /// Chunks condensed per run, the rest of the content is dropped.
+    pub max_chunks: usize,
+    /// Tokenizer per model name prefix, for models tiktoken doesn't know.
+    pub model_tokenizers: HashMap<String, String>,
+    /// Context window per model name prefix, on top of the known OpenAI models.
+    pub model_context_windows: HashMap<String, u64>,
+    pub llm_provider: String,
+    pub llm_base_url: Option<String>,
+    pub llm_api_key: Option<String>,
+    pub default_model: String,
+    pub agents_file: Option<String>,
+    pub allowed_models: Vec<String>,
+    pub max_subtasks: usize,
+    pub schema_repair_retries: u32,
+    /// Agents that have to succeed for a run to go on.
+    pub agent_quorum: usize,
+    /// Times a failed agent is run again.
+    pub agent_reruns: u32,
+    /// Retries per model before moving down the fallback chain.
+    pub llm_max_retries: u32,
+    pub llm_retry_base_ms: u64,
+    /// Longest we wait between retries, `Retry-After` included.
+    pub llm_retry_max_ms: u64,
+    pub fallback_models: StageFallbacks,
+    pub database_path: String,
+    pub prices_file: Option<String>,
+    pub run_max_tokens: Option<u64>,
+    pub run_max_cost_usd: Option<f64>,
+    /// Default and longest deadline for a run.
+    pub run_timeout: Duration,
+    pub stage_timeouts: StageTimeouts,
+    /// Limits for keys that don't set their own.
+    pub default_key_limits: KeyLimits,
+    pub health_cache_secs: u64,
+    /// Consecutive failures that open an upstream's circuit.
+    pub circuit_failure_threshold: u32,
+    /// How long an open circuit fails calls before letting a probe through.
+    pub circuit_cooldown_secs: u64,
+    /// OTLP collector to export traces to. Tracing is off when unset.
+    pub otlp_endpoint: Option<String>,
+    pub otel_service_name: String,
 }
 
 impl EnvConfig {
@@ -14,19 +83,263 @@ impl EnvConfig {
         env::var(key).unwrap_or_else(|_| panic!("Environment variable {} not set", key))
     }
 
+    // Get from env, None when unset or empty
+    fn get_env_opt(key: &str) -> Option<String> {
+        env::var(key).ok().filter(|v| !v.is_empty())
+    }
+
+    // A positive number from env, None when unset. Anything else stops startup, a typo must
+    // not leave a limit off.
+    fn get_env_limit<T: FromStr + Default + PartialEq>(key: &str) -> Option<T> {
+        let value = Self::get_env_opt(key)?;
+        match value.parse::<T>() {
+            Ok(n) if n != T::default() => Some(n),
+            _ => panic!("Invalid {} '{}', expected a number greater than 0", key, value),
+        }
+    }
+
     pub fn from_env() -> Self {
         dotenv::dotenv().ok();
 
         let port: i32 = Self::get_env("PORT").parse().unwrap_or(8081);
-        let api_key = Self::get_env("API_KEY");
-        let oai_key = Self::get_env("OAI_KEY");
-        let firecrawl_key = Self::get_env("FC_KEY");
+        let api_key = Self::get_env_opt("API_KEY");
+        let firecrawl_key = Self::get_env_opt("FC_KEY");
+        // Existing deployments with a Firecrawl key keep using it unless told otherwise.
+        let fetcher = Self::get_env_opt("FETCHER").unwrap_or_else(|| {
+            if firecrawl_key.is_some() { "firecrawl" } else { "native" }.to_string()
+        });
+        let fetch_timeout_secs: u64 = Self::get_env_opt("FETCH_TIMEOUT_SECS")
+            .and_then(|v| v.parse().ok())
+            .filter(|n| *n > 0)
+            .unwrap_or(20);
+        let fetch_max_bytes: usize = Self::get_env_opt("FETCH_MAX_BYTES")
+            .and_then(|v| v.parse().ok())
+            .filter(|n| *n > 0)
+            .unwrap_or(5_000_000);
+        let list = |key: &str, default: &str| {
+            Self::get_env_opt(key)
+                .unwrap_or_else(|| default.to_string())
+                .split(',')
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty())
+                .collect::<Vec<_>>()
+        };
+        let fetch_allow_private = Self::get_env_opt("FETCH_ALLOW_PRIVATE")
+            .is_some_and(|v| v == "true" || v == "1");
+        let fetch_allowed_schemes = list("FETCH_ALLOWED_SCHEMES", "http,https");
+        // FETCH_ALLOWED_PORTS=* allows any port. Anything else must be a port number, a typo
+        // must not end up allowing every port.
+        let fetch_allowed_ports = match list("FETCH_ALLOWED_PORTS", "80,443") {
+            ports if ports == ["*"] => Vec::new(),
+            ports => ports
+                .iter()
+                .map(|p| {
+                    p.parse().unwrap_or_else(|_| {
+                        panic!("Invalid port '{}' in FETCH_ALLOWED_PORTS, expected port numbers or *", p)
+                    })
+                })
+                .collect(),
+        };
+        let fetch_allowed_domains = list("FETCH_ALLOWED_DOMAINS", "");
+        let fetch_denied_domains = list("FETCH_DENIED_DOMAINS", "");
+        let fetch_max_redirects: usize = Self::get_env_opt("FETCH_MAX_REDIRECTS")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(5);
+        let fetch_cache_ttl = Duration::from_secs(
+            Self::get_env_opt("FETCH_CACHE_TTL_SECS")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(3600),
+        );
+        let fetch_cache_max_entries: usize = Self::get_env_opt("FETCH_CACHE_MAX_ENTRIES")
+            .and_then(|v| v.parse().ok())
+            .filter(|n| *n > 0)
+            .unwrap_or(1000);
+        let context_max_tokens: u64 = Self::get_env_opt("CONTEXT_MAX_TOKENS")
+            .and_then(|v| v.parse().ok())
+            .filter(|n| *n > 0)
+            .unwrap_or(8000);
+        // Per model settings like MODEL_CONTEXT_TOKENS=gpt-4o=16000,llama3.1=4000
+        let per_model = |key: &str| -> HashMap<String, String> {
+            Self::get_env_opt(key)
+                .map(|v| {
+                    v.split(',')
+                        .filter_map(|entry| {
+                            let (model, value) = entry.trim().rsplit_once('=')?;
+                            Some((model.trim().to_string(), value.trim().to_string()))
+                        })
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+        let per_model_tokens = |key: &str| -> HashMap<String, u64> {
+            per_model(key)
+                .into_iter()
+                .filter_map(|(model, tokens)| Some((model, tokens.parse().ok()?)))
+                .collect()
+        };
+        let model_context_tokens = per_model_tokens("MODEL_CONTEXT_TOKENS");
+        let chunk_tokens: u64 = Self::get_env_opt("CHUNK_TOKENS")
+            .and_then(|v| v.parse().ok())
+            .filter(|n| *n > 0)
+            .unwrap_or(2000);
+        let max_chunks: usize = Self::get_env_opt("MAX_CHUNKS")
+            .and_then(|v| v.parse().ok())
+            .filter(|n| *n > 0)
+            .unwrap_or(24);
+        // MODEL_TOKENIZERS=llama3=cl100k_base,mistral=approx
+        let model_tokenizers = per_model("MODEL_TOKENIZERS");
+        let model_context_windows = per_model_tokens("MODEL_CONTEXT_WINDOWS");
+
+        let llm_provider = Self::get_env_opt("LLM_PROVIDER").unwrap_or_else(|| "openai".to_string());
+        let llm_base_url = Self::get_env_opt("LLM_BASE_URL");
+        let llm_api_key = Self::get_env_opt("LLM_API_KEY");
+        let default_model = Self::get_env_opt("LLM_MODEL").unwrap_or_else(|| "gpt-4o".to_string());
+        let agents_file = Self::get_env_opt("AGENTS_FILE");
+        // Models a request may ask for, on top of the default and any roster models.
+        let allowed_models = Self::get_env_opt("ALLOWED_MODELS")
+            .map(|v| v.split(',').map(|m| m.trim().to_string()).filter(|m| !m.is_empty()).collect())
+            .unwrap_or_default();
+        let max_subtasks: usize = Self::get_env_opt("MAX_SUBTASKS")
+            .and_then(|v| v.parse().ok())
+            .filter(|n| *n > 0)
+            .unwrap_or(8);
+        let schema_repair_retries: u32 = Self::get_env_opt("SCHEMA_REPAIR_RETRIES")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(2);
+        let agent_quorum: usize = Self::get_env_opt("AGENT_QUORUM")
+            .and_then(|v| v.parse().ok())
+            .filter(|n| *n > 0)
+            .unwrap_or(1);
+        let agent_reruns: u32 = Self::get_env_opt("AGENT_RERUNS")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0);
+        let llm_max_retries: u32 = Self::get_env_opt("LLM_MAX_RETRIES")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(2);
+        let llm_retry_base_ms: u64 = Self::get_env_opt("LLM_RETRY_BASE_MS")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(500);
+        let llm_retry_max_ms: u64 = Self::get_env_opt("LLM_RETRY_MAX_MS")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(30_000);
+        // LLM_FALLBACK_MODELS applies to every stage, LLM_FALLBACK_MODELS_<STAGE> to one.
+        let model_list = |key: &str| {
+            Self::get_env_opt(key).map(|v| {
+                v.split(',')
+                    .map(|m| m.trim().to_string())
+                    .filter(|m| !m.is_empty())
+                    .collect::<Vec<_>>()
+            })
+        };
+        let all_stages = model_list("LLM_FALLBACK_MODELS");
+        let fallback_models = StageFallbacks {
+            digest: model_list("LLM_FALLBACK_MODELS_DIGEST").or_else(|| all_stages.clone()),
+            task_master: model_list("LLM_FALLBACK_MODELS_TASK_MASTER").or_else(|| all_stages.clone()),
+            agents: model_list("LLM_FALLBACK_MODELS_AGENTS").or_else(|| all_stages.clone()),
+            assessment: model_list("LLM_FALLBACK_MODELS_ASSESSMENT").or_else(|| all_stages.clone()),
+            summary: model_list("LLM_FALLBACK_MODELS_SUMMARY").or(all_stages),
+        };
+        let database_path =
+            Self::get_env_opt("DATABASE_PATH").unwrap_or_else(|| "choir.db".to_string());
+        let prices_file = Self::get_env_opt("PRICES_FILE");
+        // Default spending limits for a single run, unlimited when unset.
+        let run_max_tokens: Option<u64> = Self::get_env_opt("RUN_MAX_TOKENS")
+            .and_then(|v| v.parse().ok())
+            .filter(|n| *n > 0);
+        let run_max_cost_usd: Option<f64> = Self::get_env_opt("RUN_MAX_COST_USD")
+            .and_then(|v| v.parse().ok())
+            .filter(|n| *n > 0.0);
+        let secs = |key: &str| {
+            Self::get_env_opt(key)
+                .and_then(|v| v.parse().ok())
+                .filter(|n| *n > 0)
+                .map(Duration::from_secs)
+        };
+        let run_timeout = secs("RUN_TIMEOUT_SECS").unwrap_or(Duration::from_secs(300));
+        // STAGE_TIMEOUT_SECS applies to every stage, STAGE_TIMEOUT_SECS_<STAGE> to one.
+        let all_stages = secs("STAGE_TIMEOUT_SECS").unwrap_or(Duration::from_secs(120));
+        let stage_timeouts = StageTimeouts {
+            enrichment: secs("STAGE_TIMEOUT_SECS_ENRICHMENT").unwrap_or(all_stages),
+            digest: secs("STAGE_TIMEOUT_SECS_DIGEST").unwrap_or(all_stages),
+            task_master: secs("STAGE_TIMEOUT_SECS_TASK_MASTER").unwrap_or(all_stages),
+            agents: secs("STAGE_TIMEOUT_SECS_AGENTS").unwrap_or(all_stages),
+            assessment: secs("STAGE_TIMEOUT_SECS_ASSESSMENT").unwrap_or(all_stages),
+            summary: secs("STAGE_TIMEOUT_SECS_SUMMARY").unwrap_or(all_stages),
+        };
+
+        let default_key_limits = KeyLimits {
+            requests_per_minute: Self::get_env_limit("RATE_LIMIT_PER_MINUTE"),
+            max_concurrent_runs: Self::get_env_limit("MAX_CONCURRENT_RUNS"),
+            daily_runs: Self::get_env_limit("DAILY_RUN_QUOTA"),
+            daily_tokens: Self::get_env_limit("DAILY_TOKEN_QUOTA"),
+        };
+
+        let health_cache_secs: u64 = Self::get_env_opt("HEALTH_CACHE_SECS")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(10);
+        let circuit_failure_threshold: u32 = Self::get_env_opt("CIRCUIT_FAILURE_THRESHOLD")
+            .and_then(|v| v.parse().ok())
+            .filter(|n| *n > 0)
+            .unwrap_or(5);
+        let circuit_cooldown_secs: u64 = Self::get_env_opt("CIRCUIT_COOLDOWN_SECS")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(30);
+        let otlp_endpoint = Self::get_env_opt("OTEL_EXPORTER_OTLP_ENDPOINT")
+            .or_else(|| Self::get_env_opt("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT"));
+        let otel_service_name =
+            Self::get_env_opt("OTEL_SERVICE_NAME").unwrap_or_else(|| "choir".to_string());
+
+        // OAI_KEY is only mandatory when talking to OpenAI itself, which the provider checks.
+        let oai_key = Self::get_env_opt("OAI_KEY");
 
         EnvConfig {
             port,
             api_key,
             oai_key,
-            firecrawl_key
+            firecrawl_key,
+            fetcher,
+            fetch_timeout_secs,
+            fetch_max_bytes,
+            fetch_allow_private,
+            fetch_allowed_schemes,
+            fetch_allowed_ports,
+            fetch_allowed_domains,
+            fetch_denied_domains,
+            fetch_max_redirects,
+            fetch_cache_ttl,
+            fetch_cache_max_entries,
+            context_max_tokens,
+            model_context_tokens,
+            chunk_tokens,
+            max_chunks,
+            model_tokenizers,
+            model_context_windows,
+            llm_provider,
+            llm_base_url,
+            llm_api_key,
+            default_model,
+            agents_file,
+            allowed_models,
+            max_subtasks,
+            schema_repair_retries,
+            agent_quorum,
+            agent_reruns,
+            llm_max_retries,
+            llm_retry_base_ms,
+            llm_retry_max_ms,
+            fallback_models,
+            database_path,
+            prices_file,
+            run_max_tokens,
+            run_max_cost_usd,
+            run_timeout,
+            stage_timeouts,
+            default_key_limits,
+            health_cache_secs,
+            circuit_failure_threshold,
+            circuit_cooldown_secs,
+            otlp_endpoint,
+            otel_service_name,
         }
     }
 }
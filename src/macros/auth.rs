@@ -1,8 +1,17 @@
 #[macro_export]
 macro_rules! require_api_key {
+    // Any active key. Evaluates to the caller's `ApiKey`.
     ($req:expr) => {
-        if let Some(resp) = crate::utils::webutils::WebUtils::require_api_key($req) {
-            return resp;
+        match $crate::utils::webutils::WebUtils::require_api_key($req, None) {
+            Ok(key) => key,
+            Err(resp) => return resp,
+        }
+    };
+    // An active key holding `$scope`.
+    ($req:expr, $scope:expr) => {
+        match $crate::utils::webutils::WebUtils::require_api_key($req, Some($scope)) {
+            Ok(key) => key,
+            Err(resp) => return resp,
         }
     };
 }
@@ -1,8 +1,20 @@
 use crate::config::EnvConfig;
-use crate::modules::{choir::ChoirService, openai};
+use crate::modules::breaker::CircuitBreaker;
+use crate::modules::health::HealthChecker;
+use crate::modules::history::RunHistory;
+use crate::modules::jobs::{JobStore, NoJobPersistence};
+use crate::modules::keys::KeyStore;
+use crate::modules::limits::KeyLimiter;
+use crate::modules::roster::AgentRoster;
+use crate::modules::tokenizer::Tokenizers;
+use crate::modules::usage::PriceTable;
+use crate::modules::{choir::ChoirService, fetcher, metrics, provider, telemetry};
 use crate::routes::configure_routes;
+use actix_web::middleware::from_fn;
 use actix_web::{web, App, HttpServer};
+use log::info;
 use std::sync::Arc;
+use std::time::Duration;
 
 mod ai_functions;
 mod config;
@@ -19,18 +31,76 @@ pub type Error = Box<dyn std::error::Error + Send + Sync>;
 async fn main() -> std::io::Result<()> {
     env_logger::init();
     let config = Arc::new(EnvConfig::from_env());
+    let _telemetry = telemetry::init(&config);
     let addr = format!("0.0.0.0:{}", config.port);
 
-    println!("Starting server on {}", addr);
-    let openai_service = Arc::new(openai::OpenAIService::new(config.clone()).await);
-    let choir_service = web::Data::new(ChoirService::new(openai_service.clone()));
+    info!("Starting server on {}", addr);
+    let llm_breaker = Arc::new(CircuitBreaker::new("llm", &config));
+    let fetcher_breaker = Arc::new(CircuitBreaker::new("fetcher", &config));
+    let llm_provider = provider::from_config(config.clone(), llm_breaker.clone())
+        .unwrap_or_else(|e| panic!("{}", e));
+    info!("Using LLM provider: {}", llm_provider.name());
+    let fetcher = fetcher::from_config(&config, fetcher_breaker.clone())
+        .unwrap_or_else(|e| panic!("{}", e));
+    info!("Using fetcher: {}", fetcher.name());
+
+    let roster = match &config.agents_file {
+        Some(path) => AgentRoster::load(path).unwrap_or_else(|e| panic!("{}", e)),
+        None => AgentRoster::default(),
+    };
+    info!("Loaded {} agents", roster.agents().len());
+
+    let prices = match &config.prices_file {
+        Some(path) => PriceTable::load(path).unwrap_or_else(|e| panic!("{}", e)),
+        None => PriceTable::default(),
+    };
+
+    let tokenizers = Tokenizers::from_config(&config).unwrap_or_else(|e| panic!("{}", e));
+
+    let history = Arc::new(
+        RunHistory::open(&config.database_path)
+            .unwrap_or_else(|e| panic!("Failed to open run history database: {}", e)),
+    );
+
+    let keys = web::Data::new(
+        KeyStore::open(&config.database_path, config.api_key.as_deref())
+            .unwrap_or_else(|e| panic!("Failed to open key store: {}", e)),
+    );
+
+    let health = web::Data::new(HealthChecker::new(
+        llm_provider.clone(),
+        fetcher.clone(),
+        history.clone(),
+        vec![llm_breaker, fetcher_breaker],
+        Duration::from_secs(config.health_cache_secs),
+    ));
+
+    let limiter = web::Data::new(KeyLimiter::new(config.default_key_limits, history.clone()));
+
+    let choir_service = web::Data::new(ChoirService::new(
+        llm_provider.clone(),
+        config.clone(),
+        roster,
+        history.clone(),
+        prices,
+        tokenizers,
+        fetcher,
+    ));
+    let job_store = web::Data::new(JobStore::new(Arc::new(NoJobPersistence)));
 
     HttpServer::new(move || {
         App::new()
+            .wrap(from_fn(metrics::track_requests))
+            .wrap(from_fn(telemetry::trace_requests))
             .configure(configure_routes)
             .app_data(web::Data::new(config.clone()))
-            .app_data(web::Data::from(openai_service.clone()))
+            .app_data(web::Data::from(llm_provider.clone()))
             .app_data(choir_service.clone())
+            .app_data(job_store.clone())
+            .app_data(web::Data::from(history.clone()))
+            .app_data(keys.clone())
+            .app_data(limiter.clone())
+            .app_data(health.clone())
     })
     .bind(addr)?
     .run()
@@ -0,0 +1,295 @@
+use crate::config::EnvConfig;
+use crate::modules::metrics::METRICS;
+use crate::types::terror::ChoirError;
+use crate::types::thealth::{CircuitHealth, CircuitState};
+use crate::Error;
+use log::{info, warn};
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// How long callers are told to wait while another call is probing a half-open circuit.
+const PROBE_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+// Stops calling an upstream that keeps failing. After `threshold` consecutive failures the
+// circuit opens and calls fail straight away for `cooldown`. Then a single probe call is let
+// through (half-open): the circuit closes if it works and opens again if it doesn't.
+pub struct CircuitBreaker {
+    upstream: &'static str,
+    threshold: u32,
+    cooldown: Duration,
+    state: Mutex<BreakerState>,
+}
+
+#[derive(Default)]
+struct BreakerState {
+    failures: u32,
+    /// Set while open. Once it has passed the circuit is half-open.
+    open_until: Option<Instant>,
+    /// A half-open probe is in flight.
+    probing: bool,
+}
+
+impl CircuitBreaker {
+    pub fn new(upstream: &'static str, config: &EnvConfig) -> Self {
+        METRICS.circuit_state(upstream, CircuitState::Closed);
+        Self {
+            upstream,
+            threshold: config.circuit_failure_threshold,
+            cooldown: Duration::from_secs(config.circuit_cooldown_secs),
+            state: Mutex::new(BreakerState::default()),
+        }
+    }
+
+    pub fn upstream(&self) -> &'static str {
+        self.upstream
+    }
+
+    pub fn health(&self) -> CircuitHealth {
+        let state = self.state.lock().unwrap();
+        let (circuit, retry_after) = match state.open_until {
+            None => (CircuitState::Closed, None),
+            Some(until) => match until.checked_duration_since(Instant::now()) {
+                Some(remaining) => (CircuitState::Open, Some(remaining)),
+                None => (CircuitState::HalfOpen, None),
+            },
+        };
+
+        CircuitHealth {
+            state: circuit,
+            consecutive_failures: state.failures,
+            retry_after_secs: retry_after.map(|d| d.as_secs() + u64::from(d.subsec_nanos() > 0)),
+        }
+    }
+
+    // Make a call through the breaker, failing fast with `CircuitOpen` while it's open.
+    pub async fn call<T>(&self, call: impl Future<Output = Result<T, Error>>) -> Result<T, Error> {
+        let _probe = self.acquire()?;
+        let result = call.await;
+        match &result {
+            Err(e) if is_upstream_failure(e) => self.failed(e),
+            _ => self.succeeded(),
+        }
+        result
+    }
+
+    // Let a call through, or not. Returns a guard when the call is the half-open probe.
+    fn acquire(&self) -> Result<Option<Probe<'_>>, ChoirError> {
+        let mut state = self.state.lock().unwrap();
+        let Some(until) = state.open_until else {
+            return Ok(None);
+        };
+
+        let retry_after = match until.checked_duration_since(Instant::now()) {
+            Some(remaining) => remaining,
+            None if state.probing => PROBE_RETRY_AFTER,
+            None => {
+                state.probing = true;
+                METRICS.circuit_state(self.upstream, CircuitState::HalfOpen);
+                return Ok(Some(Probe(self)));
+            }
+        };
+
+        METRICS.circuit_rejected(self.upstream);
+        Err(ChoirError::CircuitOpen {
+            upstream: self.upstream.to_string(),
+            retry_after,
+        })
+    }
+
+    fn succeeded(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.open_until.is_some() {
+            info!("Circuit for {} closed", self.upstream);
+            METRICS.circuit_state(self.upstream, CircuitState::Closed);
+        }
+        *state = BreakerState::default();
+    }
+
+    fn failed(&self, error: &Error) {
+        let mut state = self.state.lock().unwrap();
+        state.failures += 1;
+
+        // A failed probe reopens straight away, otherwise it takes `threshold` in a row.
+        if state.probing || (state.open_until.is_none() && state.failures >= self.threshold) {
+            warn!(
+                "Circuit for {} opened for {}s after {} consecutive failures, last: {}",
+                self.upstream,
+                self.cooldown.as_secs(),
+                state.failures,
+                error
+            );
+            state.open_until = Some(Instant::now() + self.cooldown);
+            state.probing = false;
+            METRICS.circuit_state(self.upstream, CircuitState::Open);
+        }
+    }
+}
+
+// Frees the probe slot if the probe is dropped before it finishes, so the next call gets to
+// probe instead of the circuit staying half-open for good.
+struct Probe<'a>(&'a CircuitBreaker);
+
+impl Drop for Probe<'_> {
+    fn drop(&mut self) {
+        self.0.state.lock().unwrap().probing = false;
+    }
+}
+
+// Whether an error says the upstream itself is in trouble. Requests it turned down, rate
+// limits and our own errors all mean it answered, so they count as successes.
+fn is_upstream_failure(error: &Error) -> bool {
+    match error.downcast_ref::<ChoirError>() {
+        Some(ChoirError::Provider { retryable, .. }) => *retryable,
+        Some(ChoirError::Timeout(_)) | Some(ChoirError::FetchFailed { .. }) => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::rt::time::sleep;
+    use futures::channel::oneshot;
+
+    const COOLDOWN: Duration = Duration::from_millis(50);
+
+    fn breaker() -> CircuitBreaker {
+        CircuitBreaker {
+            upstream: "test",
+            threshold: 3,
+            cooldown: COOLDOWN,
+            state: Mutex::new(BreakerState::default()),
+        }
+    }
+
+    async fn fail(breaker: &CircuitBreaker) -> Result<(), Error> {
+        breaker
+            .call(async { Err::<(), _>(ChoirError::provider("502 Bad Gateway", true).into()) })
+            .await
+    }
+
+    async fn succeed(breaker: &CircuitBreaker) -> Result<(), Error> {
+        breaker.call(async { Ok(()) }).await
+    }
+
+    fn is_open(result: Result<(), Error>) -> bool {
+        matches!(result.map_err(ChoirError::from), Err(ChoirError::CircuitOpen { .. }))
+    }
+
+    #[actix_web::test]
+    async fn opens_after_consecutive_failures() {
+        let breaker = breaker();
+        fail(&breaker).await.unwrap_err();
+        fail(&breaker).await.unwrap_err();
+        // A success in between starts the count again.
+        succeed(&breaker).await.unwrap();
+        fail(&breaker).await.unwrap_err();
+        fail(&breaker).await.unwrap_err();
+        assert_eq!(breaker.health().state, CircuitState::Closed);
+
+        fail(&breaker).await.unwrap_err();
+        let health = breaker.health();
+        assert_eq!(health.state, CircuitState::Open);
+        assert_eq!(health.consecutive_failures, 3);
+
+        // While open, calls fail without being made.
+        let mut called = false;
+        let result = breaker
+            .call(async {
+                called = true;
+                Ok(())
+            })
+            .await;
+        assert!(is_open(result));
+        assert!(!called);
+    }
+
+    #[actix_web::test]
+    async fn answers_from_the_upstream_are_not_failures() {
+        let breaker = breaker();
+        for _ in 0..5 {
+            let rejected = breaker
+                .call(async { Err::<(), _>(ChoirError::provider("401 Unauthorized", false).into()) })
+                .await;
+            assert!(!is_open(rejected));
+            let bad = breaker
+                .call(async { Err::<(), _>(ChoirError::BadRequest("bad".to_string()).into()) })
+                .await;
+            assert!(!is_open(bad));
+        }
+        assert_eq!(breaker.health().state, CircuitState::Closed);
+        assert_eq!(breaker.health().consecutive_failures, 0);
+    }
+
+    #[actix_web::test]
+    async fn probe_closes_or_reopens() {
+        let breaker = breaker();
+        for _ in 0..3 {
+            fail(&breaker).await.unwrap_err();
+        }
+        sleep(COOLDOWN).await;
+        assert_eq!(breaker.health().state, CircuitState::HalfOpen);
+
+        // A failed probe opens the circuit again at once.
+        assert!(!is_open(fail(&breaker).await));
+        assert_eq!(breaker.health().state, CircuitState::Open);
+        assert!(is_open(succeed(&breaker).await));
+
+        sleep(COOLDOWN).await;
+        succeed(&breaker).await.unwrap();
+        let health = breaker.health();
+        assert_eq!(health.state, CircuitState::Closed);
+        assert_eq!(health.consecutive_failures, 0);
+    }
+
+    #[actix_web::test]
+    async fn one_probe_at_a_time() {
+        let breaker = breaker();
+        for _ in 0..3 {
+            fail(&breaker).await.unwrap_err();
+        }
+        sleep(COOLDOWN).await;
+
+        let (release, released) = oneshot::channel::<()>();
+        let probe = breaker.call(async {
+            released.await.unwrap();
+            Ok(())
+        });
+        let others = async {
+            // Give the probe its turn first.
+            sleep(Duration::from_millis(5)).await;
+            let result = succeed(&breaker).await.map_err(ChoirError::from);
+            release.send(()).unwrap();
+            result
+        };
+        let (probe, other) = futures::join!(probe, others);
+
+        probe.unwrap();
+        match other {
+            Err(ChoirError::CircuitOpen { retry_after, .. }) => assert_eq!(retry_after, PROBE_RETRY_AFTER),
+            other => panic!("second call wasn't turned away: {:?}", other),
+        }
+        assert_eq!(breaker.health().state, CircuitState::Closed);
+    }
+
+    #[actix_web::test]
+    async fn dropped_probe_frees_the_slot() {
+        let breaker = breaker();
+        for _ in 0..3 {
+            fail(&breaker).await.unwrap_err();
+        }
+        sleep(COOLDOWN).await;
+
+        // The probe is abandoned halfway, say by a run timing out.
+        let abandoned = actix_web::rt::time::timeout(
+            Duration::from_millis(5),
+            breaker.call(futures::future::pending::<Result<(), Error>>()),
+        )
+        .await;
+        assert!(abandoned.is_err());
+
+        succeed(&breaker).await.unwrap();
+        assert_eq!(breaker.health().state, CircuitState::Closed);
+    }
+}
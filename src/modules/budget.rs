@@ -0,0 +1,353 @@
+use crate::modules::provider::ChatMessage;
+use crate::modules::tokenizer::{Tokenizer, Tokenizers};
+use crate::types::tchoir::BudgetLimits;
+use crate::types::trun::UsageTotals;
+
+// What `truncate_to_tokens` adds to say it cut something, with some slack.
+const TRUNCATION_MARKER_TOKENS: u64 = 16;
+
+// Spending limits of one run. Real usage is only known once a completion returns,
+// so stages are checked up front against estimated token counts.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RunBudget {
+    pub max_tokens: Option<u64>,
+    pub max_cost_usd: Option<f64>,
+}
+
+impl RunBudget {
+    // The tighter of the server limit and the request's own, per dimension.
+    pub fn resolve(server: BudgetLimits, request: Option<BudgetLimits>) -> Self {
+        let request = request.unwrap_or_default();
+        Self {
+            max_tokens: tighter(server.max_tokens, request.max_tokens, u64::min),
+            max_cost_usd: tighter(server.max_cost_usd, request.max_cost_usd, f64::min),
+        }
+    }
+
+    pub fn is_limited(&self) -> bool {
+        self.max_tokens.is_some() || self.max_cost_usd.is_some()
+    }
+
+    // Whether spending another `tokens` tokens costing `cost_usd` on top of `spent` stays in budget.
+    pub fn allows(&self, spent: &UsageTotals, tokens: u64, cost_usd: f64) -> bool {
+        let tokens_ok = self
+            .max_tokens
+            .is_none_or(|max| spent.total_tokens + tokens <= max);
+        let cost_ok = self
+            .max_cost_usd
+            .is_none_or(|max| spent.cost_usd + cost_usd <= max);
+        tokens_ok && cost_ok
+    }
+
+    // Tokens still affordable after `spent`, at `usd_per_token` for the cost limit.
+    pub fn remaining_tokens(&self, spent: &UsageTotals, usd_per_token: f64) -> Option<u64> {
+        let by_tokens = self
+            .max_tokens
+            .map(|max| max.saturating_sub(spent.total_tokens));
+        let by_cost = self
+            .max_cost_usd
+            .filter(|_| usd_per_token > 0.0)
+            .map(|max| ((max - spent.cost_usd).max(0.0) / usd_per_token) as u64);
+        tighter(by_tokens, by_cost, u64::min)
+    }
+}
+
+fn tighter<T: Copy>(a: Option<T>, b: Option<T>, min: fn(T, T) -> T) -> Option<T> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(min(a, b)),
+        (a, b) => a.or(b),
+    }
+}
+
+// Cut `text` down to `tokens` tokens, at a line break or between words where one is close
+// enough to the limit. None when it already fits.
+pub fn truncate_to_tokens(tokenizer: &dyn Tokenizer, text: &str, tokens: u64) -> Option<String> {
+    if tokenizer.count(text) <= tokens {
+        return None;
+    }
+
+    let prefix = tokenizer.prefix(text, tokens);
+    let boundary = ["\n\n", "\n", " "]
+        .iter()
+        .filter_map(|separator| prefix.rfind(separator))
+        .find(|end| *end >= prefix.len() / 2);
+    let mut truncated = boundary.map_or(prefix, |end| &prefix[..end]).to_string();
+    truncated.push_str("\n\n[... truncated to fit the context limit]");
+    Some(truncated)
+}
+
+// How `fit_messages` got a prompt under its limit.
+#[derive(Debug, PartialEq)]
+pub struct Fitted {
+    /// Prompt tokens of what's left.
+    pub tokens: u64,
+    /// Earlier tool or retry rounds left out.
+    pub dropped_rounds: usize,
+    /// Whether a message had to be cut short.
+    pub cut: bool,
+}
+
+// Get the prompt of `messages` down to `limit` tokens on `model`. Whole rounds go first:
+// everything from one assistant message up to the next, oldest first, so tool calls keep
+// their results and the newest round stays. What's left then is the system prompt, the task
+// and the latest round, none of which the call makes sense without, so if those still don't
+// fit it's because one of them carries a page or tool result too big for the window by itself.
+// That one, the longest user or tool message, is cut. None when even that isn't enough.
+pub fn fit_messages(tokenizers: &Tokenizers, model: &str, messages: &mut Vec<ChatMessage>, limit: u64) -> Option<Fitted> {
+    let mut fitted = Fitted {
+        tokens: tokenizers.count_messages(model, messages),
+        dropped_rounds: 0,
+        cut: false,
+    };
+    while fitted.tokens > limit {
+        let mut rounds = messages
+            .iter()
+            .enumerate()
+            .filter(|(_, message)| matches!(message, ChatMessage::Assistant { .. }))
+            .map(|(i, _)| i);
+        let (Some(oldest), Some(next)) = (rounds.next(), rounds.next()) else {
+            break;
+        };
+        messages.drain(oldest..next);
+        fitted.dropped_rounds += 1;
+        fitted.tokens = tokenizers.count_messages(model, messages);
+    }
+    if fitted.tokens <= limit {
+        return Some(fitted);
+    }
+
+    let tokenizer = tokenizers.for_model(model);
+    let longest = messages
+        .iter_mut()
+        .filter_map(|message| match message {
+            ChatMessage::User(content) | ChatMessage::Tool { content, .. } => Some(content),
+            _ => None,
+        })
+        .max_by_key(|content| content.len())?;
+    let keep = tokenizer
+        .count(longest)
+        .checked_sub(fitted.tokens - limit + TRUNCATION_MARKER_TOKENS)?;
+    *longest = truncate_to_tokens(tokenizer.as_ref(), longest, keep)?;
+    fitted.tokens = tokenizers.count_messages(model, messages);
+    fitted.cut = true;
+    Some(fitted)
+}
+
+// Split `text` into pieces of about `tokens` tokens at most, breaking between paragraphs
+// where it can, then between lines, then between words, and only then anywhere.
+pub fn chunk_by_tokens(tokenizer: &dyn Tokenizer, text: &str, tokens: u64) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+    let mut current_tokens = 0;
+    for piece in split_to_fit(tokenizer, text, tokens, &["\n\n", "\n", " "]) {
+        let piece_tokens = tokenizer.count(piece);
+        if !current.is_empty() && current_tokens + piece_tokens > tokens {
+            chunks.push(std::mem::take(&mut current));
+            current_tokens = 0;
+        }
+        current.push_str(piece);
+        current_tokens += piece_tokens;
+    }
+    chunks.push(current);
+    chunks.retain(|c| !c.trim().is_empty());
+    chunks
+}
+
+// `text` split on the coarsest separator that gets every piece under `tokens`,
+// separators kept so the pieces join back into `text`.
+fn split_to_fit<'a>(tokenizer: &dyn Tokenizer, text: &'a str, tokens: u64, separators: &[&str]) -> Vec<&'a str> {
+    if tokenizer.count(text) <= tokens {
+        return vec![text];
+    }
+    let Some((separator, finer)) = separators.split_first() else {
+        let mut pieces = Vec::new();
+        let mut rest = text;
+        while !rest.is_empty() {
+            // At least one character, or a `tokens` of 0 would never get anywhere.
+            let mut end = tokenizer.prefix(rest, tokens).len();
+            if end == 0 {
+                end = rest.chars().next().map_or(rest.len(), char::len_utf8);
+            }
+            let (piece, tail) = rest.split_at(end);
+            pieces.push(piece);
+            rest = tail;
+        }
+        return pieces;
+    };
+    text.split_inclusive(separator)
+        .flat_map(|piece| split_to_fit(tokenizer, piece, tokens, finer))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::provider::ToolCall;
+    use crate::modules::tokenizer::ApproxTokenizer;
+    use std::sync::Arc;
+
+    const MARKER: &str = "\n\n[... truncated to fit the context limit]";
+
+    #[test]
+    fn truncate_leaves_text_that_fits() {
+        assert_eq!(truncate_to_tokens(&ApproxTokenizer, "twelve chars", 3), None);
+        assert_eq!(truncate_to_tokens(&ApproxTokenizer, "", 0), None);
+    }
+
+    #[test]
+    fn truncate_cuts_between_words_under_the_limit() {
+        let text = "alpha beta gamma delta epsilon zeta";
+        let truncated = truncate_to_tokens(&ApproxTokenizer, text, 5).unwrap();
+        let kept = truncated.strip_suffix(MARKER).unwrap();
+        assert_eq!(kept, "alpha beta gamma");
+        assert!(ApproxTokenizer.count(kept) <= 5);
+    }
+
+    #[test]
+    fn truncate_prefers_paragraph_breaks() {
+        let text = "first paragraph\n\nsecond one, which is quite a bit longer";
+        let truncated = truncate_to_tokens(&ApproxTokenizer, text, 6).unwrap();
+        assert_eq!(truncated.strip_suffix(MARKER), Some("first paragraph"));
+    }
+
+    #[test]
+    fn truncate_cuts_mid_word_when_no_break_is_close() {
+        // The only space is too early to be worth cutting at.
+        let text = "a bcdefghijklmnopqrstuvwxyz";
+        let truncated = truncate_to_tokens(&ApproxTokenizer, text, 4).unwrap();
+        assert_eq!(truncated.strip_suffix(MARKER), Some("a bcdefghijklmno"));
+    }
+
+    #[test]
+    fn chunks_stay_under_the_limit_and_keep_the_text() {
+        let text = "one two three\n\nfour five six seven\neight nine\n\nten eleven twelve thirteen";
+        for tokens in [1, 3, 5, 8, 100] {
+            let chunks = chunk_by_tokens(&ApproxTokenizer, text, tokens);
+            for chunk in &chunks {
+                assert!(ApproxTokenizer.count(chunk) <= tokens, "{:?} over {}", chunk, tokens);
+            }
+            // Chunks don't overlap and lose nothing but chunks of bare whitespace.
+            let words = |text: &str| text.split_whitespace().collect::<String>();
+            assert_eq!(words(&chunks.concat()), words(text));
+        }
+        for tokens in [5, 8, 100] {
+            assert_eq!(chunk_by_tokens(&ApproxTokenizer, text, tokens).concat(), text);
+        }
+    }
+
+    #[test]
+    fn chunks_break_at_paragraphs_first() {
+        let text = "aaaa bbbb\n\ncccc dddd\n\neeee";
+        let chunks = chunk_by_tokens(&ApproxTokenizer, text, 3);
+        assert_eq!(chunks, vec!["aaaa bbbb\n\n", "cccc dddd\n\n", "eeee"]);
+    }
+
+    #[test]
+    fn chunks_split_words_longer_than_the_limit() {
+        let chunks = chunk_by_tokens(&ApproxTokenizer, "abcdefghij", 1);
+        assert_eq!(chunks, vec!["abcd", "efgh", "ij"]);
+        // A zero limit still makes progress, a character at a time.
+        assert_eq!(chunk_by_tokens(&ApproxTokenizer, "héé", 0), vec!["h", "é", "é"]);
+    }
+
+    #[test]
+    fn chunks_of_blank_text_are_dropped() {
+        assert!(chunk_by_tokens(&ApproxTokenizer, "", 10).is_empty());
+        assert!(chunk_by_tokens(&ApproxTokenizer, " \n\n ", 10).is_empty());
+    }
+
+    fn tokenizers() -> Tokenizers {
+        let mut tokenizers = Tokenizers::default();
+        tokenizers.register("test", Arc::new(ApproxTokenizer));
+        tokenizers
+    }
+
+    fn call(id: &str) -> ChatMessage {
+        ChatMessage::Assistant {
+            content: None,
+            tool_calls: vec![ToolCall {
+                id: id.to_string(),
+                name: "website_to_md".to_string(),
+                arguments: "{}".to_string(),
+            }],
+        }
+    }
+
+    fn result(id: &str, content: String) -> ChatMessage {
+        ChatMessage::Tool {
+            tool_call_id: id.to_string(),
+            content,
+        }
+    }
+
+    #[test]
+    fn fitting_messages_are_left_alone() {
+        let mut messages = vec![ChatMessage::system("be brief"), ChatMessage::user("what's up")];
+        let before = messages.clone();
+        let fitted = fit_messages(&tokenizers(), "test", &mut messages, 100).unwrap();
+        assert_eq!(fitted, Fitted { tokens: 16, dropped_rounds: 0, cut: false });
+        assert_eq!(messages, before);
+    }
+
+    #[test]
+    fn older_rounds_are_dropped_whole_before_anything_is_cut() {
+        let mut messages = vec![
+            ChatMessage::system("be brief"),
+            ChatMessage::user("what's up"),
+            call("1"),
+            result("1", "x".repeat(400)),
+            call("2"),
+            result("2", "y".repeat(40)),
+        ];
+        let fitted = fit_messages(&tokenizers(), "test", &mut messages, 60).unwrap();
+        assert_eq!(fitted.dropped_rounds, 1);
+        assert!(!fitted.cut);
+        assert!(fitted.tokens <= 60);
+        assert_eq!(
+            messages,
+            vec![
+                ChatMessage::system("be brief"),
+                ChatMessage::user("what's up"),
+                call("2"),
+                result("2", "y".repeat(40)),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_message_too_big_on_its_own_is_cut() {
+        let page = "word ".repeat(200);
+        let mut messages = vec![ChatMessage::system("summarize"), ChatMessage::user(page.clone())];
+        let fitted = fit_messages(&tokenizers(), "test", &mut messages, 100).unwrap();
+        assert!(fitted.cut);
+        assert!(fitted.tokens <= 100);
+        assert_eq!(messages[0], ChatMessage::system("summarize"));
+        let ChatMessage::User(cut) = &messages[1] else { panic!("user message gone") };
+        assert!(cut.ends_with(MARKER));
+        assert!(page.starts_with(cut.strip_suffix(MARKER).unwrap()));
+    }
+
+    #[test]
+    fn the_latest_round_is_cut_rather_than_dropped() {
+        let mut messages = vec![
+            ChatMessage::system("be brief"),
+            ChatMessage::user("what's up"),
+            call("1"),
+            result("1", "x".repeat(40)),
+            call("2"),
+            result("2", "y ".repeat(400)),
+        ];
+        let fitted = fit_messages(&tokenizers(), "test", &mut messages, 100).unwrap();
+        assert_eq!(fitted.dropped_rounds, 1);
+        assert!(fitted.cut);
+        assert!(fitted.tokens <= 100);
+        assert_eq!(messages.len(), 4);
+        assert!(matches!(&messages[3], ChatMessage::Tool { content, .. } if content.ends_with(MARKER)));
+    }
+
+    #[test]
+    fn messages_that_cannot_fit_are_refused() {
+        let mut messages = vec![ChatMessage::system("s".repeat(400)), ChatMessage::user("hi")];
+        assert_eq!(fit_messages(&tokenizers(), "test", &mut messages, 50), None);
+    }
+}
@@ -1,280 +1,1912 @@
-use crate::ai_functions::{get_all_functions, AIFunction};
-use crate::modules::openai::OpenAIService;
-use crate::types::tchoir::{get_choir_agent_response_schema, ChoirAgentResponse, ChoirRequest};
-use crate::Error;
-use async_openai::types::{
-    ChatCompletionRequestMessage, ChatCompletionRequestSystemMessage,
-    ChatCompletionRequestSystemMessageContent, ChatCompletionRequestUserMessage,
-    ChatCompletionRequestUserMessageContent,
+use crate::ai_functions::{execute_function, get_all_functions, AIFunction};
+use crate::config::EnvConfig;
+use crate::modules::budget::{chunk_by_tokens, fit_messages, truncate_to_tokens, RunBudget};
+use crate::modules::fetcher::Fetcher;
+use crate::modules::history::RunHistory;
+use crate::modules::metrics::METRICS;
+use crate::modules::provider::{
+    ChatMessage, CompletionRequest, CompletionResponse, LLMProvider, StreamChunk, TokenUsage,
+    ToolDefinition,
+};
+use crate::modules::retry::{CallAttempts, RetryLog, RetryPolicy};
+use crate::modules::roster::{validate_agents, AgentRoster};
+use crate::modules::tokenizer::{message_tokens, Tokenizer, Tokenizers};
+use crate::modules::usage::{PriceTable, UsageMeter};
+use crate::types::tchoir::{
+    get_choir_agent_response_schema, get_task_plan_schema, AgentDefinition, AgentSelection,
+    BudgetLimits, ChoirAgentResponse, ChoirEvent, ChoirRequest, StageFallbacks, StageTimeouts,
+    SubTask, TaskPlan,
+};
+use crate::types::terror::ChoirError;
+use crate::types::tfetch::CacheHit;
+use crate::types::trun::{
+    AgentRun, BlockedUrl, CachedUrl, DegradationKind, RunMetadata, RunRecord, RunStatus,
 };
-use log::{error, info};
+use crate::utils::schemautils::SchemaUtils;
+use crate::Error;
+use chrono::Utc;
+use futures::channel::mpsc::UnboundedSender;
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use jsonschema::Validator;
+use log::{error, info, warn};
 use serde_json::Value;
-use std::collections::HashMap;
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::field::Empty;
+use tracing::{info_span, Instrument, Span};
+use uuid::Uuid;
+
+// Budget estimates assume this many completion tokens per call unless `max_tokens` says otherwise.
+const COMPLETION_ESTIMATE_TOKENS: u64 = 1000;
+// Allowance for the system prompts and framing around the text we estimate.
+const PROMPT_OVERHEAD_TOKENS: u64 = 500;
+// Most reruns a request may ask for, as each one can cost a whole agents stage.
+const MAX_AGENT_RERUNS: u32 = 3;
+// Rounds of tool calls an agent gets. The round after is offered no tools, so it has to answer.
+const MAX_TOOL_ROUNDS: usize = 5;
+// Chunks of fetched content condensed at once.
+const DIGEST_CONCURRENCY: usize = 4;
+// Smallest note allowance per chunk, however many chunks share the context limit.
+const MIN_NOTES_TOKENS: u64 = 128;
+
+// Where run progress goes. The default sink drops everything.
+#[derive(Clone, Default)]
+pub struct ChoirEvents(Option<UnboundedSender<ChoirEvent>>);
+
+impl ChoirEvents {
+    pub fn new(sender: UnboundedSender<ChoirEvent>) -> Self {
+        Self(Some(sender))
+    }
+
+    pub fn emit(&self, event: ChoirEvent) {
+        if let Some(sender) = &self.0 {
+            // The listener going away must not break the run.
+            let _ = sender.unbounded_send(event);
+        }
+    }
+
+    pub fn is_listening(&self) -> bool {
+        self.0.as_ref().is_some_and(|s| !s.is_closed())
+    }
+}
 
 pub struct ChoirService {
-    openai_service: Arc<OpenAIService>,
+    llm: Arc<dyn LLMProvider>,
+    config: Arc<EnvConfig>,
+    roster: AgentRoster,
+    allowed_models: HashSet<String>,
+    history: Arc<RunHistory>,
+    prices: PriceTable,
+    retry: RetryPolicy,
+    tokenizers: Tokenizers,
     ai_functions: Vec<Box<dyn AIFunction>>,
 }
 
+// A finished run's answer and what it cost.
+pub struct ChoirResult {
+    pub answer: Value,
+    pub meta: RunMetadata,
+}
+
+// Everything about a run that a request may override, resolved against the server defaults,
+// plus the meter its completions are counted on and the log its retries go to.
+struct RunContext {
+    digest_model: String,
+    task_master_model: String,
+    assessment_model: String,
+    summary_model: String,
+    /// Agents to run, with their model and temperature already filled in.
+    agents: Vec<AgentDefinition>,
+    temperature: Option<f32>,
+    max_tokens: Option<u32>,
+    /// The caller's `json_schema`, compiled once up front.
+    output: Option<OutputSchema>,
+    usage: Arc<UsageMeter>,
+    budget: RunBudget,
+    fallbacks: StageFallbacks,
+    retries: Arc<RetryLog>,
+    /// Agents that have to succeed, and how often failed ones are run again.
+    quorum: usize,
+    agent_reruns: u32,
+    /// When the run has to be done by, from the caller's `timeout_ms` or the server's limit.
+    deadline: Instant,
+    stage_timeouts: StageTimeouts,
+    /// Most tokens of fetched content the task master and agents get, see `condense_pages`.
+    context_tokens: u64,
+    /// Prompts that were cut to fit their model's context window, see `fit_window`.
+    cut_prompts: Mutex<Vec<String>>,
+}
+
+impl RunContext {
+    // How long `stage` may take from now: its own timeout, cut short by the run's deadline.
+    fn time_for(&self, stage: &str) -> Duration {
+        self.stage_timeouts
+            .stage(stage)
+            .min(self.deadline.saturating_duration_since(Instant::now()))
+    }
+
+    fn out_of_time(&self) -> bool {
+        Instant::now() >= self.deadline
+    }
+}
+
+struct OutputSchema {
+    schema: Value,
+    validator: Validator,
+}
+
 impl ChoirService {
-    pub fn new(openai_service: Arc<OpenAIService>) -> Self {
+    pub fn new(
+        llm: Arc<dyn LLMProvider>,
+        config: Arc<EnvConfig>,
+        roster: AgentRoster,
+        history: Arc<RunHistory>,
+        prices: PriceTable,
+        tokenizers: Tokenizers,
+        fetcher: Arc<dyn Fetcher>,
+    ) -> Self {
+        let mut allowed_models: HashSet<String> = config.allowed_models.iter().cloned().collect();
+        allowed_models.insert(config.default_model.clone());
+        allowed_models.extend(roster.agents().iter().filter_map(|a| a.model.clone()));
+        allowed_models.extend(config.fallback_models.models().cloned());
+        let retry = RetryPolicy::from_config(&config);
+
         Self {
-            openai_service,
-            ai_functions: get_all_functions(),
+            llm,
+            config,
+            roster,
+            allowed_models,
+            history,
+            prices,
+            retry,
+            tokenizers,
+            ai_functions: get_all_functions(fetcher),
         }
     }
 
-    pub async fn run_choir(&self, request: &ChoirRequest) -> Result<String, Error> {
-        let model = "gpt-4o";
+    // Check a request's overrides before running it. The error is safe to show the caller.
+    pub fn validate_request(&self, request: &ChoirRequest) -> Result<(), ChoirError> {
+        self.resolve_context(request)
+            .map(|_| ())
+            .map_err(ChoirError::BadRequest)
+    }
+
+    pub async fn run_choir(
+        &self,
+        request: &ChoirRequest,
+        api_key_id: Option<String>,
+    ) -> Result<ChoirResult, ChoirError> {
+        self.run_choir_with_events(Uuid::new_v4(), request, api_key_id, &ChoirEvents::default())
+            .await
+    }
+
+    // Runs the pipeline and records it in the run history under `run_id`.
+    pub async fn run_choir_with_events(
+        &self,
+        run_id: Uuid,
+        request: &ChoirRequest,
+        api_key_id: Option<String>,
+        events: &ChoirEvents,
+    ) -> Result<ChoirResult, ChoirError> {
+        let mut record = RunRecord::new(run_id, request.clone());
+        record.api_key_id = api_key_id;
+        let mut recorder = RunRecorder {
+            history: &self.history,
+            record,
+            usage: None,
+            retries: None,
+            started: Instant::now(),
+        };
+
+        let span = info_span!(
+            "choir_run",
+            run_id = %run_id,
+            api_key_id = recorder.record.api_key_id.as_deref(),
+            status = Empty,
+        );
+        let result = match self.resolve_context(request) {
+            Ok(ctx) => {
+                recorder.usage = Some(ctx.usage.clone());
+                recorder.retries = Some(ctx.retries.clone());
+                let result = self
+                    .execute_run(&ctx, request, events, &mut recorder.record)
+                    .instrument(span.clone())
+                    .await
+                    .map_err(ChoirError::from);
+                for detail in ctx.cut_prompts.lock().unwrap().drain(..) {
+                    recorder.record.degrade(DegradationKind::ContextTruncated, detail);
+                }
+                result
+            }
+            Err(e) => Err(ChoirError::BadRequest(e)),
+        };
+        match &result {
+            Ok(answer) => {
+                recorder.record.status = if recorder.record.is_partial() {
+                    RunStatus::Partial
+                } else {
+                    RunStatus::Succeeded
+                };
+                recorder.record.answer = Some(answer.clone());
+            }
+            Err(e) => {
+                recorder.record.status = RunStatus::Failed;
+                recorder.record.error = Some(e.to_string());
+            }
+        }
+        span.record("status", recorder.record.status.as_str());
 
+        let meta = RunMetadata {
+            run_id,
+            usage: recorder
+                .usage
+                .as_ref()
+                .map(|u| u.snapshot())
+                .unwrap_or_default(),
+            degraded: recorder.record.degraded.clone(),
+            retries: recorder
+                .retries
+                .as_ref()
+                .map(|r| r.snapshot())
+                .unwrap_or_default(),
+            failed_agents: recorder.record.failed_agents(),
+            partial: recorder.record.is_partial(),
+            blocked_urls: recorder.record.blocked_urls.clone(),
+            cached_urls: recorder.record.cached_urls.clone(),
+        };
+        result.map(|answer| ChoirResult { answer, meta })
+    }
+
+    async fn execute_run(
+        &self,
+        ctx: &RunContext,
+        request: &ChoirRequest,
+        events: &ChoirEvents,
+        record: &mut RunRecord,
+    ) -> Result<Value, Error> {
         info!("Gathering initial data with AI functions.");
-        let enriched_query = self.enrich_query_with_functions(&request.query).await?;
+        let started = Instant::now();
+        let mut report = FetchReport::default();
+        let fetched = in_time(
+            ctx,
+            "enrichment",
+            self.enrich_query_with_functions(&request.query, request.no_cache, &mut report, events)
+                .instrument(stage_span("enrichment")),
+        )
+        .await;
+        record.add_timing("enrichment", started.elapsed());
+        record.blocked_urls = report.blocked;
+        record.cached_urls = report.cached;
+        if fetched.is_none() {
+            if ctx.out_of_time() {
+                return Err(out_of_time("enrichment"));
+            }
+            warn!("Fetching pages timed out, going on with what was fetched.");
+            record.degrade(
+                DegradationKind::StageTimedOut,
+                "Fetching pages timed out, the rest of the URLs were skipped.",
+            );
+        }
+        let pages = self.condense_pages(ctx, &request.query, report.pages, record).await?;
+        let enriched_query = format!("{}{}", request.query, render_pages(&pages));
+        let enriched_query = self.fit_context(ctx, &request.query, enriched_query, record);
+        record.enriched_query = Some(enriched_query.clone());
         info!("Data gathering complete.");
 
-        info!("Getting a plan of action.");
-        let task_master_response = self.get_task_master_response(model, &enriched_query).await?;
-        info!("Plan of action received.");
+        let context_tokens = self.count_tokens(&ctx.task_master_model, &enriched_query);
+        let plan = if self.affordable(ctx, &[
+            (&ctx.task_master_model, context_tokens),
+            (ctx.agents[0].model.as_deref().unwrap_or_default(), context_tokens),
+            (&ctx.summary_model, COMPLETION_ESTIMATE_TOKENS),
+        ]) {
+            info!("Getting a plan of action.");
+            let started = Instant::now();
+            let plan = in_time(
+                ctx,
+                "task_master",
+                self.get_task_plan(ctx, &enriched_query)
+                    .instrument(stage_span("task_master")),
+            )
+            .await;
+            record.add_timing("task_master", started.elapsed());
+            match plan {
+                Some(plan) => plan?,
+                None if ctx.out_of_time() => return Err(out_of_time("task_master")),
+                None => {
+                    warn!("The task master timed out, using one generic sub-task per agent.");
+                    record.degrade(
+                        DegradationKind::StageTimedOut,
+                        "The task master timed out, so one generic sub-task per agent was used.",
+                    );
+                    fallback_plan(ctx)
+                }
+            }
+        } else {
+            warn!("Skipping the task master to stay within the run budget.");
+            record.degrade(
+                DegradationKind::PlanningSkipped,
+                "Used one generic sub-task per agent instead of asking the task master.",
+            );
+            fallback_plan(ctx)
+        };
+        record.plan = Some(plan.clone());
+        events.emit(ChoirEvent::Plan { plan: plan.clone() });
+        info!("Plan of action received with {} sub-tasks.", plan.subtasks.len());
+
+        let mut assignments = assign_subtasks(ctx, &plan);
+        // A plan with fewer sub-tasks than the quorum needs every one of them.
+        let quorum = ctx.quorum.min(assignments.len());
+        let affordable = self.affordable_agents(ctx, &assignments, context_tokens);
+        if affordable < quorum {
+            return Err(ChoirError::BudgetExceeded(format!(
+                "Run budget exhausted before enough agents could run ({} of the {} needed)",
+                affordable, quorum
+            ))
+            .into());
+        }
+        if affordable < assignments.len() {
+            warn!("Running {} of {} sub-tasks to stay within the run budget.", affordable, assignments.len());
+            record.degrade(
+                DegradationKind::AgentsReduced,
+                format!(
+                    "Ran {} of {} planned sub-tasks.",
+                    affordable,
+                    assignments.len()
+                ),
+            );
+            assignments.truncate(affordable);
+        }
 
         info!("Delegating to agents.");
-        let agents = self.run_agents(model, &task_master_response).await?;
+        let started = Instant::now();
+        let outcomes = self
+            .run_agents(ctx, &assignments, &enriched_query, events)
+            .instrument(stage_span("agents"))
+            .await;
+        record.add_timing("agents", started.elapsed());
         info!("Agents finished.");
 
-        for agent in agents.iter() {
-            info!("Thoughts: {}", agent.thoughts);
+        let unfinished = outcomes.iter().filter(|o| o.result.is_none()).count();
+        if unfinished > 0 {
+            warn!("{} of {} agents timed out.", unfinished, outcomes.len());
+            record.degrade(
+                DegradationKind::StageTimedOut,
+                format!("{} of {} sub-tasks timed out.", unfinished, outcomes.len()),
+            );
         }
 
-        info!("Getting assessment from chorus master.");
-        let assessment = self
-            .get_assessment(&model, &agents, &request.json_schema)
-            .await?;
-        info!("Assessment: {:?}", assessment);
+        // Failed agents are only kept in the record, nothing after this stage sees them.
+        let mut answers = Vec::new();
+        record.agents = Vec::new();
+        for (i, (assignment, outcome)) in assignments.iter().zip(outcomes).enumerate() {
+            let result = outcome
+                .result
+                .unwrap_or_else(|| Err("Timed out before finishing".to_string()));
+            record.agents.push(AgentRun {
+                agent: i + 1,
+                name: assignment.agent.name.clone(),
+                task: assignment.task.title.clone(),
+                model: assignment.agent.model.clone().unwrap_or_default(),
+                response: result.as_ref().ok().cloned(),
+                error: result.as_ref().err().cloned(),
+                attempts: outcome.attempts,
+            });
+            if let Ok(response) = result {
+                info!("Thoughts: {}", response.thoughts);
+                answers.push(Answer {
+                    agent: i + 1,
+                    assignment,
+                    response,
+                });
+            }
+        }
 
-        let final_res = self.openai_service.get_completion_response(
-            model,
-            vec![
-                ChatCompletionRequestMessage::System(ChatCompletionRequestSystemMessage {
-                    content: ChatCompletionRequestSystemMessageContent::Text(
-                        r#"
-                        You are the final summary agent. Your job is to provide the user with a direct, accurate answer to their question.
-                        You have access to webpage content and analysis from multiple expert agents.
-                        Be specific and factual. If you can answer the user's question directly, do so.
-                        Do not say "the agents didn't find" unless you're absolutely certain the information isn't in the data provided.
-                        "#.to_string(),
-                    ),
-                    name: None,
-                }),
-                ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
-                    content: ChatCompletionRequestUserMessageContent::Text(
-                        format!("User's original query: {}\n\nExpert analysis: {}\n\nDetailed agent responses: {:#?}",
-                            request.query,
-                            assessment,
-                            agents.iter().map(|agent| agent.detailed_response.clone()).collect::<Vec<_>>()
-                        ),
-                    ),
-                    name: None,
-                }),
-            ],
+        if answers.len() < quorum {
+            return Err(ChoirError::QuorumNotMet(format!(
+                "Only {} of {} agents answered, {} had to",
+                answers.len(),
+                assignments.len(),
+                quorum
+            ))
+            .into());
+        }
+        if ctx.out_of_time() {
+            return partial_answer(ctx, record, "agents");
+        }
+
+        // The assessment is the first thing to go, as long as the summary still fits without it.
+        let responses_tokens: u64 = answers
+            .iter()
+            .map(|a| self.count_tokens(&ctx.assessment_model, &a.response.detailed_response))
+            .sum();
+        let assessment = if self.affordable(ctx, &[
+            (&ctx.assessment_model, responses_tokens),
+            (&ctx.summary_model, responses_tokens + COMPLETION_ESTIMATE_TOKENS),
+        ]) {
+            info!("Getting assessment from chorus master.");
+            let started = Instant::now();
+            let assessment = in_time(
+                ctx,
+                "assessment",
+                self.get_assessment(ctx, &answers)
+                    .instrument(stage_span("assessment")),
+            )
+            .await;
+            record.add_timing("assessment", started.elapsed());
+            match assessment {
+                Some(assessment) => {
+                    let assessment = assessment?;
+                    record.assessment = Some(assessment.clone());
+                    events.emit(ChoirEvent::Assessment {
+                        assessment: assessment.clone(),
+                    });
+                    info!("Assessment: {:?}", assessment);
+                    Some(assessment)
+                }
+                None if ctx.out_of_time() => return partial_answer(ctx, record, "assessment"),
+                None => {
+                    warn!("The assessment timed out, summarising the agent responses directly.");
+                    record.degrade(
+                        DegradationKind::StageTimedOut,
+                        "The assessment timed out, the summary worked from the agent responses directly.",
+                    );
+                    None
+                }
+            }
+        } else {
+            warn!("Skipping the assessment to stay within the run budget.");
+            record.degrade(
+                DegradationKind::AssessmentSkipped,
+                "The summary worked from the agent responses directly.",
+            );
             None
-        ).await?;
+        };
 
+        let summary_request = summary_request(ctx, request, assessment.as_deref(), &answers);
+        if !self.affordable(ctx, &[(&ctx.summary_model, self.estimate_request(&summary_request))]) {
+            // A plain text answer can fall back to the assessment. A structured one can't.
+            return match assessment {
+                Some(assessment) if ctx.output.is_none() => {
+                    warn!("Skipping the summary to stay within the run budget.");
+                    record.degrade(
+                        DegradationKind::SummarySkipped,
+                        "Returned the assessment as the answer.",
+                    );
+                    Ok(Value::String(assessment))
+                }
+                _ => Err(ChoirError::BudgetExceeded(
+                    "Run budget exhausted before the final answer".to_string(),
+                )
+                .into()),
+            };
+        }
 
-        Ok(final_res)
+        let started = Instant::now();
+        let answer = in_time(
+            ctx,
+            "summary",
+            self.get_final_answer(ctx, summary_request, events)
+                .instrument(stage_span("summary")),
+        )
+        .await;
+        record.add_timing("summary", started.elapsed());
+        match answer {
+            Some(answer) => answer,
+            None => partial_answer(ctx, record, "summary"),
+        }
     }
 
-    async fn get_task_master_response(&self, model: &str, query: &str) -> Result<String, Error> {
-        self.openai_service.get_completion_response(
-            model,
-            vec![
-                ChatCompletionRequestMessage::System(ChatCompletionRequestSystemMessage {
-                    content: ChatCompletionRequestSystemMessageContent::Text(r#"
-                    You are the task master coordinating 5 expert agents.
-                    Given the user's query and any data we've gathered, create 5 distinct analytical approaches:
-                    1. Direct analysis approach
-                    2. Critical evaluation approach
-                    3. Contextual analysis approach
-                    4. Creative interpretation approach
-                    5. Comprehensive synthesis approach
-
-                    Be specific about what each agent should focus on. Each approach must be unique.
-                    "#.to_string()),
-                    name: None,
-                }),
-                ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
-                    content: ChatCompletionRequestUserMessageContent::Text(query.to_string()),
-                    name: None,
-                })
-            ],
-            None,
-        ).await
+    async fn get_final_answer(
+        &self,
+        ctx: &RunContext,
+        summary_request: CompletionRequest,
+        events: &ChoirEvents,
+    ) -> Result<Value, Error> {
+        if let Some(output) = &ctx.output {
+            return self.get_structured_answer(ctx, summary_request, output).await;
+        }
+
+        if !events.is_listening() {
+            return self
+                .chat(ctx, "summary", summary_request)
+                .await?
+                .content
+                .map(Value::String)
+                .ok_or_else(no_content);
+        }
+
+        // Someone is watching, so stream the summary token by token. Only opening the stream is
+        // retried, once deltas have gone out a failure can't be taken back.
+        let mut attempts = self.call_attempts(ctx, "summary", &summary_request.model);
+        let (model, estimated, span, started, mut stream) = loop {
+            let model = attempts.model().to_string();
+            let request = CompletionRequest {
+                model: model.clone(),
+                ..summary_request.clone()
+            };
+            let (request, estimated) = match self.fit_window(ctx, "summary", request) {
+                Ok(fitted) => fitted,
+                Err(e) => {
+                    attempts.failed(e).await?;
+                    continue;
+                }
+            };
+            let span = llm_span("summary", &model);
+            span.record("estimated_prompt_tokens", estimated);
+            let started = Instant::now();
+            match self.llm.stream_completion(request).instrument(span.clone()).await {
+                Ok(stream) => break (model, estimated, span, started, stream),
+                Err(e) => attempts.failed(e).await?,
+            }
+        };
+
+        let streamed = async {
+            let mut final_res = String::new();
+            while let Some(chunk) = stream.next().await {
+                match chunk? {
+                    StreamChunk::Delta(delta) => {
+                        final_res.push_str(&delta);
+                        events.emit(ChoirEvent::SummaryDelta { delta });
+                    }
+                    StreamChunk::Usage(usage) => {
+                        self.record_usage(ctx, "summary", &model, &usage, estimated)
+                    }
+                }
+            }
+            Ok::<_, Error>(final_res)
+        }
+        .instrument(span.clone())
+        .await;
+        span.record("latency_ms", started.elapsed().as_millis() as u64);
+
+        streamed.map(Value::String)
     }
 
-    async fn run_agents(
+    // Ask for the caller's schema and feed validation errors back until the answer conforms.
+    async fn get_structured_answer(
         &self,
-        model: &str,
-        task_master_response: &str,
-    ) -> Result<Vec<ChoirAgentResponse>, Error> {
-        let agents_prompts = [
-            r#"You are Agent 1: Direct Analysis Expert. Focus on the first assigned approach.
-            You MUST respond with a valid JSON object containing exactly these three fields:
-            - "detailed_response": Your comprehensive analysis (multiple paragraphs)
-            - "short_overview": Brief 2-3 sentence summary
-            - "thoughts": Your analytical thoughts and reasoning
-            Be precise and methodical in your analysis."#,
-            r#"You are Agent 2: Critical Evaluator. Focus on the second assigned approach.
-            You MUST respond with a valid JSON object containing exactly these three fields:
-            - "detailed_response": Your comprehensive evaluation (multiple paragraphs)
-            - "short_overview": Brief 2-3 sentence summary
-            - "thoughts": Your critical thoughts and concerns
-            Question assumptions and identify potential issues."#,
-            r#"You are Agent 3: Context Specialist. Focus on the third assigned approach.
-            You MUST respond with a valid JSON object containing exactly these three fields:
-            - "detailed_response": Your contextual analysis (multiple paragraphs)
-            - "short_overview": Brief 2-3 sentence summary
-            - "thoughts": Your thoughts on context and connections
-            Consider broader context, connections, and underlying patterns."#,
-            r#"You are Agent 4: Creative Interpreter. Focus on the fourth assigned approach.
-            You MUST respond with a valid JSON object containing exactly these three fields:
-            - "detailed_response": Your creative interpretation (multiple paragraphs)
-            - "short_overview": Brief 2-3 sentence summary
-            - "thoughts": Your innovative thoughts and perspectives
-            Think creatively while staying grounded in facts."#,
-            r#"You are Agent 5: Synthesis Expert. Focus on the fifth assigned approach.
-            You MUST respond with a valid JSON object containing exactly these three fields:
-            - "detailed_response": Your comprehensive synthesis (multiple paragraphs)
-            - "short_overview": Brief 2-3 sentence summary
-            - "thoughts": Your integrative thoughts and conclusions
-            Integrate different viewpoints and provide comprehensive analysis."#,
-        ];
+        ctx: &RunContext,
+        mut request: CompletionRequest,
+        output: &OutputSchema,
+    ) -> Result<Value, Error> {
+        request.json_schema = Some(output.schema.clone());
+        let attempts = self.config.schema_repair_retries + 1;
+        let mut errors = Vec::new();
+        let mut made = 0;
 
-        let agent_futures = agents_prompts.iter().map(|prompt| {
-            self.openai_service.get_completion_response(
-                model,
-                vec![
-                    ChatCompletionRequestMessage::System(ChatCompletionRequestSystemMessage {
-                        content: ChatCompletionRequestSystemMessageContent::Text(
-                            prompt.to_string(),
-                        ),
-                        name: None,
-                    }),
-                    ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
-                        content: ChatCompletionRequestUserMessageContent::Text(
-                            task_master_response.to_string(),
-                        ),
-                        name: None,
+        for attempt in 1..=attempts {
+            if attempt > 1 && !self.affordable(ctx, &[(&request.model, self.estimate_request(&request))]) {
+                warn!("Stopping schema repair to stay within the run budget.");
+                break;
+            }
+
+            made += 1;
+            let response = self
+                .chat(ctx, "summary", request.clone())
+                .await?
+                .content
+                .ok_or_else(no_content)?;
+
+            errors = match serde_json::from_str::<Value>(&response) {
+                Ok(value) => SchemaUtils::errors(&output.validator, &value),
+                Err(e) => vec![format!("Response is not valid JSON: {}", e)],
+            };
+            if errors.is_empty() {
+                return serde_json::from_str(&response).map_err(Error::from);
+            }
+
+            warn!(
+                "Final answer failed schema validation (attempt {}/{}): {}",
+                attempt,
+                attempts,
+                errors.join("; ")
+            );
+
+            request.messages.push(ChatMessage::Assistant {
+                content: Some(response),
+                tool_calls: Vec::new(),
+            });
+            request.messages.push(ChatMessage::user(format!(
+                "Your response does not match the required JSON schema:\n- {}\nRespond again with only the corrected JSON.",
+                errors.join("\n- ")
+            )));
+        }
+
+        Err(ChoirError::SchemaInvalid(format!(
+            "Final answer failed schema validation after {} attempts: {}",
+            made,
+            errors.join("; ")
+        ))
+        .into())
+    }
+
+    fn resolve_context(&self, request: &ChoirRequest) -> Result<RunContext, String> {
+        let models = &request.models;
+        for model in [
+            &models.digest,
+            &models.task_master,
+            &models.agents,
+            &models.assessment,
+            &models.summary,
+        ]
+        .into_iter()
+        .flatten()
+        .chain(request.fallback_models.models())
+        {
+            self.check_model(model)?;
+        }
+
+        if let Some(t) = request.temperature {
+            if !(0.0..=2.0).contains(&t) {
+                return Err("temperature must be between 0 and 2".to_string());
+            }
+        }
+        if request.max_tokens == Some(0) {
+            return Err("max_tokens must be greater than 0".to_string());
+        }
+        if request.timeout_ms == Some(0) {
+            return Err("timeout_ms must be greater than 0".to_string());
+        }
+        let quorum = request.quorum.unwrap_or_default();
+        if quorum.min_successful == Some(0) {
+            return Err("quorum.min_successful must be greater than 0".to_string());
+        }
+        if quorum.reruns.is_some_and(|n| n > MAX_AGENT_RERUNS) {
+            return Err(format!("quorum.reruns must be at most {}", MAX_AGENT_RERUNS));
+        }
+        let timeout = request
+            .timeout_ms
+            .map_or(self.config.run_timeout, |ms| {
+                Duration::from_millis(ms).min(self.config.run_timeout)
+            });
+
+        if let Some(budget) = &request.budget {
+            if budget.max_tokens == Some(0) {
+                return Err("budget.max_tokens must be greater than 0".to_string());
+            }
+            if budget.max_cost_usd.is_some_and(|c| c <= 0.0) {
+                return Err("budget.max_cost_usd must be greater than 0".to_string());
+            }
+        }
+        let budget = RunBudget::resolve(
+            BudgetLimits {
+                max_tokens: self.config.run_max_tokens,
+                max_cost_usd: self.config.run_max_cost_usd,
+            },
+            request.budget,
+        );
+
+        let output = match &request.json_schema {
+            Some(schema) => Some(OutputSchema {
+                schema: schema.clone(),
+                validator: SchemaUtils::compile(schema)?,
+            }),
+            None => None,
+        };
+
+        let stage_model = |model: &Option<String>| {
+            model
+                .clone()
+                .unwrap_or_else(|| self.config.default_model.clone())
+        };
+
+        // Roster agents take the request overrides, inline agents keep whatever they set themselves.
+        let roster_agent = |agent: &AgentDefinition| AgentDefinition {
+            model: models.agents.clone().or_else(|| agent.model.clone()),
+            temperature: request.temperature.or(agent.temperature),
+            ..agent.clone()
+        };
+
+        let mut agents: Vec<AgentDefinition> = match &request.agents {
+            None => self.roster.agents().iter().map(roster_agent).collect(),
+            Some(selection) => selection
+                .iter()
+                .map(|s| match s {
+                    AgentSelection::Named(name) => self
+                        .roster
+                        .get(name)
+                        .map(roster_agent)
+                        .ok_or_else(|| format!("Unknown agent: {}", name)),
+                    AgentSelection::Inline(agent) => Ok(AgentDefinition {
+                        model: agent.model.clone().or_else(|| models.agents.clone()),
+                        temperature: agent.temperature.or(request.temperature),
+                        ..agent.clone()
                     }),
-                ],
-                Some(get_choir_agent_response_schema()),
+                })
+                .collect::<Result<_, _>>()?,
+        };
+
+        validate_agents(&agents)?;
+        for agent in agents.iter_mut() {
+            let model = agent
+                .model
+                .get_or_insert_with(|| self.config.default_model.clone());
+            self.check_model(model)?;
+        }
+
+        // Fetched content goes to the task master and every agent, so it has to fit the
+        // smallest of their limits.
+        let task_master_model = stage_model(&models.task_master);
+        let context_tokens = std::iter::once(&task_master_model)
+            .chain(agents.iter().filter_map(|a| a.model.as_ref()))
+            .map(|model| {
+                self.config
+                    .model_context_tokens
+                    .get(model)
+                    .copied()
+                    .unwrap_or(self.config.context_max_tokens)
+            })
+            .min()
+            .unwrap_or(self.config.context_max_tokens);
+
+        Ok(RunContext {
+            digest_model: stage_model(&models.digest),
+            task_master_model,
+            assessment_model: stage_model(&models.assessment),
+            summary_model: stage_model(&models.summary),
+            agents,
+            temperature: request.temperature,
+            max_tokens: request.max_tokens,
+            output,
+            usage: Arc::new(UsageMeter::default()),
+            budget,
+            fallbacks: request.fallback_models.or(&self.config.fallback_models),
+            retries: Arc::new(RetryLog::default()),
+            quorum: quorum.min_successful.unwrap_or(self.config.agent_quorum),
+            agent_reruns: quorum.reruns.unwrap_or(self.config.agent_reruns),
+            deadline: Instant::now() + timeout,
+            stage_timeouts: self.config.stage_timeouts,
+            context_tokens,
+            cut_prompts: Mutex::new(Vec::new()),
+        })
+    }
+
+    fn check_model(&self, model: &str) -> Result<(), String> {
+        if self.allowed_models.contains(model) {
+            Ok(())
+        } else {
+            Err(format!("Unknown model: {}", model))
+        }
+    }
+
+    fn count_tokens(&self, model: &str, text: &str) -> u64 {
+        self.tokenizers.for_model(model).count(text)
+    }
+
+    // Prompt tokens of a request, leaving out the system prompts as `affordable` allows for them.
+    fn estimate_request(&self, request: &CompletionRequest) -> u64 {
+        let tokenizer = self.tokenizers.for_model(&request.model);
+        request
+            .messages
+            .iter()
+            .filter(|message| !matches!(message, ChatMessage::System(_)))
+            .map(|message| message_tokens(tokenizer.as_ref(), message))
+            .sum()
+    }
+
+    // Whether completions on these models, with prompts of roughly these many tokens,
+    // still fit in what's left of the run's budget.
+    fn affordable(&self, ctx: &RunContext, calls: &[(&str, u64)]) -> bool {
+        if !ctx.budget.is_limited() {
+            return true;
+        }
+
+        let completion = ctx.max_tokens.map_or(COMPLETION_ESTIMATE_TOKENS, u64::from);
+        let (tokens, cost) = calls.iter().fold((0, 0.0), |(tokens, cost), (model, prompt)| {
+            let prompt = prompt + PROMPT_OVERHEAD_TOKENS;
+            (
+                tokens + prompt + completion,
+                cost + self.prices.estimate(model, prompt, completion),
             )
         });
+        ctx.budget.allows(&ctx.usage.snapshot().total, tokens, cost)
+    }
 
-        let results = futures::future::join_all(agent_futures).await;
+    // How many of the assignments, in plan order, fit while leaving room for the summary.
+    fn affordable_agents(
+        &self,
+        ctx: &RunContext,
+        assignments: &[Assignment<'_>],
+        context_tokens: u64,
+    ) -> usize {
+        let mut calls: Vec<(&str, u64)> = Vec::new();
+        for (i, assignment) in assignments.iter().enumerate() {
+            let model = assignment.agent.model.as_deref().unwrap_or_default();
+            calls.push((
+                model,
+                context_tokens + self.count_tokens(model, &assignment.task.instructions),
+            ));
+            let summary = (
+                ctx.summary_model.as_str(),
+                (i as u64 + 1) * COMPLETION_ESTIMATE_TOKENS,
+            );
+            if !self.affordable(ctx, &[calls.as_slice(), &[summary]].concat()) {
+                return i;
+            }
+        }
+        assignments.len()
+    }
 
-        let mut successful_agents = Vec::new();
+    // Fetched pages go into the task master's and every agent's prompt. When together they're
+    // over `ctx.context_tokens`, they're cut into chunks and the notes relevant to the query
+    // are pulled out of each chunk (map). Notes still over the limit are merged into a single
+    // digest (reduce). If condensing fails or isn't affordable the pages are cut instead.
+    async fn condense_pages(
+        &self,
+        ctx: &RunContext,
+        query: &str,
+        pages: Vec<PageContent>,
+        record: &mut RunRecord,
+    ) -> Result<Vec<PageContent>, Error> {
+        let limit = ctx.context_tokens;
+        let tokenizer = self.tokenizers.for_model(&ctx.task_master_model);
+        let digest_tokenizer = self.tokenizers.for_model(&ctx.digest_model);
+        let total = page_tokens(tokenizer.as_ref(), &pages);
+        if total <= limit {
+            return Ok(pages);
+        }
 
-        for (i, res) in results.into_iter().enumerate() {
-            match res {
-                Ok(json) => {
-                    match serde_json::from_str::<ChoirAgentResponse>(&json) {
-                        Ok(agent_response) => successful_agents.push(agent_response),
-                        Err(e) => {
-                            error!("Agent {} failed to parse JSON: {}", i + 1, e);
-                            error!("Agent {} raw response: {}", i + 1, json);
-                            successful_agents.push(ChoirAgentResponse::empty());
-                        }
-                    }
+        let mut chunks: Vec<Chunk> = pages
+            .iter()
+            .enumerate()
+            .flat_map(|(page, content)| {
+                let texts = chunk_by_tokens(
+                    digest_tokenizer.as_ref(),
+                    &content.markdown,
+                    self.config.chunk_tokens,
+                );
+                let parts = texts.len();
+                texts.into_iter().enumerate().map(move |(i, text)| Chunk {
+                    page,
+                    part: i + 1,
+                    parts,
+                    text,
+                })
+            })
+            .collect();
+        let dropped = chunks.len().saturating_sub(self.config.max_chunks);
+        chunks.truncate(self.config.max_chunks);
+
+        let calls: Vec<(&str, u64)> = chunks
+            .iter()
+            .map(|c| {
+                let prompt = digest_tokenizer.count(&c.text) + digest_tokenizer.count(query);
+                (ctx.digest_model.as_str(), prompt)
+            })
+            .collect();
+        if !self.affordable(ctx, &calls) {
+            warn!("Condensing fetched content isn't affordable, cutting it instead.");
+            return Ok(cut_pages(tokenizer.as_ref(), pages, limit, record, "Condensing it wasn't affordable"));
+        }
+
+        info!("Condensing ~{} tokens of fetched content in {} chunks.", total, chunks.len());
+        let started = Instant::now();
+        let condensed = in_time(
+            ctx,
+            "digest",
+            self.map_reduce(ctx, query, &pages, chunks)
+                .instrument(stage_span("digest")),
+        )
+        .await;
+        record.add_timing("digest", started.elapsed());
+
+        match condensed {
+            Some(Ok(condensed)) => {
+                let mut detail = format!(
+                    "Fetched content condensed from ~{} to ~{} tokens of notes relevant to the query.",
+                    total,
+                    page_tokens(tokenizer.as_ref(), &condensed)
+                );
+                if dropped > 0 {
+                    detail.push_str(&format!(
+                        " {} chunks past the first {} were left out.",
+                        dropped, self.config.max_chunks
+                    ));
                 }
-                Err(e) => {
-                    error!("Agent {} failed to respond: {}", i + 1, e);
-                    successful_agents.push(ChoirAgentResponse::empty());
+                record.degrade(DegradationKind::ContextDigested, detail);
+                Ok(condensed)
+            }
+            Some(Err(e)) => {
+                warn!("Condensing fetched content failed, cutting it instead: {}", e);
+                Ok(cut_pages(tokenizer.as_ref(), pages, limit, record, "Condensing it failed"))
+            }
+            None if ctx.out_of_time() => Err(out_of_time("digest")),
+            None => {
+                warn!("Condensing fetched content timed out, cutting it instead.");
+                record.degrade(
+                    DegradationKind::StageTimedOut,
+                    "Condensing fetched content timed out.",
+                );
+                Ok(cut_pages(tokenizer.as_ref(), pages, limit, record, "Condensing it timed out"))
+            }
+        }
+    }
+
+    async fn map_reduce(
+        &self,
+        ctx: &RunContext,
+        query: &str,
+        pages: &[PageContent],
+        chunks: Vec<Chunk>,
+    ) -> Result<Vec<PageContent>, Error> {
+        let limit = ctx.context_tokens;
+        // Notes share the limit between them, so that usually no reduce step is needed.
+        let notes_tokens = (limit / chunks.len().max(1) as u64)
+            .clamp(MIN_NOTES_TOKENS, self.config.chunk_tokens.max(MIN_NOTES_TOKENS));
+
+        let notes = futures::stream::iter(chunks)
+            .map(|chunk| async move {
+                let notes = self
+                    .chunk_notes(ctx, query, &pages[chunk.page], &chunk, notes_tokens)
+                    .await?;
+                Ok::<_, Error>((chunk.page, notes))
+            })
+            .buffered(DIGEST_CONCURRENCY)
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Pages whose chunks were all left out don't show up at all.
+        let condensed: Vec<PageContent> = pages
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| notes.iter().any(|(page, _)| page == i))
+            .map(|(i, page)| {
+                let relevant: Vec<&str> = notes
+                    .iter()
+                    .filter(|(p, n)| *p == i && !is_irrelevant(n))
+                    .map(|(_, n)| n.trim())
+                    .collect();
+                PageContent {
+                    source: page.source.clone(),
+                    markdown: if relevant.is_empty() {
+                        "Nothing on this page is relevant to the query.".to_string()
+                    } else {
+                        relevant.join("\n\n")
+                    },
+                    condensed: true,
+                }
+            })
+            .collect();
+        let tokenizer = self.tokenizers.for_model(&ctx.task_master_model);
+        if page_tokens(tokenizer.as_ref(), &condensed) <= limit {
+            return Ok(condensed);
+        }
+
+        let response = self.chat(ctx, "digest", CompletionRequest {
+            model: ctx.digest_model.clone(),
+            messages: vec![
+                ChatMessage::system(format!(r#"
+                    You are preparing background material for a team of expert agents working on the user's query.
+                    Merge the notes below, taken from web pages, into a single digest.
+                    Keep everything relevant to the query, drop repetition and say which page each point comes from.
+                    Keep names, numbers and dates exact. Stay under about {limit} tokens.
+                    "#)),
+                ChatMessage::user(format!("Query: {}{}", query, render_pages(&condensed))),
+            ],
+            max_tokens: Some(limit.min(u32::MAX as u64) as u32),
+            ..Default::default()
+        })
+        .await?;
+        let digest = response.content.ok_or_else(no_content)?;
+
+        Ok(vec![PageContent {
+            source: "the fetched pages".to_string(),
+            markdown: truncate_to_tokens(tokenizer.as_ref(), &digest, limit).unwrap_or(digest),
+            condensed: true,
+        }])
+    }
+
+    // The map step: notes on what one chunk of a page says about the query.
+    async fn chunk_notes(
+        &self,
+        ctx: &RunContext,
+        query: &str,
+        page: &PageContent,
+        chunk: &Chunk,
+        max_tokens: u64,
+    ) -> Result<String, Error> {
+        let response = self.chat(ctx, "digest", CompletionRequest {
+            model: ctx.digest_model.clone(),
+            messages: vec![
+                ChatMessage::system(r#"
+                    You are preparing background material for a team of expert agents working on the user's query.
+                    From the excerpt of a web page below, extract every fact, figure, quote and argument relevant to the query as concise Markdown notes.
+                    Keep names, numbers and dates exact and don't add anything that isn't in the excerpt.
+                    If nothing in the excerpt is relevant to the query, reply with exactly NONE.
+                    "#),
+                ChatMessage::user(format!(
+                    "Query: {}\n\nExcerpt {} of {} from {}:\n{}",
+                    query,
+                    chunk.part,
+                    chunk.parts,
+                    page.source,
+                    chunk.text.trim()
+                )),
+            ],
+            max_tokens: Some(max_tokens.min(u32::MAX as u64) as u32),
+            ..Default::default()
+        })
+        .await?;
+        response.content.ok_or_else(no_content)
+    }
+
+    // Fetched pages are copied into the task master's and every agent's prompt. With a budget
+    // they may use at most half of what's affordable, shared between those copies.
+    // The query itself is never cut.
+    fn fit_context(
+        &self,
+        ctx: &RunContext,
+        query: &str,
+        context: String,
+        record: &mut RunRecord,
+    ) -> String {
+        let Some(fetched) = context.strip_prefix(query).filter(|f| !f.is_empty()) else {
+            return context;
+        };
+        let tokenizer = self.tokenizers.for_model(&ctx.task_master_model);
+
+        let usd_per_token = self.prices.estimate(&ctx.task_master_model, 1, 0);
+        let Some(remaining) = ctx
+            .budget
+            .remaining_tokens(&ctx.usage.snapshot().total, usd_per_token)
+        else {
+            return context;
+        };
+
+        let copies = ctx.agents.len() as u64 + 1;
+        let allowed = (remaining / 2 / copies)
+            .saturating_sub(PROMPT_OVERHEAD_TOKENS + tokenizer.count(query));
+        match truncate_to_tokens(tokenizer.as_ref(), fetched, allowed) {
+            Some(truncated) => {
+                warn!("Truncating fetched content to ~{} tokens to stay within the run budget.", allowed);
+                record.degrade(
+                    DegradationKind::ContextTruncated,
+                    format!(
+                        "Fetched content cut from ~{} to ~{} tokens.",
+                        tokenizer.count(fetched),
+                        allowed
+                    ),
+                );
+                format!("{}{}", query, truncated)
+            }
+            None => context,
+        }
+    }
+
+    async fn get_task_plan(&self, ctx: &RunContext, query: &str) -> Result<TaskPlan, Error> {
+        let max_subtasks = self.config.max_subtasks;
+        let roles = ctx
+            .agents
+            .iter()
+            .map(|agent| format!("- {}: {}", agent.name, agent.role))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let role_names: Vec<&str> = ctx.agents.iter().map(|a| a.name.as_str()).collect();
+
+        let response = self.complete(ctx, "task_master", &ctx.task_master_model, vec![
+            ChatMessage::system(format!(r#"
+                You are the task master coordinating a team of expert agents.
+                Given the user's query and any data we've gathered, break the work into between 1 and {max_subtasks} distinct sub-tasks.
+                Use as many sub-tasks as the query actually needs. Simple questions need few, complex ones need more.
+                Each sub-task needs a short title, complete self-contained instructions and the role best suited to it.
+                The agent working a sub-task only sees its own instructions, so be specific about what it should focus on.
+                Each sub-task must be unique.
+
+                Available roles:
+                {roles}
+                "#)),
+            ChatMessage::user(query.to_string())
+        ], Some(get_task_plan_schema(&role_names, max_subtasks))).await?;
+
+        match serde_json::from_str::<TaskPlan>(&response) {
+            Ok(mut plan) if !plan.subtasks.is_empty() => {
+                if plan.subtasks.len() > max_subtasks {
+                    warn!("Task master planned {} sub-tasks, keeping the first {}.", plan.subtasks.len(), max_subtasks);
+                    plan.subtasks.truncate(max_subtasks);
                 }
+                Ok(plan)
+            }
+            Ok(_) => {
+                warn!("Task master returned an empty plan, falling back to one sub-task per agent.");
+                Ok(fallback_plan(ctx))
+            }
+            Err(e) => {
+                warn!("Task master returned an invalid plan ({}), falling back to one sub-task per agent.", e);
+                Ok(fallback_plan(ctx))
             }
         }
+    }
+
+    // Runs every sub-task, then the failed ones again up to `agent_reruns` times, all within
+    // the agents stage's time. Results are kept as they come in, so what's done when time runs
+    // out isn't lost, and dropping the rest cancels their calls.
+    async fn run_agents(
+        &self,
+        ctx: &RunContext,
+        assignments: &[Assignment<'_>],
+        context: &str,
+        events: &ChoirEvents,
+    ) -> Vec<AgentOutcome> {
+        let stage_time = ctx.time_for("agents");
+        let started = Instant::now();
+        let mut outcomes: Vec<AgentOutcome> = assignments
+            .iter()
+            .map(|_| AgentOutcome {
+                result: None,
+                attempts: 0,
+            })
+            .collect();
+
+        for round in 0..=ctx.agent_reruns {
+            let failed: Vec<usize> = (0..assignments.len())
+                .filter(|&i| !matches!(outcomes[i].result, Some(Ok(_))))
+                .collect();
+            let time_left = stage_time.saturating_sub(started.elapsed());
+            if failed.is_empty() || time_left.is_zero() {
+                break;
+            }
+            if round > 0 {
+                info!("Re-running {} failed agents (rerun {}/{}).", failed.len(), round, ctx.agent_reruns);
+            }
+
+            let mut pending: FuturesUnordered<_> = failed
+                .iter()
+                .map(|&i| {
+                    outcomes[i].attempts += 1;
+                    let agent = self.run_agent(ctx, i, &assignments[i], context, events);
+                    async move { (i, agent.await) }
+                })
+                .collect();
+            let _ = actix_web::rt::time::timeout(time_left, async {
+                while let Some((i, result)) = pending.next().await {
+                    outcomes[i].result = Some(result);
+                }
+            })
+            .await;
+        }
+
+        for (i, assignment) in assignments.iter().enumerate() {
+            if outcomes[i].result.is_none() {
+                events.emit(ChoirEvent::AgentFinished {
+                    agent: i + 1,
+                    name: assignment.agent.name.clone(),
+                    task: assignment.task.title.clone(),
+                    success: false,
+                    short_overview: None,
+                });
+            }
+        }
+        outcomes
+    }
+
+    // One agent working one sub-task. The error is safe to show the caller.
+    async fn run_agent(
+        &self,
+        ctx: &RunContext,
+        i: usize,
+        assignment: &Assignment<'_>,
+        context: &str,
+        events: &ChoirEvents,
+    ) -> Result<ChoirAgentResponse, String> {
+        let span = info_span!(
+            "agent",
+            agent = i + 1,
+            name = %assignment.agent.name,
+            task = %assignment.task.title,
+        );
+        async move {
+            let agent = assignment.agent;
+            let task = assignment.task;
+
+            let res = self
+                .chat_with_tools(ctx, "agents", &agent.tools, CompletionRequest {
+                    model: agent.model.clone().unwrap_or_default(),
+                    messages: vec![
+                        ChatMessage::system(agent_system_prompt(agent)),
+                        ChatMessage::user(format!(
+                            "Sub-task: {}\n\n{}\n\n--- Context ---\n{}",
+                            task.title, task.instructions, context
+                        )),
+                    ],
+                    json_schema: Some(
+                        agent
+                            .output_schema
+                            .clone()
+                            .unwrap_or_else(get_choir_agent_response_schema),
+                    ),
+                    temperature: agent.temperature,
+                    max_tokens: ctx.max_tokens,
+                    ..Default::default()
+                })
+                .await;
 
-        Ok(successful_agents)
+            let agent_response = match res {
+                Ok(json) => match parse_agent_response(agent, &json) {
+                    Ok(agent_response) => {
+                        METRICS.agent_outcome("success");
+                        Ok(agent_response)
+                    }
+                    Err(e) => {
+                        METRICS.agent_outcome("parse_failure");
+                        error!("Agent {} ({}) failed to parse JSON: {}", i + 1, agent.name, e);
+                        error!("Agent {} raw response: {}", i + 1, json);
+                        Err(format!("Failed to parse agent response: {}", e))
+                    }
+                },
+                Err(e) => {
+                    METRICS.agent_outcome("provider_error");
+                    error!("Agent {} ({}) failed to respond: {}", i + 1, agent.name, e);
+                    Err(format!(
+                        "Agent failed to respond: {}",
+                        ChoirError::from(e).public_message()
+                    ))
+                }
+            };
+
+            // Emitted as each agent finishes rather than once they all have.
+            events.emit(ChoirEvent::AgentFinished {
+                agent: i + 1,
+                name: agent.name.clone(),
+                task: task.title.clone(),
+                success: agent_response.is_ok(),
+                short_overview: agent_response.as_ref().ok().map(|a| a.short_overview.clone()),
+            });
+
+            agent_response
+        }
+        .instrument(span)
+        .await
     }
 
     async fn get_assessment(
         &self,
+        ctx: &RunContext,
+        answers: &[Answer<'_>],
+    ) -> Result<String, Error> {
+        let agent_results = answers
+            .iter()
+            .map(|answer| {
+                format!(
+                    "Agent {} ({}) on \"{}\": {}",
+                    answer.agent,
+                    answer.assignment.agent.name,
+                    answer.assignment.task.title,
+                    answer.response.detailed_response
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        self.complete(ctx, "assessment", &ctx.assessment_model, vec![
+            ChatMessage::system(format!(r#"
+                You are chorus.
+                There have been {} distinctly unique sub agents. Each of these agents have been given a sub-task to solve.
+                You are to assess the results of each agent and determine the best course of action.
+                Your personal goal is to think VERY hard and weigh the general pros and cons of each approach. Finally, decide on a final course of action with the best result.
+                Your result may be anything. You are only allowed to go off of information provided by the sub agents.
+                Do not repeat yourself.
+                It can be a combination of approaches. It can be a single approach.
+                Think hard. Think VERY hard.
+                Your response should look as follows:
+                IN MARKDOWN FORMAT!
+                A brief overview of all results. Generally just to make sure its known.
+                The actual answer, the absolute best answer that you decided on. Keep this to the point and in a format the user would understand.
+                More info.
+                Post game thoughts on each agents approach.
+                "#, answers.len())),
+            ChatMessage::user(agent_results)
+        ], None).await
+    }
+
+    // Single completion using the run's temperature and token limits.
+    async fn complete(
+        &self,
+        ctx: &RunContext,
+        stage: &str,
         model: &str,
-        agents: &[ChoirAgentResponse],
-        json_schema: &Option<serde_json::Value>,
+        messages: Vec<ChatMessage>,
+        json_schema: Option<Value>,
+    ) -> Result<String, Error> {
+        self.chat(ctx, stage, CompletionRequest {
+            model: model.to_string(),
+            messages,
+            json_schema,
+            temperature: ctx.temperature,
+            max_tokens: ctx.max_tokens,
+            ..Default::default()
+        })
+        .await?
+        .content
+        .ok_or_else(no_content)
+    }
+
+    // A completion that may call the named AI functions before it answers. Each round is a
+    // call of its own through `chat`, and after `MAX_TOOL_ROUNDS` rounds the tools are taken
+    // away so the model has to answer with what it has.
+    async fn chat_with_tools(
+        &self,
+        ctx: &RunContext,
+        stage: &str,
+        tools: &[String],
+        mut request: CompletionRequest,
     ) -> Result<String, Error> {
-        self.openai_service.get_completion_response(
+        let functions: Vec<&dyn AIFunction> = self
+            .ai_functions
+            .iter()
+            .map(|f| f.as_ref())
+            .filter(|f| tools.iter().any(|t| t == f.name()))
+            .collect();
+        request.tools = functions.iter().map(|f| ToolDefinition::from_function(*f)).collect();
+
+        for round in 0..=MAX_TOOL_ROUNDS {
+            if round == MAX_TOOL_ROUNDS {
+                request.tools.clear();
+            }
+            let response = self.chat(ctx, stage, request.clone()).await?;
+            if response.tool_calls.is_empty() {
+                return response.content.ok_or_else(no_content);
+            }
+
+            request.messages.push(ChatMessage::Assistant {
+                content: response.content,
+                tool_calls: response.tool_calls.clone(),
+            });
+            for call in response.tool_calls {
+                let result = match functions.iter().find(|f| f.name() == call.name) {
+                    Some(function) => match serde_json::from_str(&call.arguments) {
+                        Ok(args) => match execute_function(*function, args).await {
+                            Ok(value) => value.to_string(),
+                            Err(e) => format!("Error: {}", e),
+                        },
+                        Err(e) => format!("Error: invalid arguments ({})", e),
+                    },
+                    None => format!("Function '{}' not found", call.name),
+                };
+                request.messages.push(ChatMessage::Tool {
+                    tool_call_id: call.id,
+                    content: result,
+                });
+            }
+        }
+        Err(ChoirError::provider(
+            format!("Model kept calling tools after {} rounds", MAX_TOOL_ROUNDS),
+            false,
+        )
+        .into())
+    }
+
+    // Every completion of a run goes through here so its usage is counted against the stage,
+    // and failed calls are retried and then passed down the stage's fallback chain.
+    async fn chat(
+        &self,
+        ctx: &RunContext,
+        stage: &str,
+        request: CompletionRequest,
+    ) -> Result<CompletionResponse, Error> {
+        let mut attempts = self.call_attempts(ctx, stage, &request.model);
+        loop {
+            let request = CompletionRequest {
+                model: attempts.model().to_string(),
+                ..request.clone()
+            };
+            match self.chat_once(ctx, stage, request).await {
+                Ok(response) => return Ok(response),
+                Err(e) => attempts.failed(e).await?,
+            }
+        }
+    }
+
+    fn call_attempts<'a>(
+        &'a self,
+        ctx: &'a RunContext,
+        stage: &'a str,
+        model: &str,
+    ) -> CallAttempts<'a> {
+        let fallbacks = ctx.fallbacks.stage(stage).map(Vec::as_slice).unwrap_or_default();
+        CallAttempts::new(&self.retry, &ctx.retries, stage, model, fallbacks)
+    }
+
+    async fn chat_once(
+        &self,
+        ctx: &RunContext,
+        stage: &str,
+        request: CompletionRequest,
+    ) -> Result<CompletionResponse, Error> {
+        let model = request.model.clone();
+        let (request, estimated) = self.fit_window(ctx, stage, request)?;
+        let span = llm_span(stage, &model);
+        span.record("estimated_prompt_tokens", estimated);
+        let started = Instant::now();
+        let response = async {
+            let response = self.llm.chat_completion(request).await?;
+            if let Some(usage) = &response.usage {
+                self.record_usage(ctx, stage, &model, usage, estimated);
+            }
+            Ok(response)
+        }
+        .instrument(span.clone())
+        .await;
+        span.record("latency_ms", started.elapsed().as_millis() as u64);
+        response
+    }
+
+    // A prompt too long for the model's context window, next to room for the reply, is
+    // brought down with `fit_messages` so the call still goes through. Returns the request
+    // and its prompt tokens.
+    fn fit_window(
+        &self,
+        ctx: &RunContext,
+        stage: &str,
+        mut request: CompletionRequest,
+    ) -> Result<(CompletionRequest, u64), Error> {
+        let prompt = self.tokenizers.count_messages(&request.model, &request.messages);
+        let Some(window) = self.tokenizers.context_window(&request.model) else {
+            return Ok((request, prompt));
+        };
+        let reply = request.max_tokens.map_or(COMPLETION_ESTIMATE_TOKENS, u64::from);
+        let limit = window.saturating_sub(reply);
+        if prompt <= limit {
+            return Ok((request, prompt));
+        }
+
+        let Some(fitted) = fit_messages(&self.tokenizers, &request.model, &mut request.messages, limit) else {
+            return Err(ChoirError::ContextOverflow(format!(
+                "The {} prompt of ~{} tokens doesn't fit the {} token context window of {}.",
+                stage, prompt, window, request.model
+            ))
+            .into());
+        };
+
+        let mut detail = format!(
+            "The {} prompt was cut from {} to {} tokens to fit the context window of {}",
+            stage, prompt, fitted.tokens, request.model
+        );
+        if fitted.dropped_rounds > 0 {
+            detail.push_str(&format!(", leaving out {} earlier rounds", fitted.dropped_rounds));
+        }
+        if fitted.cut {
+            detail.push_str(", shortening its longest message");
+        }
+        detail.push('.');
+        warn!("{}", detail);
+        ctx.cut_prompts.lock().unwrap().push(detail);
+        Ok((request, fitted.tokens))
+    }
+
+    fn record_usage(
+        &self,
+        ctx: &RunContext,
+        stage: &str,
+        model: &str,
+        usage: &TokenUsage,
+        estimated_prompt_tokens: u64,
+    ) {
+        let cost = self.prices.cost(model, usage);
+        ctx.usage.record(stage, usage, estimated_prompt_tokens, cost);
+        // Called from inside the completion's `llm_call` span.
+        let span = Span::current();
+        span.record("prompt_tokens", usage.prompt_tokens);
+        span.record("completion_tokens", usage.completion_tokens);
+        METRICS.tokens(
+            stage,
             model,
-            vec![
-                ChatCompletionRequestMessage::System(ChatCompletionRequestSystemMessage {
-                    content: ChatCompletionRequestSystemMessageContent::Text(r#"
-                    You are chorus.
-                    There have been 5 distinctly unique sub agents. Each of these agents have been given a task to solve.
-                    You are to assess the results of each agent and determine the best course of action.
-                    Your personal goal is to think VERY hard and weigh the general pros and cons of each approach. Finally, decide on a final course of action with the best result.
-                    Your result may be anything. You are only allowed to go off of information provided by the sub agents.
-                    Do not repeat yourself.
-                    It can be a combination of approaches. It can be a single approach.
-                    Think hard. Think VERY hard.
-                    Your response should look as follows:
-                    IN MARKDOWN FORMAT!
-                    A brief overview of all results. Generally just to make sure its known.
-                    The actual answer, the absolute best answer that you decided on. Keep this to the point and in a format the user would understand.
-                    More info.
-                    Post game thoughts on each agents approach.
-                    "#.to_string()),
-                    name: None,
-                }),
-                ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
-                    content: ChatCompletionRequestUserMessageContent::Text(format!(
-                        "Agent 1: {}\nAgent 2: {}\nAgent 3: {}\nAgent 4: {}\nAgent 5: {}",
-                        &agents[0].detailed_response,
-                        &agents[1].detailed_response,
-                        &agents[2].detailed_response,
-                        &agents[3].detailed_response,
-                        &agents[4].detailed_response
-                    )),
-                    name: None,
-                })
-            ],
-            json_schema.clone(),
-        ).await
+            usage.prompt_tokens,
+            usage.completion_tokens,
+            cost,
+        );
+        METRICS.prompt_estimate(
+            self.tokenizers.for_model(model).name(),
+            estimated_prompt_tokens,
+            usage.prompt_tokens as u64,
+        );
     }
 
-    async fn enrich_query_with_functions(&self, query: &str) -> Result<String, Error> {
+    // Fetches every URL in the query into `report`, one page at a time, so a stage cut
+    // short by its timeout keeps the pages fetched so far.
+    async fn enrich_query_with_functions(
+        &self,
+        query: &str,
+        no_cache: bool,
+        report: &mut FetchReport,
+        events: &ChoirEvents,
+    ) {
         // Check if query contains URLs
         let url_regex = regex::Regex::new(r"https?://[^\s]+").unwrap();
-        let urls: Vec<&str> = url_regex.find_iter(query).map(|m| m.as_str()).collect();
+        let urls: Vec<String> = url_regex
+            .find_iter(query)
+            .map(|m| m.as_str().to_string())
+            .collect();
 
         if urls.is_empty() {
-            return Ok(query.to_string());
+            return;
         }
 
         info!("Found {} URLs in query, fetching content...", urls.len());
-        let mut enriched_content = query.to_string();
 
-        for url in urls {
+        for url in &urls {
             if let Some(website_function) = self.ai_functions.iter().find(|f| f.name() == "website_to_md") {
                 let mut args = HashMap::new();
                 args.insert("url".to_string(), Value::String(url.to_string()));
+                if no_cache {
+                    args.insert("no_cache".to_string(), Value::Bool(true));
+                }
 
-                match website_function.execute(args).await {
+                match execute_function(website_function.as_ref(), args).await {
                     Ok(result) => {
+                        let cache_hit = result
+                            .get("cached")
+                            .and_then(|c| serde_json::from_value::<CacheHit>(c.clone()).ok());
+                        if let Some(hit) = cache_hit {
+                            report.cached.push(CachedUrl {
+                                url: url.to_string(),
+                                fetched_at: hit.fetched_at,
+                                revalidated: hit.revalidated,
+                            });
+                        }
                         if let Some(markdown) = result.get("markdown") {
                             if let Some(markdown_str) = markdown.as_str() {
-                                enriched_content.push_str(&format!(
-                                    "\n\n--- Content from {} ---\n{}",
-                                    url, markdown_str
-                                ));
+                                let source = match result.get("title").and_then(Value::as_str) {
+                                    Some(title) => format!("{} ({})", url, title),
+                                    None => url.to_string(),
+                                };
+                                report.pages.push(PageContent {
+                                    source,
+                                    markdown: markdown_str.to_string(),
+                                    condensed: false,
+                                });
                                 info!("Successfully fetched content from {}", url);
                             }
                         }
+                        events.emit(ChoirEvent::UrlFetched {
+                            url: url.to_string(),
+                            success: true,
+                            blocked: false,
+                            cached: cache_hit.is_some(),
+                            error: None,
+                        });
                     }
                     Err(e) => {
-                        error!("Failed to fetch content from {}: {}", url, e);
+                        let e = ChoirError::from(e);
+                        let is_blocked = if let ChoirError::UrlBlocked { url: at, reason } = &e {
+                            // A redirect or the host's address, rather than the URL itself.
+                            let reason = if at == url {
+                                reason.clone()
+                            } else {
+                                format!("{}: {}", at, reason)
+                            };
+                            warn!("Not fetching {}: {}", url, reason);
+                            report.blocked.push(BlockedUrl {
+                                url: url.to_string(),
+                                reason,
+                            });
+                            true
+                        } else {
+                            error!("Failed to fetch content from {}: {}", url, e);
+                            false
+                        };
+                        events.emit(ChoirEvent::UrlFetched {
+                            url: url.to_string(),
+                            success: false,
+                            blocked: is_blocked,
+                            cached: false,
+                            error: Some(e.public_message()),
+                        });
                         // Dont exit so we can continue with other URLs even if one fails
                     }
                 }
             }
         }
+    }
+}
+
+// What the URLs in a query turned up.
+#[derive(Default)]
+struct FetchReport {
+    pages: Vec<PageContent>,
+    /// Turned away by the URL policy.
+    blocked: Vec<BlockedUrl>,
+    /// Served from the page cache.
+    cached: Vec<CachedUrl>,
+}
+
+// Saves the run record when dropped, so runs cut short by cancellation are recorded too.
+struct RunRecorder<'a> {
+    history: &'a RunHistory,
+    record: RunRecord,
+    usage: Option<Arc<UsageMeter>>,
+    retries: Option<Arc<RetryLog>>,
+    started: Instant,
+}
+
+impl Drop for RunRecorder<'_> {
+    fn drop(&mut self) {
+        if self.record.status == RunStatus::Running {
+            self.record.status = RunStatus::Cancelled;
+        }
+        self.record.finished_at = Some(Utc::now());
+        self.record.duration_ms = self.started.elapsed().as_millis() as u64;
+        if let Some(usage) = &self.usage {
+            self.record.usage = usage.snapshot();
+        }
+        if let Some(retries) = &self.retries {
+            self.record.retries = retries.snapshot();
+        }
+
+        METRICS.run_finished(self.record.status.as_str());
+        for timing in &self.record.timings {
+            METRICS.stage(&timing.stage, Duration::from_millis(timing.duration_ms));
+        }
+
+        if let Err(e) = self.history.save(&self.record) {
+            error!("Failed to save run {}: {}", self.record.id, e);
+        }
+    }
+}
+
+// A completion that came back empty. Usually a provider hiccup, so worth retrying.
+fn no_content() -> Error {
+    ChoirError::provider("No content in response", true).into()
+}
+
+// Run a stage within the time it has left. None if it was cut off, which drops whatever
+// calls it still had in flight.
+async fn in_time<T>(ctx: &RunContext, stage: &str, work: impl Future<Output = T>) -> Option<T> {
+    actix_web::rt::time::timeout(ctx.time_for(stage), work)
+        .await
+        .ok()
+}
+
+fn out_of_time(stage: &str) -> Error {
+    ChoirError::Timeout(format!(
+        "The run hit its deadline during the {} stage, before there was anything to answer with",
+        stage
+    ))
+    .into()
+}
+
+// Out of time: answer with the assessment if there is one, otherwise with whatever the
+// agents came back with. A structured answer can't be pieced together like that.
+fn partial_answer(ctx: &RunContext, record: &mut RunRecord, stage: &str) -> Result<Value, Error> {
+    if ctx.output.is_some() {
+        return Err(out_of_time(stage));
+    }
+
+    let (answer, source) = match &record.assessment {
+        Some(assessment) => (assessment.clone(), "the assessment"),
+        None => {
+            let responses: Vec<String> = record
+                .agents
+                .iter()
+                .filter_map(|run| {
+                    let response = run.response.as_ref()?;
+                    Some(format!("## {}: {}\n\n{}", run.name, run.task, response.detailed_response))
+                })
+                .collect();
+            if responses.is_empty() {
+                return Err(out_of_time(stage));
+            }
+            (responses.join("\n\n"), "the agent responses")
+        }
+    };
+
+    warn!("Ran out of time in the {} stage, answering with {}.", stage, source);
+    record.degrade(
+        DegradationKind::PartialAnswer,
+        format!("Ran out of time in the {} stage, answered with {}.", stage, source),
+    );
+    Ok(Value::String(answer))
+}
+
+// Span around one pipeline stage, named after it in the trace.
+fn stage_span(stage: &str) -> Span {
+    info_span!("stage", otel.name = stage, stage)
+}
+
+// Span around one completion. Tokens are filled in by `record_usage`, latency by the caller.
+fn llm_span(stage: &str, model: &str) -> Span {
+    info_span!(
+        "llm_call",
+        stage,
+        model,
+        estimated_prompt_tokens = Empty,
+        prompt_tokens = Empty,
+        completion_tokens = Empty,
+        latency_ms = Empty,
+    )
+}
+
+fn summary_request(
+    ctx: &RunContext,
+    request: &ChoirRequest,
+    assessment: Option<&str>,
+    answers: &[Answer<'_>],
+) -> CompletionRequest {
+    let analysis = match assessment {
+        Some(assessment) => format!("Expert analysis: {}\n\n", assessment),
+        None => String::new(),
+    };
 
-        Ok(enriched_content)
+    CompletionRequest {
+        model: ctx.summary_model.clone(),
+        messages: vec![
+            ChatMessage::system(r#"
+                You are the final summary agent. Your job is to provide the user with a direct, accurate answer to their question.
+                You have access to webpage content and analysis from multiple expert agents.
+                Be specific and factual. If you can answer the user's question directly, do so.
+                Do not say "the agents didn't find" unless you're absolutely certain the information isn't in the data provided.
+                "#),
+            ChatMessage::user(format!("User's original query: {}\n\n{}Detailed agent responses: {:#?}",
+                request.query,
+                analysis,
+                answers.iter().map(|a| a.response.detailed_response.clone()).collect::<Vec<_>>()
+            )),
+        ],
+        temperature: ctx.temperature,
+        max_tokens: ctx.max_tokens,
+        ..Default::default()
     }
 }
+
+// A fetched page, or notes on one, as it's handed to the task master and agents.
+struct PageContent {
+    /// The page's URL and title.
+    source: String,
+    markdown: String,
+    /// Only what's relevant to the query, see `condense_pages`.
+    condensed: bool,
+}
+
+// A piece of a page that's condensed on its own.
+struct Chunk {
+    page: usize,
+    /// Which piece of its page this is, from 1.
+    part: usize,
+    parts: usize,
+    text: String,
+}
+
+// A sub-task paired with the agent that will work on it.
+struct Assignment<'a> {
+    agent: &'a AgentDefinition,
+    task: &'a SubTask,
+}
+
+// How one sub-task went. `result` is None if it never finished before the agents stage ran
+// out of time.
+struct AgentOutcome {
+    result: Option<Result<ChoirAgentResponse, String>>,
+    attempts: u32,
+}
+
+// An agent that answered its sub-task, numbered as in the plan.
+struct Answer<'a> {
+    agent: usize,
+    assignment: &'a Assignment<'a>,
+    response: ChoirAgentResponse,
+}
+
+// The pages as they're appended to the query.
+fn render_pages(pages: &[PageContent]) -> String {
+    pages
+        .iter()
+        .map(|page| {
+            if page.condensed {
+                format!(
+                    "\n\n--- Notes from {}, as relevant to the query ---\n{}",
+                    page.source, page.markdown
+                )
+            } else {
+                format!("\n\n--- Content from {} ---\n{}", page.source, page.markdown)
+            }
+        })
+        .collect()
+}
+
+fn page_tokens(tokenizer: &dyn Tokenizer, pages: &[PageContent]) -> u64 {
+    pages.iter().map(|page| tokenizer.count(&page.markdown)).sum()
+}
+
+// Cut every page down to its share of `limit`, in proportion to its length.
+fn cut_pages(
+    tokenizer: &dyn Tokenizer,
+    pages: Vec<PageContent>,
+    limit: u64,
+    record: &mut RunRecord,
+    why: &str,
+) -> Vec<PageContent> {
+    let total = page_tokens(tokenizer, &pages).max(1);
+    record.degrade(
+        DegradationKind::ContextTruncated,
+        format!("{}, so fetched content was cut from ~{} to ~{} tokens.", why, total, limit),
+    );
+    pages
+        .into_iter()
+        .map(|page| {
+            let share = limit * tokenizer.count(&page.markdown) / total;
+            PageContent {
+                markdown: truncate_to_tokens(tokenizer, &page.markdown, share).unwrap_or(page.markdown),
+                ..page
+            }
+        })
+        .collect()
+}
+
+// The map step's answer for a chunk with nothing relevant in it.
+fn is_irrelevant(notes: &str) -> bool {
+    notes.trim().trim_end_matches('.').eq_ignore_ascii_case("none")
+}
+
+// Unknown roles are spread over the available agents so every sub-task still gets worked.
+fn assign_subtasks<'a>(ctx: &'a RunContext, plan: &'a TaskPlan) -> Vec<Assignment<'a>> {
+    plan.subtasks
+        .iter()
+        .enumerate()
+        .map(|(i, task)| Assignment {
+            agent: ctx
+                .agents
+                .iter()
+                .find(|a| a.name.eq_ignore_ascii_case(task.suggested_role.trim()))
+                .unwrap_or(&ctx.agents[i % ctx.agents.len()]),
+            task,
+        })
+        .collect()
+}
+
+// One generic sub-task per agent, used when the task master's plan is unusable.
+fn fallback_plan(ctx: &RunContext) -> TaskPlan {
+    TaskPlan {
+        subtasks: ctx
+            .agents
+            .iter()
+            .map(|agent| SubTask {
+                title: format!("{} analysis", agent.name),
+                instructions: "Answer the user's query in the context from your own perspective.".to_string(),
+                suggested_role: agent.name.clone(),
+            })
+            .collect(),
+    }
+}
+
+fn agent_system_prompt(agent: &AgentDefinition) -> String {
+    let output = if agent.output_schema.is_some() {
+        "You MUST respond with a valid JSON object matching the provided schema.".to_string()
+    } else {
+        r#"You MUST respond with a valid JSON object containing exactly these three fields:
+            - "detailed_response": Your comprehensive analysis (multiple paragraphs)
+            - "short_overview": Brief 2-3 sentence summary
+            - "thoughts": Your analytical thoughts and reasoning"#
+            .to_string()
+    };
+
+    format!(
+        "You are {}. Work only on the sub-task you are given.\n{}\n{}",
+        agent.name, output, agent.role
+    )
+}
+
+// Agents with a custom output schema keep their whole JSON answer as the detailed response.
+fn parse_agent_response(agent: &AgentDefinition, json: &str) -> Result<ChoirAgentResponse, Error> {
+    if agent.output_schema.is_none() {
+        return Ok(serde_json::from_str::<ChoirAgentResponse>(json)?);
+    }
+
+    let value: Value = serde_json::from_str(json)?;
+    let field = |name: &str| {
+        value
+            .get(name)
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string()
+    };
+
+    Ok(ChoirAgentResponse {
+        detailed_response: serde_json::to_string_pretty(&value)?,
+        short_overview: field("short_overview"),
+        thoughts: field("thoughts"),
+    })
+}
@@ -0,0 +1,125 @@
+use crate::config::EnvConfig;
+use crate::modules::breaker::CircuitBreaker;
+use crate::modules::firecrawl::FirecrawlFetcher;
+use crate::modules::native_fetcher::NativeFetcher;
+use crate::modules::pagecache::PageCache;
+use crate::modules::urlpolicy::UrlPolicy;
+use crate::types::tfetch::{FetchedPage, Validators};
+use crate::Error;
+use async_trait::async_trait;
+use std::sync::Arc;
+use std::time::Duration;
+
+// Turns a URL into Markdown for the prompts.
+#[async_trait]
+pub trait Fetcher: Send + Sync {
+    fn name(&self) -> &'static str;
+    async fn fetch(&self, url: &str) -> Result<FetchedPage, Error>;
+
+    // Like `fetch`, but never served from a cache in front of the fetcher.
+    async fn fetch_fresh(&self, url: &str) -> Result<FetchedPage, Error> {
+        self.fetch(url).await
+    }
+
+    // Fetch the page only if it changed since `validators` were handed out, Ok(None) if it
+    // hasn't. Fetchers that can't ask just fetch it again.
+    async fn fetch_if_changed(&self, url: &str, validators: &Validators) -> Result<Option<FetchedPage>, Error> {
+        let _ = validators;
+        self.fetch(url).await.map(Some)
+    }
+
+    // Succeeds when the fetcher can do its job, for the readiness check.
+    async fn health_check(&self) -> Result<(), Error>;
+}
+
+// Puts a fetcher behind a circuit breaker, like `GuardedProvider` does for the LLM.
+pub struct GuardedFetcher {
+    inner: Arc<dyn Fetcher>,
+    breaker: Arc<CircuitBreaker>,
+}
+
+#[async_trait]
+impl Fetcher for GuardedFetcher {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    async fn fetch(&self, url: &str) -> Result<FetchedPage, Error> {
+        self.breaker.call(self.inner.fetch(url)).await
+    }
+
+    async fn fetch_fresh(&self, url: &str) -> Result<FetchedPage, Error> {
+        self.breaker.call(self.inner.fetch_fresh(url)).await
+    }
+
+    async fn fetch_if_changed(&self, url: &str, validators: &Validators) -> Result<Option<FetchedPage>, Error> {
+        self.breaker.call(self.inner.fetch_if_changed(url, validators)).await
+    }
+
+    async fn health_check(&self) -> Result<(), Error> {
+        self.inner.health_check().await
+    }
+}
+
+// Turns away URLs the policy doesn't allow before the fetcher sees them.
+pub struct PolicedFetcher {
+    inner: Arc<dyn Fetcher>,
+    policy: Arc<UrlPolicy>,
+}
+
+#[async_trait]
+impl Fetcher for PolicedFetcher {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    async fn fetch(&self, url: &str) -> Result<FetchedPage, Error> {
+        self.policy.check(url).await?;
+        self.inner.fetch(url).await
+    }
+
+    async fn fetch_fresh(&self, url: &str) -> Result<FetchedPage, Error> {
+        self.policy.check(url).await?;
+        self.inner.fetch_fresh(url).await
+    }
+
+    async fn fetch_if_changed(&self, url: &str, validators: &Validators) -> Result<Option<FetchedPage>, Error> {
+        self.policy.check(url).await?;
+        self.inner.fetch_if_changed(url, validators).await
+    }
+
+    async fn health_check(&self) -> Result<(), Error> {
+        self.inner.health_check().await
+    }
+}
+
+// Build the fetcher selected by `FETCHER`, behind the page cache and the URL policy, in
+// that order so cached pages are still checked against the policy. Only Firecrawl goes
+// behind the breaker: the native fetcher talks to every site directly, and one site
+// being down says nothing about the next.
+pub fn from_config(config: &EnvConfig, breaker: Arc<CircuitBreaker>) -> Result<Arc<dyn Fetcher>, Error> {
+    let policy = Arc::new(UrlPolicy::from_config(config));
+    let mut inner: Arc<dyn Fetcher> = match config.fetcher.as_str() {
+        "firecrawl" => {
+            let key = config
+                .firecrawl_key
+                .as_deref()
+                .ok_or("FC_KEY must be set to use the firecrawl fetcher")?;
+            let firecrawl = Arc::new(FirecrawlFetcher::new(key)?);
+            Arc::new(GuardedFetcher {
+                inner: firecrawl,
+                breaker,
+            })
+        }
+        "native" => Arc::new(NativeFetcher::new(
+            Duration::from_secs(config.fetch_timeout_secs),
+            config.fetch_max_bytes,
+            policy.clone(),
+        )?),
+        other => return Err(format!("Unknown fetcher '{}', expected native or firecrawl", other).into()),
+    };
+    if !config.fetch_cache_ttl.is_zero() {
+        inner = Arc::new(PageCache::open(&config.database_path, inner, config)?);
+    }
+    Ok(Arc::new(PolicedFetcher { inner, policy }))
+}
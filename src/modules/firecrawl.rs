@@ -0,0 +1,70 @@
+use crate::modules::fetcher::Fetcher;
+use crate::types::terror::ChoirError;
+use crate::types::tfetch::{FetchedPage, PageMetadata, Validators};
+use crate::Error;
+use async_trait::async_trait;
+use firecrawl::scrape::{ScrapeFormats, ScrapeOptions};
+use firecrawl::FirecrawlApp;
+
+// Where the Firecrawl API lives. Any HTTP response at all means it's reachable.
+const FIRECRAWL_URL: &str = "https://api.firecrawl.dev";
+
+// Has Firecrawl fetch and convert pages.
+pub struct FirecrawlFetcher {
+    client: FirecrawlApp,
+    http: reqwest::Client,
+}
+
+impl FirecrawlFetcher {
+    pub fn new(api_key: &str) -> Result<Self, Error> {
+        let client = FirecrawlApp::new(api_key)
+            .map_err(|e| format!("Failed to create Firecrawl client: {}", e))?;
+        Ok(Self {
+            client,
+            http: reqwest::Client::new(),
+        })
+    }
+}
+
+#[async_trait]
+impl Fetcher for FirecrawlFetcher {
+    fn name(&self) -> &'static str {
+        "firecrawl"
+    }
+
+    async fn fetch(&self, url: &str) -> Result<FetchedPage, Error> {
+        let options = ScrapeOptions {
+            formats: Some(vec![ScrapeFormats::Markdown]),
+            ..Default::default()
+        };
+
+        let document = self.client.scrape_url(url, options).await.map_err(|e| {
+            Error::from(ChoirError::FetchFailed {
+                url: url.to_string(),
+                message: e.to_string(),
+            })
+        })?;
+
+        let metadata = document.metadata;
+        Ok(FetchedPage {
+            url: url.to_string(),
+            markdown: document.markdown.unwrap_or_default(),
+            title: metadata.og_title.or(metadata.title),
+            metadata: PageMetadata {
+                description: metadata.description.or(metadata.og_description),
+                author: None,
+                site_name: metadata.og_site_name,
+                published: metadata.published_time,
+                language: metadata.language,
+                canonical_url: metadata.og_url,
+            },
+            cached: None,
+            validators: Validators::default(),
+        })
+    }
+
+    async fn health_check(&self) -> Result<(), Error> {
+        self.http.head(FIRECRAWL_URL).send().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,114 @@
+use crate::modules::breaker::CircuitBreaker;
+use crate::modules::fetcher::Fetcher;
+use crate::modules::history::RunHistory;
+use crate::modules::provider::LLMProvider;
+use crate::types::thealth::{ComponentHealth, ComponentStatus, HealthReport};
+use crate::Error;
+use chrono::Utc;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use log::warn;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// A probe slower than this counts as down.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Probes the services a run depends on. Results are cached briefly so load balancer
+// polling doesn't turn into a stream of provider calls.
+pub struct HealthChecker {
+    llm: Arc<dyn LLMProvider>,
+    fetcher: Arc<dyn Fetcher>,
+    history: Arc<RunHistory>,
+    /// Reported live with their upstream's component, never cached.
+    breakers: Vec<Arc<CircuitBreaker>>,
+    cache_ttl: Duration,
+    cached: Mutex<Option<(Instant, HealthReport)>>,
+}
+
+impl HealthChecker {
+    pub fn new(
+        llm: Arc<dyn LLMProvider>,
+        fetcher: Arc<dyn Fetcher>,
+        history: Arc<RunHistory>,
+        breakers: Vec<Arc<CircuitBreaker>>,
+        cache_ttl: Duration,
+    ) -> Self {
+        Self {
+            llm,
+            fetcher,
+            history,
+            breakers,
+            cache_ttl,
+            cached: Mutex::new(None),
+        }
+    }
+
+    // The probe results, possibly cached, with the current state of each circuit.
+    // An open circuit doesn't make the report down by itself, the probes decide that.
+    pub async fn check(&self) -> HealthReport {
+        let mut report = self.probe().await;
+        for component in &mut report.components {
+            component.circuit = self
+                .breakers
+                .iter()
+                .find(|b| b.upstream() == component.name)
+                .map(|b| b.health());
+        }
+        report
+    }
+
+    async fn probe(&self) -> HealthReport {
+        if let Some((at, report)) = self.cached.lock().unwrap().as_ref() {
+            if at.elapsed() < self.cache_ttl {
+                return report.clone();
+            }
+        }
+
+        let probes: Vec<(&str, BoxFuture<'_, Result<(), Error>>)> = vec![
+            ("llm", self.llm.health_check()),
+            ("fetcher", self.fetcher.health_check()),
+            ("database", async { self.history.ping() }.boxed()),
+        ];
+        let components =
+            futures::future::join_all(probes.into_iter().map(|(name, probe)| run_probe(name, probe)))
+                .await;
+
+        let status = if components.iter().all(|c| c.status == ComponentStatus::Up) {
+            ComponentStatus::Up
+        } else {
+            ComponentStatus::Down
+        };
+        let report = HealthReport {
+            status,
+            components,
+            checked_at: Utc::now(),
+        };
+
+        *self.cached.lock().unwrap() = Some((Instant::now(), report.clone()));
+        report
+    }
+}
+
+async fn run_probe(name: &str, probe: BoxFuture<'_, Result<(), Error>>) -> ComponentHealth {
+    let started = Instant::now();
+    let result = match actix_web::rt::time::timeout(PROBE_TIMEOUT, probe).await {
+        Ok(result) => result,
+        Err(_) => Err(format!("Timed out after {}s", PROBE_TIMEOUT.as_secs()).into()),
+    };
+    // The report is public, and errors can name internal hosts or echo provider responses.
+    let status = match result {
+        Ok(()) => ComponentStatus::Up,
+        Err(e) => {
+            warn!("Health probe of {} failed: {}", name, e);
+            ComponentStatus::Down
+        }
+    };
+
+    ComponentHealth {
+        name: name.to_string(),
+        status,
+        latency_ms: started.elapsed().as_millis() as u64,
+        circuit: None,
+    }
+}
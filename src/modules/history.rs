@@ -0,0 +1,211 @@
+use crate::types::trun::{KeyUsage, RunList, RunListQuery, RunRecord, RunSummary, UsageQuery};
+use crate::Error;
+use chrono::{DateTime, SecondsFormat, Utc};
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension};
+use std::sync::Mutex;
+use std::time::Duration;
+use uuid::Uuid;
+
+const MAX_PER_PAGE: u32 = 100;
+
+// Run history in an embedded SQLite database. Filterable columns are broken out,
+// the full record is kept as JSON so it can grow without migrations.
+pub struct RunHistory {
+    conn: Mutex<Connection>,
+}
+
+impl RunHistory {
+    pub fn open(path: &str) -> Result<Self, Error> {
+        let conn = Connection::open(path)?;
+        // The key store writes to the same file through its own connection.
+        conn.busy_timeout(Duration::from_secs(5))?;
+        conn.execute_batch(
+            r#"
+            CREATE TABLE IF NOT EXISTS runs (
+                id TEXT PRIMARY KEY,
+                status TEXT NOT NULL,
+                query TEXT NOT NULL,
+                error TEXT,
+                started_at TEXT NOT NULL,
+                duration_ms INTEGER NOT NULL,
+                record TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS runs_started_at ON runs (started_at);
+            CREATE INDEX IF NOT EXISTS runs_status ON runs (status);
+            "#,
+        )?;
+
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    pub fn ping(&self) -> Result<(), Error> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row("SELECT 1", [], |_| Ok(()))?;
+        Ok(())
+    }
+
+    pub fn save(&self, record: &RunRecord) -> Result<(), Error> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT OR REPLACE INTO runs (id, status, query, error, started_at, duration_ms, record)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                record.id.to_string(),
+                record.status.as_str(),
+                record.request.query,
+                record.error,
+                timestamp(&record.started_at),
+                record.duration_ms as i64,
+                serde_json::to_string(record)?,
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn get(&self, id: Uuid) -> Result<Option<RunRecord>, Error> {
+        let conn = self.conn.lock().unwrap();
+        let record: Option<String> = conn
+            .query_row(
+                "SELECT record FROM runs WHERE id = ?1",
+                params![id.to_string()],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        match record {
+            Some(json) => Ok(Some(serde_json::from_str(&json)?)),
+            None => Ok(None),
+        }
+    }
+
+    // Newest first.
+    pub fn list(&self, query: &RunListQuery) -> Result<RunList, Error> {
+        let page = query.page.unwrap_or(1).max(1);
+        let per_page = query.per_page.unwrap_or(20).clamp(1, MAX_PER_PAGE);
+
+        let mut filters = Vec::new();
+        let mut args = Vec::new();
+        if let Some(status) = query.status {
+            filters.push("status = ?");
+            args.push(status.as_str().to_string());
+        }
+        if let Some(from) = &query.from {
+            filters.push("started_at >= ?");
+            args.push(timestamp(from));
+        }
+        if let Some(to) = &query.to {
+            filters.push("started_at < ?");
+            args.push(timestamp(to));
+        }
+        let where_clause = if filters.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", filters.join(" AND "))
+        };
+
+        let conn = self.conn.lock().unwrap();
+        let total: i64 = conn.query_row(
+            &format!("SELECT COUNT(*) FROM runs {}", where_clause),
+            params_from_iter(args.iter()),
+            |row| row.get(0),
+        )?;
+
+        let mut stmt = conn.prepare(&format!(
+            "SELECT id, status, query, error, started_at, duration_ms FROM runs {}
+             ORDER BY started_at DESC LIMIT {} OFFSET {}",
+            where_clause,
+            per_page,
+            (page - 1) as u64 * per_page as u64
+        ))?;
+
+        let rows = stmt.query_map(params_from_iter(args.iter()), |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, Option<String>>(3)?,
+                row.get::<_, String>(4)?,
+                row.get::<_, i64>(5)?,
+            ))
+        })?;
+
+        let mut runs = Vec::new();
+        for row in rows {
+            let (id, status, query, error, started_at, duration_ms) = row?;
+            runs.push(RunSummary {
+                id: Uuid::parse_str(&id)?,
+                status: serde_json::from_value(serde_json::Value::String(status))?,
+                query,
+                error,
+                started_at: DateTime::parse_from_rfc3339(&started_at)?.with_timezone(&Utc),
+                duration_ms: duration_ms as u64,
+            });
+        }
+
+        Ok(RunList {
+            runs,
+            page,
+            per_page,
+            total: total as u64,
+        })
+    }
+
+    // Runs started and tokens used by one API key since `since`.
+    pub fn key_usage_since(&self, api_key_id: &str, since: &DateTime<Utc>) -> Result<(u64, u64), Error> {
+        let conn = self.conn.lock().unwrap();
+        let (runs, tokens): (i64, i64) = conn.query_row(
+            "SELECT COUNT(*), COALESCE(SUM(json_extract(record, '$.usage.total.total_tokens')), 0)
+             FROM runs WHERE started_at >= ?1 AND json_extract(record, '$.api_key_id') = ?2",
+            params![timestamp(since), api_key_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        Ok((runs as u64, tokens as u64))
+    }
+
+    // Cumulative usage per API key, read straight out of the stored records. Most expensive first.
+    pub fn usage_by_key(&self, query: &UsageQuery) -> Result<Vec<KeyUsage>, Error> {
+        let mut filters = vec!["json_extract(record, '$.api_key_id') IS NOT NULL"];
+        let mut args = Vec::new();
+        if let Some(from) = &query.from {
+            filters.push("started_at >= ?");
+            args.push(timestamp(from));
+        }
+        if let Some(to) = &query.to {
+            filters.push("started_at < ?");
+            args.push(timestamp(to));
+        }
+
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT json_extract(record, '$.api_key_id') AS key,
+                    COUNT(*),
+                    COALESCE(SUM(json_extract(record, '$.usage.total.prompt_tokens')), 0),
+                    COALESCE(SUM(json_extract(record, '$.usage.total.completion_tokens')), 0),
+                    COALESCE(SUM(json_extract(record, '$.usage.total.total_tokens')), 0),
+                    COALESCE(SUM(json_extract(record, '$.usage.total.cost_usd')), 0.0)
+             FROM runs WHERE {}
+             GROUP BY key ORDER BY 6 DESC",
+            filters.join(" AND ")
+        ))?;
+
+        let rows = stmt.query_map(params_from_iter(args.iter()), |row| {
+            Ok(KeyUsage {
+                api_key_id: row.get(0)?,
+                runs: row.get::<_, i64>(1)? as u64,
+                prompt_tokens: row.get::<_, i64>(2)? as u64,
+                completion_tokens: row.get::<_, i64>(3)? as u64,
+                total_tokens: row.get::<_, i64>(4)? as u64,
+                cost_usd: row.get(5)?,
+            })
+        })?;
+
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+}
+
+// Fixed width UTC timestamps so string comparison in SQL orders correctly.
+pub(crate) fn timestamp(time: &DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Micros, true)
+}
@@ -0,0 +1,180 @@
+use crate::modules::choir::{ChoirEvents, ChoirService};
+use crate::modules::limits::RunPermit;
+use crate::types::tchoir::ChoirRequest;
+use crate::types::tjob::{ChoirJob, JobStatus};
+use crate::Error;
+use chrono::Utc;
+use futures::StreamExt;
+use log::{error, info};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::task::AbortHandle;
+use tracing::Instrument;
+use uuid::Uuid;
+
+// Finished jobs are dropped from memory after this long. Persistence may keep them.
+const FINISHED_JOB_RETENTION_SECS: i64 = 60 * 60;
+
+// Somewhere jobs are written to so they outlive the in-memory store.
+pub trait JobPersistence: Send + Sync {
+    fn save(&self, job: &ChoirJob) -> Result<(), Error>;
+    fn load(&self, id: Uuid) -> Result<Option<ChoirJob>, Error>;
+}
+
+// Default persistence: nothing outlives the process.
+pub struct NoJobPersistence;
+
+impl JobPersistence for NoJobPersistence {
+    fn save(&self, _job: &ChoirJob) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn load(&self, _id: Uuid) -> Result<Option<ChoirJob>, Error> {
+        Ok(None)
+    }
+}
+
+pub struct JobStore {
+    jobs: RwLock<HashMap<Uuid, ChoirJob>>,
+    handles: Mutex<HashMap<Uuid, AbortHandle>>,
+    persistence: Arc<dyn JobPersistence>,
+}
+
+impl JobStore {
+    pub fn new(persistence: Arc<dyn JobPersistence>) -> Self {
+        Self {
+            jobs: RwLock::new(HashMap::new()),
+            handles: Mutex::new(HashMap::new()),
+            persistence,
+        }
+    }
+
+    pub fn get(&self, id: Uuid) -> Option<ChoirJob> {
+        if let Some(job) = self.jobs.read().unwrap().get(&id) {
+            return Some(job.clone());
+        }
+
+        self.persistence.load(id).unwrap_or_else(|e| {
+            error!("Failed to load job {}: {}", id, e);
+            None
+        })
+    }
+
+    // Queue a choir run in the background and return the job immediately.
+    // The permit is held until the run ends, however it ends.
+    pub fn start(
+        self: &Arc<Self>,
+        service: Arc<ChoirService>,
+        request: ChoirRequest,
+        api_key_id: Option<String>,
+        permit: RunPermit,
+    ) -> ChoirJob {
+        self.prune_finished();
+
+        let job = ChoirJob::new(request.clone(), api_key_id.clone());
+        let id = job.id;
+        self.jobs.write().unwrap().insert(id, job.clone());
+        self.persist(&job);
+
+        let store = Arc::clone(self);
+        let handle = actix_web::rt::spawn(async move {
+            let _permit = permit;
+            store.update(id, true, |job| job.status = JobStatus::Running);
+
+            let (tx, mut rx) = futures::channel::mpsc::unbounded();
+            let run = async {
+                // Dropping the sink when the run ends closes the progress stream below.
+                let events = ChoirEvents::new(tx);
+                // The job id doubles as the run id in the run history.
+                service
+                    .run_choir_with_events(id, &request, api_key_id, &events)
+                    .await
+            };
+            let progress = async {
+                while let Some(event) = rx.next().await {
+                    store.update(id, false, |job| job.stages.apply(&event));
+                }
+            };
+
+            let (result, ()) = futures::join!(run, progress);
+            store.handles.lock().unwrap().remove(&id);
+
+            match result {
+                Ok(result) => {
+                    info!("Job {} finished.", id);
+                    store.update(id, true, |job| {
+                        job.status = JobStatus::Succeeded;
+                        job.result = Some(result.answer);
+                        job.meta = Some(result.meta);
+                    });
+                }
+                Err(e) => {
+                    error!("Job {} failed: {}", id, e);
+                    store.update(id, true, |job| {
+                        job.status = JobStatus::Failed;
+                        job.error = Some(e.public_message());
+                        job.error_code = Some(e.code());
+                        job.retryable = Some(e.is_retryable());
+                    });
+                }
+            }
+        }
+        // Keep the run in the trace of the request that queued it.
+        .in_current_span());
+
+        self.handles
+            .lock()
+            .unwrap()
+            .insert(id, handle.abort_handle());
+
+        job
+    }
+
+    // Abort the run, dropping any in-flight agent futures. None if the job doesn't exist.
+    pub fn cancel(&self, id: Uuid) -> Option<ChoirJob> {
+        let job = self.get(id)?;
+        if job.status.is_finished() {
+            return Some(job);
+        }
+
+        if let Some(handle) = self.handles.lock().unwrap().remove(&id) {
+            handle.abort();
+        }
+
+        info!("Job {} cancelled.", id);
+        self.update(id, true, |job| job.status = JobStatus::Cancelled)
+    }
+
+    fn update(&self, id: Uuid, persist: bool, f: impl FnOnce(&mut ChoirJob)) -> Option<ChoirJob> {
+        let job = {
+            let mut jobs = self.jobs.write().unwrap();
+            let job = jobs.get_mut(&id)?;
+            // A cancelled job stays cancelled even if the run races to completion.
+            if job.status == JobStatus::Cancelled {
+                return Some(job.clone());
+            }
+            f(job);
+            job.updated_at = Utc::now();
+            job.clone()
+        };
+
+        if persist {
+            self.persist(&job);
+        }
+        Some(job)
+    }
+
+    fn persist(&self, job: &ChoirJob) {
+        if let Err(e) = self.persistence.save(job) {
+            error!("Failed to persist job {}: {}", job.id, e);
+        }
+    }
+
+    fn prune_finished(&self) {
+        let cutoff = Utc::now() - chrono::Duration::seconds(FINISHED_JOB_RETENTION_SECS);
+        self.jobs
+            .write()
+            .unwrap()
+            .retain(|_, job| !job.status.is_finished() || job.updated_at > cutoff);
+    }
+}
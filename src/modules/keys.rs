@@ -0,0 +1,369 @@
+use crate::modules::history::timestamp;
+use crate::types::tkey::{ApiKey, CreateKeyRequest, CreatedKey, KeyLimits, Scope, UpdateKeyRequest};
+use crate::Error;
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use sha2::{Digest, Sha256};
+use std::sync::Mutex;
+use std::time::Duration;
+use uuid::Uuid;
+
+// Tokens look like `ck_<id>_<secret>`. The id finds the row, the secret is checked against its hash.
+const TOKEN_PREFIX: &str = "ck_";
+
+// Id the `API_KEY` environment key is reported under.
+pub const ENV_KEY_ID: &str = "env";
+
+// API keys in the SQLite database, stored as SHA-256 hashes of their secrets.
+pub struct KeyStore {
+    conn: Mutex<Connection>,
+    /// `API_KEY`, if set, is accepted as an admin key so the first stored keys can be created.
+    env_key: Option<ApiKey>,
+    env_key_hash: Option<String>,
+}
+
+impl KeyStore {
+    pub fn open(path: &str, env_key: Option<&str>) -> Result<Self, Error> {
+        let conn = Connection::open(path)?;
+        conn.busy_timeout(Duration::from_secs(5))?;
+        conn.execute_batch(
+            r#"
+            CREATE TABLE IF NOT EXISTS api_keys (
+                id TEXT PRIMARY KEY,
+                name TEXT NOT NULL,
+                key_hash TEXT NOT NULL,
+                scopes TEXT NOT NULL,
+                enabled INTEGER NOT NULL,
+                created_at TEXT NOT NULL,
+                expires_at TEXT
+            );
+            "#,
+        )?;
+
+        // Added after the table first shipped.
+        let has_limits = conn
+            .prepare("SELECT 1 FROM pragma_table_info('api_keys') WHERE name = 'limits'")?
+            .exists([])?;
+        if !has_limits {
+            conn.execute_batch("ALTER TABLE api_keys ADD COLUMN limits TEXT NOT NULL DEFAULT '{}'")?;
+        }
+
+        Ok(Self {
+            conn: Mutex::new(conn),
+            env_key: env_key.map(|_| ApiKey {
+                id: ENV_KEY_ID.to_string(),
+                name: "API_KEY".to_string(),
+                scopes: vec![Scope::Admin],
+                enabled: true,
+                created_at: Utc::now(),
+                expires_at: None,
+                limits: KeyLimits::default(),
+            }),
+            env_key_hash: env_key.map(hash),
+        })
+    }
+
+    pub fn create(&self, request: &CreateKeyRequest) -> Result<CreatedKey, Error> {
+        let id = Uuid::new_v4().simple().to_string()[..12].to_string();
+        let secret = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+        let key = ApiKey {
+            id,
+            name: request.name.trim().to_string(),
+            scopes: request.scopes.clone(),
+            enabled: true,
+            created_at: Utc::now(),
+            expires_at: request.expires_at,
+            limits: request.limits,
+        };
+
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO api_keys (id, name, key_hash, scopes, enabled, created_at, expires_at, limits)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                key.id,
+                key.name,
+                hash(&secret),
+                serde_json::to_string(&key.scopes)?,
+                key.enabled,
+                timestamp(&key.created_at),
+                key.expires_at.as_ref().map(timestamp),
+                serde_json::to_string(&key.limits)?,
+            ],
+        )?;
+
+        Ok(CreatedKey {
+            token: format!("{}{}_{}", TOKEN_PREFIX, key.id, secret),
+            key,
+        })
+    }
+
+    // Oldest first.
+    pub fn list(&self) -> Result<Vec<ApiKey>, Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, name, scopes, enabled, created_at, expires_at, limits FROM api_keys
+             ORDER BY created_at",
+        )?;
+        let rows = stmt.query_map([], raw_key)?;
+
+        rows.map(|row| row?.parse()).collect()
+    }
+
+    pub fn get(&self, id: &str) -> Result<Option<ApiKey>, Error> {
+        let conn = self.conn.lock().unwrap();
+        let row = conn
+            .query_row(
+                "SELECT id, name, scopes, enabled, created_at, expires_at, limits FROM api_keys
+                 WHERE id = ?1",
+                params![id],
+                raw_key,
+            )
+            .optional()?;
+
+        row.map(RawKey::parse).transpose()
+    }
+
+    // Apply the fields set in `update`. None if the key doesn't exist.
+    pub fn update(&self, id: &str, update: &UpdateKeyRequest) -> Result<Option<ApiKey>, Error> {
+        let limits = update.limits.as_ref().map(serde_json::to_string).transpose()?;
+        let updated = self.conn.lock().unwrap().execute(
+            "UPDATE api_keys SET enabled = COALESCE(?1, enabled), limits = COALESCE(?2, limits)
+             WHERE id = ?3",
+            params![update.enabled, limits, id],
+        )?;
+
+        if updated == 0 {
+            return Ok(None);
+        }
+        self.get(id)
+    }
+
+    // Deletes the key for good. False if it didn't exist.
+    pub fn revoke(&self, id: &str) -> Result<bool, Error> {
+        let deleted = self
+            .conn
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM api_keys WHERE id = ?1", params![id])?;
+        Ok(deleted > 0)
+    }
+
+    // The key a bearer token belongs to. Disabled and expired keys are returned too,
+    // it's up to the caller to check `is_active`.
+    pub fn authenticate(&self, token: &str) -> Result<Option<ApiKey>, Error> {
+        if let Some(env_hash) = &self.env_key_hash {
+            if constant_time_eq(hash(token).as_bytes(), env_hash.as_bytes()) {
+                return Ok(self.env_key.clone());
+            }
+        }
+
+        let Some((id, secret)) = token
+            .strip_prefix(TOKEN_PREFIX)
+            .and_then(|rest| rest.split_once('_'))
+        else {
+            return Ok(None);
+        };
+
+        let stored: Option<String> = self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT key_hash FROM api_keys WHERE id = ?1",
+                params![id],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        match stored {
+            Some(stored) if constant_time_eq(hash(secret).as_bytes(), stored.as_bytes()) => {
+                self.get(id)
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+// A row as stored, before the scopes and timestamps are parsed.
+struct RawKey {
+    id: String,
+    name: String,
+    scopes: String,
+    enabled: bool,
+    created_at: String,
+    expires_at: Option<String>,
+    limits: String,
+}
+
+fn raw_key(row: &Row) -> Result<RawKey, rusqlite::Error> {
+    Ok(RawKey {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        scopes: row.get(2)?,
+        enabled: row.get(3)?,
+        created_at: row.get(4)?,
+        expires_at: row.get(5)?,
+        limits: row.get(6)?,
+    })
+}
+
+impl RawKey {
+    fn parse(self) -> Result<ApiKey, Error> {
+        let parse_time = |time: &str| -> Result<DateTime<Utc>, Error> {
+            Ok(DateTime::parse_from_rfc3339(time)?.with_timezone(&Utc))
+        };
+
+        Ok(ApiKey {
+            id: self.id,
+            name: self.name,
+            scopes: serde_json::from_str(&self.scopes)?,
+            enabled: self.enabled,
+            created_at: parse_time(&self.created_at)?,
+            expires_at: self.expires_at.as_deref().map(parse_time).transpose()?,
+            limits: serde_json::from_str(&self.limits)?,
+        })
+    }
+}
+
+fn hash(secret: &str) -> String {
+    Sha256::digest(secret.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// Compares every byte regardless of where the first difference is.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::webutils::WebUtils;
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+    use actix_web::web;
+    use chrono::TimeDelta;
+
+    fn store() -> web::Data<KeyStore> {
+        web::Data::new(KeyStore::open(":memory:", None).unwrap())
+    }
+
+    fn create(store: &KeyStore, scopes: &[Scope], expires_at: Option<DateTime<Utc>>) -> CreatedKey {
+        store
+            .create(&CreateKeyRequest {
+                name: "test".to_string(),
+                scopes: scopes.to_vec(),
+                expires_at,
+                limits: KeyLimits::default(),
+            })
+            .unwrap()
+    }
+
+    // What `require_api_key` makes of `token` when a route needs `scope`.
+    fn require(store: &web::Data<KeyStore>, token: &str, scope: Scope) -> Result<ApiKey, StatusCode> {
+        let req = TestRequest::default()
+            .app_data(store.clone())
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .to_http_request();
+        WebUtils::require_api_key(&req, Some(scope)).map_err(|response| response.status())
+    }
+
+    #[test]
+    fn secrets_are_hashed_with_sha256() {
+        assert_eq!(
+            hash("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn constant_time_eq_compares_whole_slices() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(constant_time_eq(b"", b""));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"Secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+    }
+
+    #[test]
+    fn only_the_hash_of_a_secret_is_stored() {
+        let store = store();
+        let created = create(&store, &[Scope::ChoirRun], None);
+        let secret = created.token.rsplit('_').next().unwrap();
+        let stored: String = store
+            .conn
+            .lock()
+            .unwrap()
+            .query_row("SELECT key_hash FROM api_keys", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(stored, hash(secret));
+        assert!(!stored.contains(secret));
+    }
+
+    #[test]
+    fn tokens_authenticate_their_own_key_only() {
+        let store = store();
+        let created = create(&store, &[Scope::ChoirRun], None);
+        let key = store.authenticate(&created.token).unwrap().unwrap();
+        assert_eq!(key.id, created.key.id);
+
+        let other = create(&store, &[Scope::ChoirRun], None);
+        let secret = other.token.rsplit('_').next().unwrap();
+        let swapped = format!("{}{}_{}", TOKEN_PREFIX, created.key.id, secret);
+        for token in [swapped.as_str(), "ck_nope_nope", "garbage", "", &created.token[1..]] {
+            assert!(store.authenticate(token).unwrap().is_none(), "{} was accepted", token);
+        }
+    }
+
+    #[test]
+    fn the_env_key_is_an_admin_key() {
+        let bootstrapped = web::Data::new(KeyStore::open(":memory:", Some("bootstrap")).unwrap());
+        let key = bootstrapped.authenticate("bootstrap").unwrap().unwrap();
+        assert_eq!(key.id, ENV_KEY_ID);
+        assert!(key.is_active());
+        assert!(key.allows(Scope::RunsRead));
+        assert!(bootstrapped.authenticate("bootstrap2").unwrap().is_none());
+
+        assert!(require(&bootstrapped, "bootstrap", Scope::Admin).is_ok());
+        assert!(store().authenticate("bootstrap").unwrap().is_none());
+    }
+
+    #[test]
+    fn disabled_and_expired_keys_are_rejected() {
+        let store = store();
+        let disabled = create(&store, &[Scope::ChoirRun], None);
+        store
+            .update(
+                &disabled.key.id,
+                &UpdateKeyRequest {
+                    enabled: Some(false),
+                    limits: None,
+                },
+            )
+            .unwrap();
+        let expired = create(&store, &[Scope::ChoirRun], Some(Utc::now() - TimeDelta::minutes(1)));
+        let current = create(&store, &[Scope::ChoirRun], Some(Utc::now() + TimeDelta::hours(1)));
+
+        // Still found, it's `is_active` that turns them away.
+        assert!(!store.authenticate(&disabled.token).unwrap().unwrap().is_active());
+        assert!(!store.authenticate(&expired.token).unwrap().unwrap().is_active());
+
+        let unauthorized = Err(StatusCode::UNAUTHORIZED);
+        assert_eq!(require(&store, &disabled.token, Scope::ChoirRun).map(|key| key.id), unauthorized);
+        assert_eq!(require(&store, &expired.token, Scope::ChoirRun).map(|key| key.id), unauthorized);
+        assert!(require(&store, &current.token, Scope::ChoirRun).is_ok());
+    }
+
+    #[test]
+    fn missing_scopes_are_forbidden() {
+        let store = store();
+        let reader = create(&store, &[Scope::RunsRead], None);
+        let admin = create(&store, &[Scope::Admin], None);
+        assert_eq!(require(&store, &reader.token, Scope::ChoirRun).unwrap_err(), StatusCode::FORBIDDEN);
+        assert!(require(&store, &reader.token, Scope::RunsRead).is_ok());
+        assert!(require(&store, &admin.token, Scope::ChoirRun).is_ok());
+        assert_eq!(require(&store, "", Scope::ChoirRun).unwrap_err(), StatusCode::UNAUTHORIZED);
+    }
+}
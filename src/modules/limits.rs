@@ -0,0 +1,149 @@
+use crate::modules::history::RunHistory;
+use crate::types::tkey::{ApiKey, KeyLimits};
+use chrono::{Duration as ChronoDuration, Utc};
+use log::error;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+// There's no telling when a running run finishes, so clients over their concurrency limit
+// are asked to come back after this long.
+const CONCURRENCY_RETRY_SECS: u64 = 5;
+
+// Why a request was turned away and when it's worth trying again.
+#[derive(Debug)]
+pub struct Limited {
+    pub message: String,
+    pub retry_after_secs: u64,
+}
+
+// Per API key request rates, concurrent runs and daily quotas.
+pub struct KeyLimiter {
+    defaults: KeyLimits,
+    history: Arc<RunHistory>,
+    buckets: Mutex<HashMap<String, Bucket>>,
+    running: Arc<Mutex<HashMap<String, u32>>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl KeyLimiter {
+    pub fn new(defaults: KeyLimits, history: Arc<RunHistory>) -> Self {
+        Self {
+            defaults,
+            history,
+            buckets: Mutex::new(HashMap::new()),
+            running: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // Token bucket per key, holding up to a minute's worth of runs.
+    fn check_rate(&self, key: &ApiKey) -> Result<(), Limited> {
+        let Some(per_minute) = key.limits.or(self.defaults).requests_per_minute else {
+            return Ok(());
+        };
+        let capacity = per_minute as f64;
+        let per_second = capacity / 60.0;
+
+        let mut buckets = self.buckets.lock().unwrap();
+        let now = Instant::now();
+        let bucket = buckets.entry(key.id.clone()).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_second).min(capacity);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+
+        Err(Limited {
+            message: format!("Rate limit of {} requests per minute exceeded.", per_minute),
+            retry_after_secs: ((1.0 - bucket.tokens) / per_second).ceil() as u64,
+        })
+    }
+
+    // Take one of the key's concurrent run slots, checking its daily quotas and rate first.
+    // The rate is charged last, so runs turned away for another reason don't use it up.
+    // The slot is freed when the permit is dropped.
+    pub fn start_run(&self, key: &ApiKey) -> Result<RunPermit, Limited> {
+        let limits = key.limits.or(self.defaults);
+        let mut running = self.running.lock().unwrap();
+        let current = running.get(&key.id).copied().unwrap_or(0);
+
+        if let Some(max) = limits.max_concurrent_runs {
+            if current >= max {
+                return Err(Limited {
+                    message: format!("Limit of {} concurrent runs reached.", max),
+                    retry_after_secs: CONCURRENCY_RETRY_SECS,
+                });
+            }
+        }
+
+        if limits.daily_runs.is_some() || limits.daily_tokens.is_some() {
+            let midnight = Utc::now().date_naive().and_hms_opt(0, 0, 0).unwrap().and_utc();
+            let until_tomorrow = (midnight + ChronoDuration::days(1) - Utc::now())
+                .num_seconds()
+                .max(1) as u64;
+
+            // Quotas fail open: a history hiccup shouldn't take the API down with it.
+            let (runs, tokens) = self
+                .history
+                .key_usage_since(&key.id, &midnight)
+                .unwrap_or_else(|e| {
+                    error!("Failed to read usage for key {}: {}", key.id, e);
+                    (0, 0)
+                });
+
+            // Running runs are only in the history once they finish.
+            if let Some(max) = limits.daily_runs {
+                if runs + current as u64 >= max as u64 {
+                    return Err(Limited {
+                        message: format!("Daily quota of {} runs used up.", max),
+                        retry_after_secs: until_tomorrow,
+                    });
+                }
+            }
+            if let Some(max) = limits.daily_tokens {
+                if tokens >= max {
+                    return Err(Limited {
+                        message: format!("Daily quota of {} tokens used up.", max),
+                        retry_after_secs: until_tomorrow,
+                    });
+                }
+            }
+        }
+
+        self.check_rate(key)?;
+        *running.entry(key.id.clone()).or_insert(0) += 1;
+        Ok(RunPermit {
+            key_id: key.id.clone(),
+            running: Arc::clone(&self.running),
+        })
+    }
+}
+
+// Holds one concurrent run slot for as long as it lives.
+pub struct RunPermit {
+    key_id: String,
+    running: Arc<Mutex<HashMap<String, u32>>>,
+}
+
+impl Drop for RunPermit {
+    fn drop(&mut self) {
+        let mut running = self.running.lock().unwrap();
+        if let Some(count) = running.get_mut(&self.key_id) {
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                running.remove(&self.key_id);
+            }
+        }
+    }
+}
@@ -0,0 +1,256 @@
+use crate::types::thealth::CircuitState;
+use crate::types::trun::RetryAction;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use prometheus::{
+    CounterVec, Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+
+// LLM calls and whole runs take far longer than the default buckets allow for.
+const SLOW_BUCKETS: &[f64] = &[0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 20.0, 30.0, 60.0, 120.0, 300.0];
+// Estimated over billed prompt tokens, 1.0 being spot on.
+const ESTIMATE_BUCKETS: &[f64] = &[0.5, 0.75, 0.9, 0.95, 0.99, 1.01, 1.05, 1.1, 1.25, 1.5, 2.0];
+
+// Process wide, so code deep in the providers can record without having it passed down.
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub struct Metrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_duration: HistogramVec,
+    runs: IntCounterVec,
+    stage_duration: HistogramVec,
+    agent_outcomes: IntCounterVec,
+    tool_executions: IntCounterVec,
+    tool_duration: HistogramVec,
+    semaphore_wait: HistogramVec,
+    tokens: IntCounterVec,
+    prompt_estimates: HistogramVec,
+    cost: CounterVec,
+    llm_retries: IntCounterVec,
+    circuit_state: IntGaugeVec,
+    circuit_rejections: IntCounterVec,
+    page_cache: IntCounterVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("choir".to_string()), None).unwrap();
+
+        let counter = |name: &str, help: &str, labels: &[&str]| {
+            let metric = IntCounterVec::new(Opts::new(name, help), labels).unwrap();
+            registry.register(Box::new(metric.clone())).unwrap();
+            metric
+        };
+        let histogram = |name: &str, help: &str, labels: &[&str], buckets: &[f64]| {
+            let opts = HistogramOpts::new(name, help).buckets(buckets.to_vec());
+            let metric = HistogramVec::new(opts, labels).unwrap();
+            registry.register(Box::new(metric.clone())).unwrap();
+            metric
+        };
+
+        let cost = CounterVec::new(
+            Opts::new("llm_cost_usd_total", "Estimated LLM spend in USD."),
+            &["stage", "model"],
+        )
+        .unwrap();
+        registry.register(Box::new(cost.clone())).unwrap();
+
+        let circuit_state = IntGaugeVec::new(
+            Opts::new(
+                "circuit_state",
+                "Circuit breaker state by upstream: 0 closed, 1 half-open, 2 open.",
+            ),
+            &["upstream"],
+        )
+        .unwrap();
+        registry.register(Box::new(circuit_state.clone())).unwrap();
+
+        Self {
+            http_requests: counter(
+                "http_requests_total",
+                "HTTP requests by route and status.",
+                &["method", "route", "status"],
+            ),
+            http_duration: histogram(
+                "http_request_duration_seconds",
+                "HTTP request latency by route.",
+                &["method", "route"],
+                SLOW_BUCKETS,
+            ),
+            runs: counter("runs_total", "Choir runs by final status.", &["status"]),
+            stage_duration: histogram(
+                "stage_duration_seconds",
+                "Time spent in each pipeline stage.",
+                &["stage"],
+                SLOW_BUCKETS,
+            ),
+            agent_outcomes: counter(
+                "agent_outcomes_total",
+                "Agent results: success, parse_failure or provider_error.",
+                &["outcome"],
+            ),
+            tool_executions: counter(
+                "tool_executions_total",
+                "AI function executions by tool and outcome.",
+                &["tool", "outcome"],
+            ),
+            tool_duration: histogram(
+                "tool_duration_seconds",
+                "AI function execution time by tool.",
+                &["tool"],
+                SLOW_BUCKETS,
+            ),
+            semaphore_wait: histogram(
+                "llm_semaphore_wait_seconds",
+                "Time spent waiting for a provider concurrency slot.",
+                &["provider"],
+                prometheus::DEFAULT_BUCKETS,
+            ),
+            tokens: counter(
+                "llm_tokens_total",
+                "Tokens used by stage, model and kind (prompt or completion).",
+                &["stage", "model", "kind"],
+            ),
+            prompt_estimates: histogram(
+                "llm_prompt_estimate_ratio",
+                "Prompt tokens counted before a call over what the provider billed, by tokenizer.",
+                &["tokenizer"],
+                ESTIMATE_BUCKETS,
+            ),
+            llm_retries: counter(
+                "llm_retries_total",
+                "Failed LLM calls by stage, model and action (retry or fallback).",
+                &["stage", "model", "action"],
+            ),
+            circuit_rejections: counter(
+                "circuit_rejections_total",
+                "Calls failed fast because the upstream's circuit was open.",
+                &["upstream"],
+            ),
+            page_cache: counter(
+                "page_cache_lookups_total",
+                "Fetched page cache lookups by result: hit, revalidated or miss.",
+                &["result"],
+            ),
+            cost,
+            circuit_state,
+            registry,
+        }
+    }
+
+    // Everything in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            log::error!("Failed to encode metrics: {}", e);
+        }
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+
+    pub fn run_finished(&self, status: &str) {
+        self.runs.with_label_values(&[status]).inc();
+    }
+
+    pub fn stage(&self, stage: &str, duration: Duration) {
+        self.stage_duration
+            .with_label_values(&[stage])
+            .observe(duration.as_secs_f64());
+    }
+
+    pub fn agent_outcome(&self, outcome: &str) {
+        self.agent_outcomes.with_label_values(&[outcome]).inc();
+    }
+
+    pub fn tool_execution(&self, tool: &str, success: bool, duration: Duration) {
+        let outcome = if success { "success" } else { "error" };
+        self.tool_executions
+            .with_label_values(&[tool, outcome])
+            .inc();
+        self.tool_duration
+            .with_label_values(&[tool])
+            .observe(duration.as_secs_f64());
+    }
+
+    pub fn semaphore_wait(&self, provider: &str, waited: Duration) {
+        self.semaphore_wait
+            .with_label_values(&[provider])
+            .observe(waited.as_secs_f64());
+    }
+
+    pub fn tokens(&self, stage: &str, model: &str, prompt: u32, completion: u32, cost_usd: f64) {
+        self.tokens
+            .with_label_values(&[stage, model, "prompt"])
+            .inc_by(prompt as u64);
+        self.tokens
+            .with_label_values(&[stage, model, "completion"])
+            .inc_by(completion as u64);
+        self.cost.with_label_values(&[stage, model]).inc_by(cost_usd);
+    }
+
+    pub fn prompt_estimate(&self, tokenizer: &str, estimated: u64, billed: u64) {
+        if billed > 0 {
+            self.prompt_estimates
+                .with_label_values(&[tokenizer])
+                .observe(estimated as f64 / billed as f64);
+        }
+    }
+
+    pub fn llm_retry(&self, stage: &str, model: &str, action: &RetryAction) {
+        let action = match action {
+            RetryAction::Retry { .. } => "retry",
+            RetryAction::Fallback { .. } => "fallback",
+        };
+        self.llm_retries
+            .with_label_values(&[stage, model, action])
+            .inc();
+    }
+
+    pub fn circuit_state(&self, upstream: &str, state: CircuitState) {
+        let value = match state {
+            CircuitState::Closed => 0,
+            CircuitState::HalfOpen => 1,
+            CircuitState::Open => 2,
+        };
+        self.circuit_state.with_label_values(&[upstream]).set(value);
+    }
+
+    pub fn circuit_rejected(&self, upstream: &str) {
+        self.circuit_rejections.with_label_values(&[upstream]).inc();
+    }
+
+    pub fn page_cache_lookup(&self, result: &str) {
+        self.page_cache.with_label_values(&[result]).inc();
+    }
+}
+
+// Middleware counting and timing every request. Routes are labelled by their pattern,
+// e.g. `/choir/runs/{id}`, so ids don't blow up the label set.
+pub async fn track_requests(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let started = Instant::now();
+    let method = req.method().to_string();
+    let route = req.match_pattern().unwrap_or_else(|| "unmatched".to_string());
+
+    let res = next.call(req).await;
+    let status = match &res {
+        Ok(res) => res.status().as_u16().to_string(),
+        Err(e) => e.as_response_error().status_code().as_u16().to_string(),
+    };
+
+    METRICS
+        .http_requests
+        .with_label_values(&[method.as_str(), route.as_str(), status.as_str()])
+        .inc();
+    METRICS
+        .http_duration
+        .with_label_values(&[method.as_str(), route.as_str()])
+        .observe(started.elapsed().as_secs_f64());
+    res
+}
@@ -1,2 +1,22 @@
+pub mod breaker;
+pub mod budget;
 pub mod choir;
+pub mod fetcher;
+pub mod firecrawl;
+pub mod health;
+pub mod history;
+pub mod jobs;
+pub mod keys;
+pub mod limits;
+pub mod metrics;
+pub mod native_fetcher;
 pub mod openai;
+pub mod openai_compat;
+pub mod pagecache;
+pub mod provider;
+pub mod retry;
+pub mod roster;
+pub mod telemetry;
+pub mod tokenizer;
+pub mod urlpolicy;
+pub mod usage;
@@ -0,0 +1,284 @@
+use crate::modules::fetcher::Fetcher;
+use crate::modules::urlpolicy::{blocked_cause, PolicyResolver, UrlPolicy};
+use crate::types::terror::ChoirError;
+use crate::types::tfetch::{FetchedPage, PageMetadata, Validators};
+use crate::utils::htmlutils::HtmlUtils;
+use crate::Error;
+use async_trait::async_trait;
+use reqwest::header::{
+    ACCEPT, CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED,
+};
+use reqwest::StatusCode;
+use std::error::Error as StdError;
+use std::sync::Arc;
+use std::time::Duration;
+
+const USER_AGENT: &str = concat!("choir/", env!("CARGO_PKG_VERSION"));
+
+// Downloads pages itself and converts them with `HtmlUtils`, no third party involved.
+// Every connection and redirect goes through the URL policy.
+pub struct NativeFetcher {
+    http: reqwest::Client,
+    /// Pages are cut off here, the rest is never downloaded.
+    max_bytes: usize,
+}
+
+impl NativeFetcher {
+    pub fn new(timeout: Duration, max_bytes: usize, policy: Arc<UrlPolicy>) -> Result<Self, Error> {
+        let http = reqwest::Client::builder()
+            .user_agent(USER_AGENT)
+            .redirect(policy.redirect_policy())
+            .dns_resolver(Arc::new(PolicyResolver(policy)))
+            .timeout(timeout)
+            .build()?;
+        Ok(Self { http, max_bytes })
+    }
+}
+
+impl NativeFetcher {
+    // Download and convert a page. With validators the request is conditional, and
+    // Ok(None) means the site says the page hasn't changed since.
+    async fn get(&self, url: &str, validators: Option<&Validators>) -> Result<Option<FetchedPage>, Error> {
+        let failed = |message: String| {
+            Error::from(ChoirError::FetchFailed {
+                url: url.to_string(),
+                message,
+            })
+        };
+
+        let mut request = self
+            .http
+            .get(url)
+            .header(ACCEPT, "text/html,application/xhtml+xml,text/plain;q=0.9,*/*;q=0.5");
+        if let Some(validators) = validators {
+            if let Some(etag) = &validators.etag {
+                request = request.header(IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &validators.last_modified {
+                request = request.header(IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+
+        let mut response = request.send().await.map_err(|e| match blocked_cause(&e) {
+            Some(blocked) => Error::from(blocked),
+            None => failed(with_causes(&e)),
+        })?;
+        if response.status() == StatusCode::NOT_MODIFIED && validators.is_some() {
+            return Ok(None);
+        }
+        if !response.status().is_success() {
+            return Err(failed(format!("HTTP {}", response.status())));
+        }
+
+        let final_url = response.url().to_string();
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        };
+        let content_type = header(CONTENT_TYPE)
+            .unwrap_or_else(|| "text/html".to_string())
+            .to_ascii_lowercase();
+        let is_html = content_type.contains("html");
+        if !is_html && !content_type.starts_with("text/") {
+            return Err(failed(format!("Unsupported content type {}", content_type)));
+        }
+        let validators = Validators {
+            etag: header(ETAG),
+            last_modified: header(LAST_MODIFIED),
+        };
+
+        let mut body = Vec::new();
+        while let Some(chunk) = response.chunk().await.map_err(|e| failed(e.to_string()))? {
+            let room = self.max_bytes - body.len();
+            body.extend_from_slice(&chunk[..chunk.len().min(room)]);
+            if body.len() >= self.max_bytes {
+                break;
+            }
+        }
+        let text = String::from_utf8_lossy(&body);
+
+        let mut page = if is_html {
+            HtmlUtils::extract(&final_url, &text)
+        } else {
+            FetchedPage {
+                url: final_url,
+                markdown: text.trim().to_string(),
+                title: None,
+                metadata: PageMetadata::default(),
+                cached: None,
+                validators: Validators::default(),
+            }
+        };
+        page.validators = validators;
+        Ok(Some(page))
+    }
+}
+
+#[async_trait]
+impl Fetcher for NativeFetcher {
+    fn name(&self) -> &'static str {
+        "native"
+    }
+
+    async fn fetch(&self, url: &str) -> Result<FetchedPage, Error> {
+        let page = self.get(url, None).await?;
+        Ok(page.expect("unconditional requests always return a page"))
+    }
+
+    async fn fetch_if_changed(&self, url: &str, validators: &Validators) -> Result<Option<FetchedPage>, Error> {
+        self.get(url, Some(validators)).await
+    }
+
+    // Nothing to reach ahead of time, every page is its own site.
+    async fn health_check(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+// reqwest keeps the useful part ("connection refused", "more than 5 redirects") in the
+// error's sources, not its message.
+fn with_causes(error: &reqwest::Error) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        message.push_str(": ");
+        message.push_str(&cause.to_string());
+        source = cause.source();
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    const ARTICLE: &str = include_str!("../../tests/fixtures/article.html");
+
+    // Serves the fixtures on a local port, by path.
+    fn fixture_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut request = String::new();
+                let _ = BufReader::new(&stream).read_line(&mut request);
+                let path = request.split_whitespace().nth(1).unwrap_or("/").to_string();
+                let (status, content_type, body) = match path.as_str() {
+                    "/articles/tuning" => ("200 OK", "text/html; charset=utf-8", ARTICLE.to_string()),
+                    "/notes.txt" => ("200 OK", "text/plain", "  Plain notes.\n".to_string()),
+                    "/long" => (
+                        "200 OK",
+                        "text/html",
+                        format!("<html><body><p>{}</p></body></html>", "word ".repeat(20_000)),
+                    ),
+                    "/image.png" => ("200 OK", "image/png", String::new()),
+                    _ => ("404 Not Found", "text/plain", "not found".to_string()),
+                };
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    content_type,
+                    body.len(),
+                    body
+                );
+            }
+        });
+        format!("http://{}", addr)
+    }
+
+    // The fixture server is local, so private addresses and any port have to be allowed.
+    fn fetcher(max_bytes: usize) -> NativeFetcher {
+        NativeFetcher::new(Duration::from_secs(5), max_bytes, Arc::new(UrlPolicy::allow_all())).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn extracts_main_content() {
+        let base = fixture_server();
+        let page = fetcher(5_000_000)
+            .fetch(&format!("{}/articles/tuning", base))
+            .await
+            .unwrap();
+        let markdown = &page.markdown;
+
+        assert_eq!(page.title.as_deref(), Some("How Choirs Tune, Explained"));
+        assert_eq!(page.metadata.author.as_deref(), Some("Sam Example"));
+        assert_eq!(page.metadata.site_name.as_deref(), Some("Example Journal"));
+        assert_eq!(page.metadata.language.as_deref(), Some("en"));
+        assert_eq!(
+            page.metadata.canonical_url,
+            Some(format!("{}/articles/tuning", base))
+        );
+
+        assert!(markdown.starts_with("# How Choirs Tune"), "{}", markdown);
+        assert!(markdown.contains("Choirs tune by **listening** to each other"), "{}", markdown);
+        assert!(markdown.contains("## Techniques"), "{}", markdown);
+        assert!(markdown.contains("> Listen twice as much as you sing."), "{}", markdown);
+        for boilerplate in ["should not appear", "color: red", "Home", "cookies", "Related", "Great article", "Copyright", "Hidden paragraph"] {
+            assert!(!markdown.contains(boilerplate), "{:?} in {}", boilerplate, markdown);
+        }
+    }
+
+    #[actix_web::test]
+    async fn converts_links_lists_tables_and_code() {
+        let base = fixture_server();
+        let page = fetcher(5_000_000)
+            .fetch(&format!("{}/articles/tuning", base))
+            .await
+            .unwrap();
+        let markdown = &page.markdown;
+
+        // Links and images are made absolute.
+        assert!(markdown.contains(&format!("a [pitch pipe]({}/pitch-pipe)", base)), "{}", markdown);
+        assert!(markdown.contains(&format!("![A choir singing]({}/articles/img/choir.png)", base)), "{}", markdown);
+        // Nested lists are indented under their item.
+        assert!(
+            markdown.contains(
+                "- Just intonation for sustained chords\n- Equal temperament with accompaniment\n  1. Piano\n  2. Organ"
+            ),
+            "{}",
+            markdown
+        );
+        assert!(
+            markdown.contains("| Section | Range |\n| --- | --- |\n| Soprano | C4–A5 |"),
+            "{}",
+            markdown
+        );
+        // Code keeps its indentation.
+        assert!(markdown.contains("```\nA4 = 440 Hz\n  A3 = 220 Hz\n```"), "{}", markdown);
+    }
+
+    #[actix_web::test]
+    async fn cuts_pages_at_max_bytes() {
+        let base = fixture_server();
+        let page = fetcher(1_000).fetch(&format!("{}/long", base)).await.unwrap();
+        assert!(!page.markdown.is_empty());
+        assert!(page.markdown.len() <= 1_000, "{} bytes", page.markdown.len());
+
+        let page = fetcher(5_000_000).fetch(&format!("{}/long", base)).await.unwrap();
+        assert_eq!(page.markdown.len(), "word ".repeat(20_000).trim().len());
+    }
+
+    #[actix_web::test]
+    async fn plain_text_and_failures() {
+        let base = fixture_server();
+        let fetcher = fetcher(5_000_000);
+
+        let page = fetcher.fetch(&format!("{}/notes.txt", base)).await.unwrap();
+        assert_eq!(page.markdown, "Plain notes.");
+        assert!(page.title.is_none());
+
+        for (path, message) in [("/missing", "HTTP 404"), ("/image.png", "Unsupported content type")] {
+            let error = fetcher.fetch(&format!("{}{}", base, path)).await.unwrap_err();
+            match ChoirError::from(error) {
+                ChoirError::FetchFailed { message: m, .. } => assert!(m.contains(message), "{}: {}", path, m),
+                other => panic!("{}: {:?}", path, other),
+            }
+        }
+    }
+}
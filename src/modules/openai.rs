@@ -1,60 +1,55 @@
-use crate::ai_functions::{get_all_functions, AIFunction};
 use crate::config::EnvConfig;
+use crate::modules::metrics::METRICS;
+use crate::modules::provider::{
+    ChatMessage, CompletionRequest, CompletionResponse, CompletionStream, LLMProvider,
+    StreamChunk, TokenUsage, ToolCall,
+};
+use crate::types::terror::ChoirError;
 use crate::Error;
 use async_openai::{
-    config::OpenAIConfig,
+    config::{Config, OpenAIConfig},
+    error::{OpenAIError, WrappedError},
     types::{
-        CategoryScore, ChatCompletionRequestAssistantMessage,
+        CategoryScore, ChatCompletionMessageToolCall, ChatCompletionRequestAssistantMessage,
         ChatCompletionRequestAssistantMessageContent, ChatCompletionRequestMessage,
         ChatCompletionRequestSystemMessage, ChatCompletionRequestSystemMessageContent,
         ChatCompletionRequestToolMessage, ChatCompletionRequestToolMessageContent,
-        ChatCompletionTool, ChatCompletionToolType, CreateChatCompletionRequest,
-        CreateModerationRequestArgs, FunctionObject, ResponseFormat, ResponseFormatJsonSchema,
+        ChatCompletionRequestUserMessage, ChatCompletionRequestUserMessageContent,
+        ChatCompletionStreamOptions, ChatCompletionTool, ChatCompletionToolType, CompletionUsage,
+        CreateChatCompletionRequest, CreateChatCompletionResponse,
+        CreateChatCompletionStreamResponse, CreateModerationRequestArgs,
+        FunctionCall, FunctionObject, ResponseFormat, ResponseFormatJsonSchema,
     },
     Client,
 };
-use serde_json::Value;
-use std::{collections::HashMap, sync::Arc};
-use tokio::sync::Semaphore;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::{Stream, StreamExt};
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 
 pub struct OpenAIService {
     pub(crate) client: Arc<Client<OpenAIConfig>>,
+    http: reqwest::Client,
     semaphore: Arc<Semaphore>,
-    functions: HashMap<String, Box<dyn AIFunction>>,
-}
-
-impl Clone for OpenAIService {
-    fn clone(&self) -> Self {
-        let mut functions: HashMap<String, Box<dyn AIFunction>> = HashMap::new();
-        for function in get_all_functions() {
-            functions.insert(function.name().to_string(), function);
-        }
-
-        Self {
-            client: Arc::clone(&self.client),
-            semaphore: Arc::clone(&self.semaphore),
-            functions,
-        }
-    }
 }
 
 impl OpenAIService {
-    pub async fn new(config: Arc<EnvConfig>) -> Self {
-        let openai_config = OpenAIConfig::new().with_api_key(config.oai_key.clone());
+    pub fn new(config: Arc<EnvConfig>) -> Result<Self, Error> {
+        let oai_key = config
+            .oai_key
+            .clone()
+            .ok_or("OAI_KEY must be set for the openai provider")?;
+        let openai_config = OpenAIConfig::new().with_api_key(oai_key);
         let client = Client::with_config(openai_config);
 
-        let semaphore = Arc::new(Semaphore::new(15));
-
-        let mut functions: HashMap<String, Box<dyn AIFunction>> = HashMap::new();
-        for function in get_all_functions() {
-            functions.insert(function.name().to_string(), function);
-        }
-
-        Self {
+        Ok(Self {
             client: Arc::new(client),
-            semaphore,
-            functions,
-        }
+            http: reqwest::Client::new(),
+            semaphore: Arc::new(Semaphore::new(15)),
+        })
     }
 
     #[allow(dead_code)]
@@ -71,181 +66,371 @@ impl OpenAIService {
         drop(permit);
         Ok(response.results[0].category_scores.clone())
     }
+}
 
-    pub async fn get_completion_response(
-        &self,
-        model: &str,
-        messages: Vec<ChatCompletionRequestMessage>,
-        json_schema: Option<Value>,
-    ) -> Result<String, Error> {
-        // map None -> None, Some(schema) -> Some(ResponseFormat::JsonSchema{...})
-        let response_format = json_schema.map(|schema| ResponseFormat::JsonSchema {
-            json_schema: ResponseFormatJsonSchema {
-                name: "root".into(),
-                description: None,
-                schema: Some(schema),
-                strict: None,
-            },
-        });
+#[async_trait]
+impl LLMProvider for OpenAIService {
+    fn name(&self) -> &'static str {
+        "openai"
+    }
 
-        let req = CreateChatCompletionRequest {
-            model: model.to_string(),
-            messages,
-            response_format,
-            ..Default::default()
-        };
+    async fn chat_completion(&self, request: CompletionRequest) -> Result<CompletionResponse, Error> {
+        let _permit = acquire_permit(self.name(), &self.semaphore).await?;
 
-        let resp = self.client.chat().create(req).await?;
-        resp.choices
-            .get(0)
-            .and_then(|c| c.message.content.clone())
-            .ok_or_else(|| "No content in response".into())
+        let resp = create_chat(&self.http, self.client.config(), &build_chat_request(request)).await?;
+        parse_chat_response(resp)
     }
 
-    pub fn get_function_tools(&self) -> Vec<ChatCompletionTool> {
-        self.functions
-            .values()
-            .map(|func| {
-                let mut properties = serde_json::Map::new();
-                let mut required = Vec::new();
-
-                for (param_name, param) in func.parameters() {
-                    properties.insert(
-                        param_name.clone(),
-                        serde_json::json!({
-                            "type": param.param_type,
-                            "description": param.description
-                        }),
-                    );
-
-                    if param.required {
-                        required.push(param_name);
-                    }
-                }
+    async fn stream_completion(&self, request: CompletionRequest) -> Result<CompletionStream, Error> {
+        stream_chat(
+            self.name(),
+            &self.http,
+            self.client.config(),
+            &self.semaphore,
+            build_chat_request(request),
+        )
+        .await
+    }
 
-                ChatCompletionTool {
+    async fn health_check(&self) -> Result<(), Error> {
+        self.client.models().list().await?;
+        Ok(())
+    }
+}
+
+// Shared between every provider that speaks the OpenAI chat completions wire format.
+
+pub(crate) fn build_chat_request(request: CompletionRequest) -> CreateChatCompletionRequest {
+    // map None -> None, Some(schema) -> Some(ResponseFormat::JsonSchema{...})
+    let response_format = request.json_schema.map(|schema| ResponseFormat::JsonSchema {
+        json_schema: ResponseFormatJsonSchema {
+            name: "root".into(),
+            description: None,
+            schema: Some(schema),
+            strict: None,
+        },
+    });
+
+    let tools = if request.tools.is_empty() {
+        None
+    } else {
+        Some(
+            request
+                .tools
+                .into_iter()
+                .map(|tool| ChatCompletionTool {
                     r#type: ChatCompletionToolType::Function,
                     function: FunctionObject {
-                        name: func.name().to_string(),
-                        description: Some(func.description().to_string()),
-                        parameters: Some(serde_json::json!({
-                            "type": "object",
-                            "properties": properties,
-                            "required": required
-                        })),
+                        name: tool.name,
+                        description: Some(tool.description),
+                        parameters: Some(tool.parameters),
                         strict: None,
                     },
-                }
-            })
-            .collect()
+                })
+                .collect(),
+        )
+    };
+
+    CreateChatCompletionRequest {
+        model: request.model,
+        messages: request.messages.into_iter().map(to_openai_message).collect(),
+        response_format,
+        tools,
+        temperature: request.temperature,
+        max_completion_tokens: request.max_tokens,
+        ..Default::default()
     }
+}
 
-    pub async fn process_openai_interactive(
-        &self,
-        _conversation_id: uuid::Uuid,
-        prompt: &str,
-    ) -> Result<String, Error> {
-        let chat = self.client.chat();
-        let tools = self.get_function_tools();
-        let sys = r#"
-            Cut, to the point, and concise. Do not repeat yourself.
-        "#;
-
-        let mut messages = vec![ChatCompletionRequestMessage::System(
-            ChatCompletionRequestSystemMessage {
-                content: ChatCompletionRequestSystemMessageContent::Text(sys.to_string()),
-                name: None,
-            },
-        )];
+pub(crate) fn parse_chat_response(
+    resp: CreateChatCompletionResponse,
+) -> Result<CompletionResponse, Error> {
+    let usage = resp.usage.map(to_token_usage);
+    let message = resp
+        .choices
+        .into_iter()
+        .next()
+        .map(|c| c.message)
+        .ok_or_else(|| ChoirError::provider("No choices in response", true))?;
 
-        messages.push(ChatCompletionRequestMessage::Assistant(
-            ChatCompletionRequestAssistantMessage {
-                content: Some(ChatCompletionRequestAssistantMessageContent::Text(
-                    prompt.to_string(),
-                )),
-                name: None,
-                tool_calls: None,
-                audio: None,
-                refusal: None,
-                ..Default::default()
-            },
-        ));
-
-        let mut response = chat
-            .create(CreateChatCompletionRequest {
-                model: "gpt-4o".to_owned(),
-                messages: messages.clone(),
-                tools: Some(tools),
-                ..Default::default()
-            })
-            .await?;
-
-        // Handle function calls
-        while let Some(choice) = response.choices.first() {
-            if let Some(tool_calls) = &choice.message.tool_calls {
-                // Add the assistant's message with function calls
-                messages.push(ChatCompletionRequestMessage::Assistant(
-                    ChatCompletionRequestAssistantMessage {
-                        content: choice
-                            .message
-                            .content
-                            .clone()
-                            .map(|c| ChatCompletionRequestAssistantMessageContent::Text(c)),
-                        name: None,
-                        tool_calls: Some(tool_calls.clone()),
-                        audio: None,
-                        refusal: None,
-                        ..Default::default()
-                    },
-                ));
-
-                // Execute each function call
-                for tool_call in tool_calls {
-                    let function = &tool_call.function;
-                    let function_name = &function.name;
-                    let arguments: HashMap<String, Value> =
-                        serde_json::from_str(&function.arguments)?;
-
-                    let result = match self.functions.get(function_name) {
-                        Some(func) => match func.execute(arguments).await {
-                            Ok(result) => serde_json::to_string(&result)?,
-                            Err(e) => format!("Error: {}", e),
-                        },
-                        None => format!("Function '{}' not found", function_name),
-                    };
-
-                    // Add function result message
-                    messages.push(ChatCompletionRequestMessage::Tool(
-                        ChatCompletionRequestToolMessage {
-                            content: ChatCompletionRequestToolMessageContent::Text(result),
-                            tool_call_id: tool_call.id.clone(),
-                        },
-                    ));
-                }
+    let tool_calls = message
+        .tool_calls
+        .unwrap_or_default()
+        .into_iter()
+        .map(|call| ToolCall {
+            id: call.id,
+            name: call.function.name,
+            arguments: call.function.arguments,
+        })
+        .collect();
 
-                // Get the next response
-                response = chat
-                    .create(CreateChatCompletionRequest {
-                        model: "gpt-4.1".to_owned(),
-                        messages: messages.clone(),
-                        tools: Some(self.get_function_tools()),
-                        ..Default::default()
-                    })
-                    .await?;
-            } else {
-                break;
+    Ok(CompletionResponse {
+        content: message.content,
+        tool_calls,
+        usage,
+    })
+}
+
+fn to_token_usage(usage: CompletionUsage) -> TokenUsage {
+    TokenUsage {
+        prompt_tokens: usage.prompt_tokens,
+        completion_tokens: usage.completion_tokens,
+        total_tokens: usage.total_tokens,
+    }
+}
+
+// Chat completion over plain reqwest rather than async-openai's client, which retries 429s and
+// 5xx itself for up to 15 minutes and hides the status and `Retry-After` from us. Retrying is
+// left to the caller.
+pub(crate) async fn create_chat(
+    http: &reqwest::Client,
+    config: &OpenAIConfig,
+    request: &CreateChatCompletionRequest,
+) -> Result<CreateChatCompletionResponse, Error> {
+    let body = send_chat(http, config, request)
+        .await?
+        .bytes()
+        .await
+        .map_err(|e| provider_error(OpenAIError::Reqwest(e)))?;
+    serde_json::from_slice(&body).map_err(|e| {
+        ChoirError::provider(format!("Invalid chat completion response: {}", e), true).into()
+    })
+}
+
+// Post a chat completion request, sorting an error status into what the caller needs to know.
+async fn send_chat(
+    http: &reqwest::Client,
+    config: &OpenAIConfig,
+    request: &CreateChatCompletionRequest,
+) -> Result<reqwest::Response, Error> {
+    let response = http
+        .post(config.url("/chat/completions"))
+        .headers(config.headers())
+        .query(&config.query())
+        .json(request)
+        .send()
+        .await
+        .map_err(|e| provider_error(OpenAIError::Reqwest(e)))?;
+
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let retry_after = retry_after(response.headers());
+    let body = response
+        .bytes()
+        .await
+        .map_err(|e| provider_error(OpenAIError::Reqwest(e)))?;
+
+    // Usually `{"error": {...}}`, but proxies and local servers send all sorts.
+    let api_error = serde_json::from_slice::<WrappedError>(&body).ok().map(|w| w.error);
+    let quota_exhausted = api_error
+        .as_ref()
+        .is_some_and(|e| e.r#type.as_deref() == Some("insufficient_quota"));
+    let message = match api_error {
+        Some(e) => format!("{}: {}", status, e),
+        None => format!("{}: {}", status, String::from_utf8_lossy(&body)),
+    };
+
+    let error = match status.as_u16() {
+        // OpenAI also answers 429 when the account is out of credit, which waiting won't fix.
+        429 if quota_exhausted => ChoirError::provider(message, false),
+        429 => ChoirError::UpstreamRateLimited {
+            message,
+            retry_after,
+        },
+        408 | 409 | 500..=599 => ChoirError::Provider {
+            message,
+            retryable: true,
+            retry_after,
+        },
+        _ => ChoirError::provider(message, false),
+    };
+    Err(error.into())
+}
+
+// `Retry-After` as either a number of seconds or an HTTP date.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse::<f64>() {
+        return (secs >= 0.0).then(|| Duration::from_secs_f64(secs));
+    }
+    let at = DateTime::parse_from_rfc2822(value).ok()?.with_timezone(&Utc);
+    (at - Utc::now()).to_std().ok()
+}
+
+// Wait for a concurrency slot, recording how long that took.
+pub(crate) async fn acquire_permit(
+    provider: &str,
+    semaphore: &Arc<Semaphore>,
+) -> Result<OwnedSemaphorePermit, Error> {
+    let started = Instant::now();
+    let permit = Arc::clone(semaphore).acquire_owned().await?;
+    METRICS.semaphore_wait(provider, started.elapsed());
+    Ok(permit)
+}
+
+// Streamed chat completion, over the same plain reqwest path as `create_chat` so a rejected
+// request fails the same way. The semaphore permit is held until the stream is dropped.
+pub(crate) async fn stream_chat(
+    provider: &str,
+    http: &reqwest::Client,
+    config: &OpenAIConfig,
+    semaphore: &Arc<Semaphore>,
+    mut request: CreateChatCompletionRequest,
+) -> Result<CompletionStream, Error> {
+    let permit = acquire_permit(provider, semaphore).await?;
+
+    request.stream = Some(true);
+    // Usage arrives in an extra final chunk with no choices.
+    request.stream_options = Some(ChatCompletionStreamOptions {
+        include_usage: true,
+    });
+    let mut stream = Box::pin(sse_data(send_chat(http, config, &request).await?).map(|data| {
+        let data = data?;
+        // Some servers only report an error once the stream has started.
+        if let Ok(wrapped) = serde_json::from_str::<WrappedError>(&data) {
+            return Err(provider_error(OpenAIError::ApiError(wrapped.error)));
+        }
+        serde_json::from_str::<CreateChatCompletionStreamResponse>(&data).map_err(|e| {
+            ChoirError::provider(format!("Invalid chat completion chunk: {}", e), true).into()
+        })
+    }));
+
+    // An error in the first event is as good as a rejected request. Surface it here, while
+    // the caller can still retry.
+    let first = match stream.next().await {
+        Some(Err(e)) => return Err(e),
+        first => first,
+    };
+    let stream = futures::stream::iter(first).chain(stream);
+
+    Ok(Box::pin(stream.flat_map(move |chunk| {
+        let _permit = &permit;
+        let chunks: Vec<Result<StreamChunk, Error>> = match chunk {
+            Ok(chunk) => {
+                let delta = chunk
+                    .choices
+                    .into_iter()
+                    .next()
+                    .and_then(|c| c.delta.content)
+                    .map(|d| Ok(StreamChunk::Delta(d)));
+                let usage = chunk
+                    .usage
+                    .map(|u| Ok(StreamChunk::Usage(to_token_usage(u))));
+                delta.into_iter().chain(usage).collect()
+            }
+            Err(e) => vec![Err(e)],
+        };
+        futures::stream::iter(chunks)
+    })))
+}
+
+// The `data` of each server-sent event in a response, up to `[DONE]` or the end of the body.
+fn sse_data(response: reqwest::Response) -> impl Stream<Item = Result<String, Error>> {
+    futures::stream::unfold(Some((response, Vec::new())), |state| async move {
+        let (mut response, mut buffer) = state?;
+        loop {
+            if let Some(end) = buffer.windows(2).position(|w| w == b"\n\n") {
+                let event: Vec<u8> = buffer.drain(..end + 2).collect();
+                let event = String::from_utf8_lossy(&event);
+                let data = event
+                    .lines()
+                    .filter_map(|line| line.strip_prefix("data:"))
+                    .map(str::trim_start)
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                match data.as_str() {
+                    // Comments and keep-alives.
+                    "" => continue,
+                    "[DONE]" => return None,
+                    _ => return Some((Ok(data), Some((response, buffer)))),
+                }
+            }
+            match response.chunk().await {
+                Ok(Some(chunk)) => buffer.extend(chunk.iter().filter(|b| **b != b'\r')),
+                Ok(None) if buffer.iter().all(u8::is_ascii_whitespace) => return None,
+                // The last event may not have its blank line.
+                Ok(None) => buffer.extend_from_slice(b"\n\n"),
+                Err(e) => return Some((Err(provider_error(OpenAIError::Reqwest(e))), None)),
             }
         }
+    })
+}
 
-        // Function calls are done so get the final response.
-        let response_content = response.choices[0]
-            .message
-            .content
-            .clone()
-            .ok_or_else(|| "LLM failed to respond.")?;
+// Sort a client error into what the caller needs to know: whether it was a timeout, the
+// provider throttling us or something else, and whether trying again could help.
+pub(crate) fn provider_error(error: OpenAIError) -> Error {
+    let error = match error {
+        OpenAIError::Reqwest(e) if e.is_timeout() => {
+            ChoirError::Timeout(format!("LLM provider timed out: {}", e))
+        }
+        OpenAIError::Reqwest(e) => ChoirError::provider(e.to_string(), true),
+        OpenAIError::ApiError(e) => {
+            let kind = e.code.as_deref().or(e.r#type.as_deref()).unwrap_or_default();
+            match kind {
+                "rate_limit_exceeded" | "rate_limit_error" => ChoirError::UpstreamRateLimited {
+                    message: e.to_string(),
+                    retry_after: None,
+                },
+                // Bad credentials, an unknown model or a malformed request won't fix themselves.
+                "invalid_request_error" | "invalid_api_key" | "authentication_error"
+                | "permission_error" | "insufficient_quota" | "model_not_found" => {
+                    ChoirError::provider(e.to_string(), false)
+                }
+                _ => ChoirError::provider(e.to_string(), true),
+            }
+        }
+        OpenAIError::InvalidArgument(e) => ChoirError::provider(e, false),
+        e => ChoirError::provider(e.to_string(), true),
+    };
+    error.into()
+}
 
-        Ok(response_content)
+fn to_openai_message(message: ChatMessage) -> ChatCompletionRequestMessage {
+    match message {
+        ChatMessage::System(content) => {
+            ChatCompletionRequestMessage::System(ChatCompletionRequestSystemMessage {
+                content: ChatCompletionRequestSystemMessageContent::Text(content),
+                name: None,
+            })
+        }
+        ChatMessage::User(content) => {
+            ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
+                content: ChatCompletionRequestUserMessageContent::Text(content),
+                name: None,
+            })
+        }
+        ChatMessage::Assistant {
+            content,
+            tool_calls,
+        } => ChatCompletionRequestMessage::Assistant(ChatCompletionRequestAssistantMessage {
+            content: content.map(ChatCompletionRequestAssistantMessageContent::Text),
+            tool_calls: if tool_calls.is_empty() {
+                None
+            } else {
+                Some(
+                    tool_calls
+                        .into_iter()
+                        .map(|call| ChatCompletionMessageToolCall {
+                            id: call.id,
+                            r#type: ChatCompletionToolType::Function,
+                            function: FunctionCall {
+                                name: call.name,
+                                arguments: call.arguments,
+                            },
+                        })
+                        .collect(),
+                )
+            },
+            ..Default::default()
+        }),
+        ChatMessage::Tool {
+            tool_call_id,
+            content,
+        } => ChatCompletionRequestMessage::Tool(ChatCompletionRequestToolMessage {
+            content: ChatCompletionRequestToolMessageContent::Text(content),
+            tool_call_id,
+        }),
     }
-
-    // ^ will integrate hashmap 15 message limit for future but this was just a quick project so meh
 }
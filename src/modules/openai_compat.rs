@@ -0,0 +1,113 @@
+use crate::config::EnvConfig;
+use crate::modules::openai::{
+    acquire_permit, build_chat_request, create_chat, parse_chat_response, stream_chat,
+};
+use crate::modules::provider::{
+    ChatMessage, CompletionRequest, CompletionResponse, CompletionStream, LLMProvider,
+};
+use crate::Error;
+use async_openai::{config::OpenAIConfig, types::CreateChatCompletionRequest, Client};
+use async_trait::async_trait;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+// Any server exposing an OpenAI compatible `/v1/chat/completions` (Ollama, llama.cpp, vLLM...).
+pub struct OpenAICompatService {
+    client: Arc<Client<OpenAIConfig>>,
+    http: reqwest::Client,
+    semaphore: Arc<Semaphore>,
+}
+
+impl OpenAICompatService {
+    pub fn new(config: Arc<EnvConfig>) -> Result<Self, Error> {
+        let base_url = config
+            .llm_base_url
+            .clone()
+            .ok_or("LLM_BASE_URL must be set for the openai_compat provider")?;
+
+        // Most local servers ignore the key but async-openai always sends one.
+        let api_key = config.llm_api_key.clone().unwrap_or_else(|| "none".to_string());
+
+        let openai_config = OpenAIConfig::new()
+            .with_api_base(base_url)
+            .with_api_key(api_key);
+
+        Ok(Self {
+            client: Arc::new(Client::with_config(openai_config)),
+            http: reqwest::Client::new(),
+            semaphore: Arc::new(Semaphore::new(15)),
+        })
+    }
+}
+
+#[async_trait]
+impl LLMProvider for OpenAICompatService {
+    fn name(&self) -> &'static str {
+        "openai_compat"
+    }
+
+    async fn chat_completion(
+        &self,
+        mut request: CompletionRequest,
+    ) -> Result<CompletionResponse, Error> {
+        let _permit = acquire_permit(self.name(), &self.semaphore).await?;
+
+        // Smaller local models don't always honour response_format, so spell the schema out too.
+        if let Some(schema) = &request.json_schema {
+            request.messages.insert(
+                0,
+                ChatMessage::system(format!(
+                    "Respond ONLY with a JSON object matching this JSON schema:\n{}",
+                    schema
+                )),
+            );
+        }
+        let structured = request.json_schema.is_some();
+
+        let resp = create_chat(&self.http, self.client.config(), &compat_chat_request(request)).await?;
+        let mut response = parse_chat_response(resp)?;
+
+        if structured {
+            response.content = response.content.map(|c| strip_code_fence(&c).to_string());
+        }
+
+        Ok(response)
+    }
+
+    async fn stream_completion(&self, request: CompletionRequest) -> Result<CompletionStream, Error> {
+        stream_chat(
+            self.name(),
+            &self.http,
+            self.client.config(),
+            &self.semaphore,
+            compat_chat_request(request),
+        )
+        .await
+    }
+
+    // Ollama, llama.cpp and vLLM all serve `/v1/models`.
+    async fn health_check(&self) -> Result<(), Error> {
+        self.client.models().list().await?;
+        Ok(())
+    }
+}
+
+// Most compatible servers only understand the older `max_tokens` field.
+#[allow(deprecated)]
+fn compat_chat_request(request: CompletionRequest) -> CreateChatCompletionRequest {
+    let mut req = build_chat_request(request);
+    req.max_tokens = req.max_completion_tokens.take();
+    req
+}
+
+// Local models love wrapping JSON in ```json fences.
+fn strip_code_fence(content: &str) -> &str {
+    let trimmed = content.trim();
+    match trimmed.strip_prefix("```") {
+        Some(rest) => {
+            let rest = rest.strip_prefix("json").unwrap_or(rest);
+            rest.strip_suffix("```").unwrap_or(rest).trim()
+        }
+        None => trimmed,
+    }
+}
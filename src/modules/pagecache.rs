@@ -0,0 +1,273 @@
+use crate::config::EnvConfig;
+use crate::modules::fetcher::Fetcher;
+use crate::modules::history::timestamp;
+use crate::modules::metrics::METRICS;
+use crate::types::tfetch::{CacheHit, FetchedPage, Validators};
+use crate::Error;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use log::warn;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use url::Url;
+
+// Keeps fetched pages in SQLite so asking about the same page twice fetches it once.
+// Pages are served as they are for `ttl`. After that they're revalidated with the site's
+// ETag or Last-Modified when the fetcher supports it, and fetched again otherwise.
+pub struct PageCache {
+    inner: Arc<dyn Fetcher>,
+    conn: Mutex<Connection>,
+    ttl: Duration,
+    /// Least recently used pages beyond this are dropped.
+    max_entries: usize,
+}
+
+struct CachedPage {
+    page: FetchedPage,
+    fetched_at: DateTime<Utc>,
+}
+
+impl PageCache {
+    pub fn open(path: &str, inner: Arc<dyn Fetcher>, config: &EnvConfig) -> Result<Self, Error> {
+        let conn = Connection::open(path)?;
+        // Run history and the key store write to the same file through their own connections.
+        conn.busy_timeout(Duration::from_secs(5))?;
+        conn.execute_batch(
+            r#"
+            CREATE TABLE IF NOT EXISTS page_cache (
+                url TEXT PRIMARY KEY,
+                page TEXT NOT NULL,
+                etag TEXT,
+                last_modified TEXT,
+                fetched_at TEXT NOT NULL,
+                last_used TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS page_cache_last_used ON page_cache (last_used);
+            "#,
+        )?;
+
+        Ok(Self {
+            inner,
+            conn: Mutex::new(conn),
+            ttl: config.fetch_cache_ttl,
+            max_entries: config.fetch_cache_max_entries,
+        })
+    }
+
+    fn load(&self, key: &str) -> Result<Option<CachedPage>, Error> {
+        let conn = self.conn.lock().unwrap();
+        let row: Option<(String, Option<String>, Option<String>, String)> = conn
+            .query_row(
+                "SELECT page, etag, last_modified, fetched_at FROM page_cache WHERE url = ?1",
+                params![key],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .optional()?;
+
+        let Some((page, etag, last_modified, fetched_at)) = row else {
+            return Ok(None);
+        };
+        let mut page: FetchedPage = serde_json::from_str(&page)?;
+        page.validators = Validators {
+            etag,
+            last_modified,
+        };
+        Ok(Some(CachedPage {
+            page,
+            fetched_at: DateTime::parse_from_rfc3339(&fetched_at)?.with_timezone(&Utc),
+        }))
+    }
+
+    fn store(&self, key: &str, page: &FetchedPage) -> Result<(), Error> {
+        let now = timestamp(&Utc::now());
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT OR REPLACE INTO page_cache (url, page, etag, last_modified, fetched_at, last_used)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?5)",
+            params![
+                key,
+                serde_json::to_string(page)?,
+                page.validators.etag,
+                page.validators.last_modified,
+                now,
+            ],
+        )?;
+        conn.execute(
+            "DELETE FROM page_cache WHERE url NOT IN
+             (SELECT url FROM page_cache ORDER BY last_used DESC LIMIT ?1)",
+            params![self.max_entries as i64],
+        )?;
+        Ok(())
+    }
+
+    // Mark a page used, and when it was revalidated also fresh again.
+    fn touch(&self, key: &str, revalidated: bool) -> Result<(), Error> {
+        let now = timestamp(&Utc::now());
+        let conn = self.conn.lock().unwrap();
+        if revalidated {
+            conn.execute(
+                "UPDATE page_cache SET fetched_at = ?2, last_used = ?2 WHERE url = ?1",
+                params![key, now],
+            )?;
+        } else {
+            conn.execute(
+                "UPDATE page_cache SET last_used = ?2 WHERE url = ?1",
+                params![key, now],
+            )?;
+        }
+        Ok(())
+    }
+
+    // Fetch from the site and cache the result. A cache that can't be written to only
+    // costs us the next fetch, so that's logged rather than failing this one.
+    async fn fetch_and_store(&self, key: &str, url: &str) -> Result<FetchedPage, Error> {
+        let page = self.inner.fetch(url).await?;
+        if let Err(e) = self.store(key, &page) {
+            warn!("Failed to cache {}: {}", url, e);
+        }
+        Ok(page)
+    }
+
+    fn is_fresh(&self, cached: &CachedPage) -> bool {
+        let age = Utc::now().signed_duration_since(cached.fetched_at);
+        age.to_std().is_ok_and(|age| age < self.ttl)
+    }
+}
+
+#[async_trait]
+impl Fetcher for PageCache {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    async fn fetch(&self, url: &str) -> Result<FetchedPage, Error> {
+        let Some(key) = cache_key(url) else {
+            return self.inner.fetch(url).await;
+        };
+        let cached = self.load(&key).unwrap_or_else(|e| {
+            warn!("Failed to read {} from the page cache: {}", url, e);
+            None
+        });
+        let Some(mut cached) = cached else {
+            METRICS.page_cache_lookup("miss");
+            return self.fetch_and_store(&key, url).await;
+        };
+
+        if self.is_fresh(&cached) {
+            METRICS.page_cache_lookup("hit");
+            if let Err(e) = self.touch(&key, false) {
+                warn!("Failed to update the page cache for {}: {}", url, e);
+            }
+            cached.page.cached = Some(CacheHit {
+                fetched_at: cached.fetched_at,
+                revalidated: false,
+            });
+            return Ok(cached.page);
+        }
+
+        if cached.page.validators.is_empty() {
+            METRICS.page_cache_lookup("miss");
+            return self.fetch_and_store(&key, url).await;
+        }
+        match self.inner.fetch_if_changed(url, &cached.page.validators).await? {
+            Some(page) => {
+                METRICS.page_cache_lookup("miss");
+                if let Err(e) = self.store(&key, &page) {
+                    warn!("Failed to cache {}: {}", url, e);
+                }
+                Ok(page)
+            }
+            None => {
+                METRICS.page_cache_lookup("revalidated");
+                if let Err(e) = self.touch(&key, true) {
+                    warn!("Failed to update the page cache for {}: {}", url, e);
+                }
+                cached.page.cached = Some(CacheHit {
+                    fetched_at: Utc::now(),
+                    revalidated: true,
+                });
+                Ok(cached.page)
+            }
+        }
+    }
+
+    // Skips the lookup, but the new copy still replaces the cached one.
+    async fn fetch_fresh(&self, url: &str) -> Result<FetchedPage, Error> {
+        match cache_key(url) {
+            Some(key) => self.fetch_and_store(&key, url).await,
+            None => self.inner.fetch(url).await,
+        }
+    }
+
+    async fn fetch_if_changed(&self, url: &str, validators: &Validators) -> Result<Option<FetchedPage>, Error> {
+        self.inner.fetch_if_changed(url, validators).await
+    }
+
+    async fn health_check(&self) -> Result<(), Error> {
+        self.inner.health_check().await
+    }
+}
+
+// The same page however the URL was written: host case, default ports, fragments and
+// query parameter order don't matter.
+fn cache_key(url: &str) -> Option<String> {
+    let mut url = Url::parse(url).ok()?;
+    url.set_fragment(None);
+
+    let mut pairs: Vec<(String, String)> = url.query_pairs().into_owned().collect();
+    if pairs.is_empty() {
+        url.set_query(None);
+    } else {
+        pairs.sort();
+        url.query_pairs_mut().clear().extend_pairs(pairs);
+    }
+    Some(url.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn same(a: &str, b: &str) {
+        assert_eq!(cache_key(a), cache_key(b), "{} and {}", a, b);
+        assert!(cache_key(a).is_some());
+    }
+
+    fn different(a: &str, b: &str) {
+        assert_ne!(cache_key(a), cache_key(b), "{} and {}", a, b);
+    }
+
+    #[test]
+    fn fragments_are_ignored() {
+        same("https://example.com/page#intro", "https://example.com/page");
+        same("https://example.com/page?q=1#a", "https://example.com/page?q=1#b");
+    }
+
+    #[test]
+    fn query_order_is_ignored() {
+        same("https://example.com/s?b=2&a=1", "https://example.com/s?a=1&b=2");
+        same("https://example.com/s?a=2&a=1", "https://example.com/s?a=1&a=2");
+        same("https://example.com/s?", "https://example.com/s");
+        different("https://example.com/s?a=1", "https://example.com/s?a=2");
+        different("https://example.com/s?a=1", "https://example.com/s");
+    }
+
+    #[test]
+    fn host_case_and_default_ports_are_ignored() {
+        same("https://EXAMPLE.com/page", "https://example.com/page");
+        same("https://example.com:443/page", "https://example.com/page");
+        same("http://example.com:80/page", "http://example.com/page");
+        same("https://example.com", "https://example.com/");
+        different("https://example.com:8443/page", "https://example.com/page");
+        different("http://example.com/page", "https://example.com/page");
+        // Paths are case sensitive.
+        different("https://example.com/Page", "https://example.com/page");
+    }
+
+    #[test]
+    fn unparseable_urls_have_no_key() {
+        assert_eq!(cache_key("not a url"), None);
+        assert_eq!(cache_key(""), None);
+    }
+}
@@ -0,0 +1,177 @@
+use crate::ai_functions::AIFunction;
+use crate::config::EnvConfig;
+use crate::modules::breaker::CircuitBreaker;
+use crate::modules::openai::OpenAIService;
+use crate::modules::openai_compat::OpenAICompatService;
+use crate::Error;
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::Arc;
+
+// Provider-neutral chat message. Providers translate these into their own wire types.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ChatMessage {
+    System(String),
+    User(String),
+    Assistant {
+        content: Option<String>,
+        tool_calls: Vec<ToolCall>,
+    },
+    Tool {
+        tool_call_id: String,
+        content: String,
+    },
+}
+
+impl ChatMessage {
+    pub fn system(content: impl Into<String>) -> Self {
+        ChatMessage::System(content.into())
+    }
+
+    pub fn user(content: impl Into<String>) -> Self {
+        ChatMessage::User(content.into())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    /// Raw JSON arguments as returned by the model.
+    pub arguments: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct ToolDefinition {
+    pub name: String,
+    pub description: String,
+    /// JSON schema of the function parameters.
+    pub parameters: Value,
+}
+
+impl ToolDefinition {
+    pub fn from_function(func: &dyn AIFunction) -> Self {
+        let mut properties = serde_json::Map::new();
+        let mut required = Vec::new();
+
+        for (param_name, param) in func.parameters() {
+            properties.insert(
+                param_name.clone(),
+                serde_json::json!({
+                    "type": param.param_type,
+                    "description": param.description
+                }),
+            );
+
+            if param.required {
+                required.push(param_name);
+            }
+        }
+
+        Self {
+            name: func.name().to_string(),
+            description: func.description().to_string(),
+            parameters: serde_json::json!({
+                "type": "object",
+                "properties": properties,
+                "required": required
+            }),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CompletionRequest {
+    pub model: String,
+    pub messages: Vec<ChatMessage>,
+    /// When set the provider is asked for structured output matching this schema.
+    pub json_schema: Option<Value>,
+    pub tools: Vec<ToolDefinition>,
+    pub temperature: Option<f32>,
+    pub max_tokens: Option<u32>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CompletionResponse {
+    pub content: Option<String>,
+    pub tool_calls: Vec<ToolCall>,
+    /// Token counts reported by the provider, when it reports them.
+    pub usage: Option<TokenUsage>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct TokenUsage {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    pub total_tokens: u32,
+}
+
+#[derive(Clone, Debug)]
+pub enum StreamChunk {
+    Delta(String),
+    /// Sent once at the end of the stream by providers that report usage.
+    Usage(TokenUsage),
+}
+
+pub type CompletionStream = BoxStream<'static, Result<StreamChunk, Error>>;
+
+#[async_trait]
+pub trait LLMProvider: Send + Sync {
+    fn name(&self) -> &'static str;
+    async fn chat_completion(&self, request: CompletionRequest) -> Result<CompletionResponse, Error>;
+
+    // A cheap call that succeeds when the provider is reachable and accepts our credentials.
+    async fn health_check(&self) -> Result<(), Error>;
+
+    // Providers without native streaming hand back the whole completion as a single delta.
+    async fn stream_completion(&self, request: CompletionRequest) -> Result<CompletionStream, Error> {
+        let response = self.chat_completion(request).await?;
+
+        let mut chunks = vec![Ok(StreamChunk::Delta(response.content.unwrap_or_default()))];
+        if let Some(usage) = response.usage {
+            chunks.push(Ok(StreamChunk::Usage(usage)));
+        }
+        Ok(Box::pin(futures::stream::iter(chunks)))
+    }
+}
+
+// Puts a provider behind a circuit breaker, so completions fail fast while it's down.
+// Health checks go straight through, they're how we find out it's back.
+pub struct GuardedProvider {
+    inner: Arc<dyn LLMProvider>,
+    breaker: Arc<CircuitBreaker>,
+}
+
+#[async_trait]
+impl LLMProvider for GuardedProvider {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    async fn chat_completion(&self, request: CompletionRequest) -> Result<CompletionResponse, Error> {
+        self.breaker.call(self.inner.chat_completion(request)).await
+    }
+
+    async fn health_check(&self) -> Result<(), Error> {
+        self.inner.health_check().await
+    }
+
+    // Only opening the stream counts, a stream that breaks halfway isn't retried either.
+    async fn stream_completion(&self, request: CompletionRequest) -> Result<CompletionStream, Error> {
+        self.breaker.call(self.inner.stream_completion(request)).await
+    }
+}
+
+// Build the provider selected by `LLM_PROVIDER`, behind the given breaker.
+pub fn from_config(config: Arc<EnvConfig>, breaker: Arc<CircuitBreaker>) -> Result<Arc<dyn LLMProvider>, Error> {
+    let inner: Arc<dyn LLMProvider> = match config.llm_provider.as_str() {
+        "openai" => Arc::new(OpenAIService::new(config)?),
+        "openai_compat" => Arc::new(OpenAICompatService::new(config)?),
+        other => {
+            return Err(format!("Unknown LLM provider '{}', expected openai or openai_compat", other).into())
+        }
+    };
+    Ok(Arc::new(GuardedProvider { inner, breaker }))
+}
@@ -0,0 +1,279 @@
+use crate::config::EnvConfig;
+use crate::modules::metrics::METRICS;
+use crate::types::terror::ChoirError;
+use crate::types::trun::{CallRetry, RetryAction};
+use crate::Error;
+use log::warn;
+use rand::Rng;
+use std::sync::Mutex;
+use std::time::Duration;
+
+// How often and how patiently a failed LLM call is retried on the same model.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    pub fn from_config(config: &EnvConfig) -> Self {
+        Self {
+            max_retries: config.llm_max_retries,
+            base_delay: Duration::from_millis(config.llm_retry_base_ms),
+            max_delay: Duration::from_millis(config.llm_retry_max_ms),
+        }
+    }
+
+    // How long to wait before retry number `retry` (from 1), or None to give up on the model.
+    // The provider's `Retry-After` wins over our own backoff, but if it asks for longer than
+    // `max_delay` we'd rather try the next model.
+    pub fn delay(&self, retry: u32, error: &ChoirError) -> Option<Duration> {
+        self.delay_with(retry, error, &mut rand::rng())
+    }
+
+    fn delay_with(&self, retry: u32, error: &ChoirError, rng: &mut impl Rng) -> Option<Duration> {
+        if retry > self.max_retries || !error.is_retryable() {
+            return None;
+        }
+        if let Some(retry_after) = error.retry_after() {
+            return (retry_after <= self.max_delay).then_some(retry_after);
+        }
+
+        // Exponential with jitter over the upper half, so callers that failed together
+        // don't all come back together.
+        let ceiling = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(retry - 1))
+            .min(self.max_delay);
+        let ceiling_ms = ceiling.as_millis() as u64;
+        Some(Duration::from_millis(rng.random_range(ceiling_ms / 2..=ceiling_ms)))
+    }
+}
+
+// Collects the retries and fallbacks made during a run.
+#[derive(Default)]
+pub struct RetryLog {
+    retries: Mutex<Vec<CallRetry>>,
+}
+
+impl RetryLog {
+    pub fn snapshot(&self) -> Vec<CallRetry> {
+        self.retries.lock().unwrap().clone()
+    }
+
+    fn record(&self, retry: CallRetry) {
+        self.retries.lock().unwrap().push(retry);
+    }
+}
+
+// Walks one call through its retries and down the stage's fallback chain.
+pub struct CallAttempts<'a> {
+    policy: &'a RetryPolicy,
+    log: &'a RetryLog,
+    stage: &'a str,
+    /// The requested model first, then its fallbacks.
+    chain: Vec<String>,
+    current: usize,
+    retries: u32,
+}
+
+impl<'a> CallAttempts<'a> {
+    pub fn new(
+        policy: &'a RetryPolicy,
+        log: &'a RetryLog,
+        stage: &'a str,
+        model: &str,
+        fallbacks: &[String],
+    ) -> Self {
+        let mut chain = vec![model.to_string()];
+        for fallback in fallbacks {
+            if !chain.contains(fallback) {
+                chain.push(fallback.clone());
+            }
+        }
+
+        Self {
+            policy,
+            log,
+            stage,
+            chain,
+            current: 0,
+            retries: 0,
+        }
+    }
+
+    // The model to call next.
+    pub fn model(&self) -> &str {
+        &self.chain[self.current]
+    }
+
+    // Decide what to do about a failed call: wait and retry the same model, move on to the
+    // next one, or hand the error back when there's nothing left to try.
+    pub async fn failed(&mut self, error: Error) -> Result<(), Error> {
+        let error = ChoirError::from(error);
+        // Every model in the chain is on the same provider, and the point of an open
+        // circuit is not waiting on it.
+        if let ChoirError::CircuitOpen { .. } = error {
+            return Err(error.into());
+        }
+        let model = self.model().to_string();
+
+        let (action, delay) = if let Some(delay) = self.policy.delay(self.retries + 1, &error) {
+            self.retries += 1;
+            let delay_ms = delay.as_millis() as u64;
+            (RetryAction::Retry { delay_ms }, Some(delay))
+        } else if let Some(next) = self.chain.get(self.current + 1) {
+            self.current += 1;
+            self.retries = 0;
+            (RetryAction::Fallback { to: next.clone() }, None)
+        } else {
+            return Err(error.into());
+        };
+
+        warn!(
+            "{} call to {} failed ({}), {}",
+            self.stage,
+            model,
+            error,
+            match &action {
+                RetryAction::Retry { delay_ms } => format!("retrying in {}ms", delay_ms),
+                RetryAction::Fallback { to } => format!("falling back to {}", to),
+            }
+        );
+        METRICS.llm_retry(self.stage, &model, &action);
+        self.log.record(CallRetry {
+            stage: self.stage.to_string(),
+            model,
+            code: error.code(),
+            error: error.public_message(),
+            action,
+        });
+
+        if let Some(delay) = delay {
+            actix_web::rt::time::sleep(delay).await;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn policy(max_retries: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_retries,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(1_000),
+        }
+    }
+
+    fn retryable() -> ChoirError {
+        ChoirError::provider("503 Service Unavailable", true)
+    }
+
+    #[test]
+    fn backoff_grows_and_is_capped() {
+        let policy = policy(10);
+        let mut rng = StdRng::seed_from_u64(7);
+        // Ceilings of 100, 200, 400 and 800ms, then the 1s cap.
+        for (retry, ceiling) in [(1, 100), (2, 200), (3, 400), (4, 800), (5, 1_000), (10, 1_000)] {
+            for _ in 0..50 {
+                let delay = policy.delay_with(retry, &retryable(), &mut rng).unwrap();
+                let ms = delay.as_millis() as u64;
+                assert!((ceiling / 2..=ceiling).contains(&ms), "retry {}: {}ms", retry, ms);
+            }
+        }
+    }
+
+    #[test]
+    fn jitter_comes_from_the_rng() {
+        let policy = policy(3);
+        let delays = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            (1..=3)
+                .map(|retry| policy.delay_with(retry, &retryable(), &mut rng))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(delays(1), delays(1));
+        // Callers that failed together don't all come back together.
+        assert_ne!(delays(1), delays(2));
+    }
+
+    #[test]
+    fn gives_up_after_max_retries_or_on_permanent_errors() {
+        let policy = policy(2);
+        assert!(policy.delay(2, &retryable()).is_some());
+        assert!(policy.delay(3, &retryable()).is_none());
+        assert!(policy.delay(1, &ChoirError::provider("401 Unauthorized", false)).is_none());
+        assert!(policy.delay(1, &ChoirError::BadRequest("bad".to_string())).is_none());
+    }
+
+    #[test]
+    fn honours_retry_after_up_to_max_delay() {
+        let policy = policy(2);
+        let limited = |secs: f64| ChoirError::UpstreamRateLimited {
+            message: "429 Too Many Requests".to_string(),
+            retry_after: Some(Duration::from_secs_f64(secs)),
+        };
+        assert_eq!(policy.delay(1, &limited(0.75)), Some(Duration::from_millis(750)));
+        // Longer than we're willing to wait, the next model gets a go instead.
+        assert_eq!(policy.delay(1, &limited(30.0)), None);
+        // Still only up to max_retries.
+        assert_eq!(policy.delay(3, &limited(0.1)), None);
+    }
+
+    #[actix_web::test]
+    async fn retries_then_falls_back_down_the_chain() {
+        let policy = RetryPolicy {
+            max_retries: 1,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(1),
+        };
+        let log = RetryLog::default();
+        let fallbacks = ["gpt-4o".to_string(), "gpt-4o-mini".to_string(), "llama3".to_string()];
+        let mut attempts = CallAttempts::new(&policy, &log, "summary", "gpt-4o", &fallbacks);
+
+        assert_eq!(attempts.model(), "gpt-4o");
+        attempts.failed(retryable().into()).await.unwrap();
+        assert_eq!(attempts.model(), "gpt-4o");
+        attempts.failed(retryable().into()).await.unwrap();
+        assert_eq!(attempts.model(), "gpt-4o-mini");
+        // A permanent error skips the retries.
+        attempts.failed(ChoirError::provider("404 model_not_found", false).into()).await.unwrap();
+        assert_eq!(attempts.model(), "llama3");
+        attempts.failed(retryable().into()).await.unwrap();
+        let error = attempts.failed(retryable().into()).await.unwrap_err();
+        assert!(matches!(ChoirError::from(error), ChoirError::Provider { .. }));
+
+        let actions: Vec<String> = log
+            .snapshot()
+            .into_iter()
+            .map(|retry| match retry.action {
+                RetryAction::Retry { .. } => format!("{} retry", retry.model),
+                RetryAction::Fallback { to } => format!("{} -> {}", retry.model, to),
+            })
+            .collect();
+        assert_eq!(
+            actions,
+            ["gpt-4o retry", "gpt-4o -> gpt-4o-mini", "gpt-4o-mini -> llama3", "llama3 retry"]
+        );
+    }
+
+    #[actix_web::test]
+    async fn open_circuit_is_not_retried() {
+        let policy = policy(3);
+        let log = RetryLog::default();
+        let fallbacks = ["gpt-4o-mini".to_string()];
+        let mut attempts = CallAttempts::new(&policy, &log, "agents", "gpt-4o", &fallbacks);
+        let open = ChoirError::CircuitOpen {
+            upstream: "llm".to_string(),
+            retry_after: Duration::from_millis(10),
+        };
+        assert!(attempts.failed(open.into()).await.is_err());
+        assert!(log.snapshot().is_empty());
+    }
+}
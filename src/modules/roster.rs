@@ -0,0 +1,94 @@
+use crate::ai_functions::FUNCTION_NAMES;
+use crate::types::tchoir::AgentDefinition;
+use crate::Error;
+use serde::Deserialize;
+use std::collections::HashSet;
+
+// The agents a choir run delegates to, loaded once at startup.
+#[derive(Deserialize, Debug, Clone)]
+pub struct AgentRoster {
+    agents: Vec<AgentDefinition>,
+}
+
+impl AgentRoster {
+    // Read a roster from a JSON file shaped like `{ "agents": [ ... ] }`.
+    pub fn load(path: &str) -> Result<Self, Error> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| Error::from(format!("Failed to read agent roster {}: {}", path, e)))?;
+        let roster: AgentRoster = serde_json::from_str(&contents)
+            .map_err(|e| Error::from(format!("Invalid agent roster {}: {}", path, e)))?;
+
+        validate_agents(&roster.agents)
+            .map_err(|e| Error::from(format!("Invalid agent roster {}: {}", path, e)))?;
+        Ok(roster)
+    }
+
+    pub fn agents(&self) -> &[AgentDefinition] {
+        &self.agents
+    }
+
+    pub fn get(&self, name: &str) -> Option<&AgentDefinition> {
+        self.agents.iter().find(|a| a.name == name)
+    }
+}
+
+// Shared by the startup roster and agents supplied inline with a request.
+pub fn validate_agents(agents: &[AgentDefinition]) -> Result<(), String> {
+    if agents.is_empty() {
+        return Err("At least one agent is required".to_string());
+    }
+
+    let mut names = HashSet::new();
+    for agent in agents {
+        if agent.name.trim().is_empty() || agent.role.trim().is_empty() {
+            return Err("Agents need a non-empty name and role".to_string());
+        }
+        if !names.insert(agent.name.as_str()) {
+            return Err(format!("Duplicate agent name: {}", agent.name));
+        }
+        if let Some(t) = agent.temperature {
+            if !(0.0..=2.0).contains(&t) {
+                return Err(format!("Agent {} temperature must be between 0 and 2", agent.name));
+            }
+        }
+        if let Some(schema) = &agent.output_schema {
+            if !schema.is_object() {
+                return Err(format!("Agent {} output_schema must be a JSON object", agent.name));
+            }
+        }
+        if let Some(tool) = agent.tools.iter().find(|t| !FUNCTION_NAMES.contains(&t.as_str())) {
+            return Err(format!(
+                "Agent {} has unknown tool {}, expected one of {}",
+                agent.name,
+                tool,
+                FUNCTION_NAMES.join(", ")
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+impl Default for AgentRoster {
+    // The original five agent choir.
+    fn default() -> Self {
+        let agent = |name: &str, role: &str| AgentDefinition {
+            name: name.to_string(),
+            role: role.to_string(),
+            model: None,
+            temperature: None,
+            output_schema: None,
+            tools: Vec::new(),
+        };
+
+        Self {
+            agents: vec![
+                agent("Direct Analysis Expert", "Be precise and methodical in your analysis."),
+                agent("Critical Evaluator", "Question assumptions and identify potential issues."),
+                agent("Context Specialist", "Consider broader context, connections, and underlying patterns."),
+                agent("Creative Interpreter", "Think creatively while staying grounded in facts."),
+                agent("Synthesis Expert", "Integrate different viewpoints and provide comprehensive analysis."),
+            ],
+        }
+    }
+}
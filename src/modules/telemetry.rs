@@ -0,0 +1,104 @@
+use crate::config::EnvConfig;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use log::{error, info};
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::SpanExporter;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use tracing::field::Empty;
+use tracing::Instrument;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+// Spans from everything else (hyper, reqwest, ...) are noise in a trace of a run.
+const DEFAULT_TRACE_FILTER: &str = "choir=info";
+
+// Flushes and stops the exporter when dropped, so spans from the last requests aren't lost.
+pub struct Telemetry(Option<SdkTracerProvider>);
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if let Some(provider) = self.0.take() {
+            if let Err(e) = provider.shutdown() {
+                error!("Failed to shut down the trace exporter: {}", e);
+            }
+        }
+    }
+}
+
+// Export spans over OTLP/HTTP when a collector is configured. Without one spans are never
+// recorded and cost next to nothing. Logging stays with env_logger either way.
+pub fn init(config: &EnvConfig) -> Telemetry {
+    let Some(endpoint) = &config.otlp_endpoint else {
+        // With no subscriber at all tracing forwards every span to `log` (actix turns that on),
+        // doubling up the env_logger output.
+        let _ = tracing::subscriber::set_global_default(tracing::subscriber::NoSubscriber::default());
+        return Telemetry(None);
+    };
+
+    // The exporter picks up the endpoint, headers and timeout from the standard
+    // OTEL_EXPORTER_OTLP_* variables itself.
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .build()
+        .unwrap_or_else(|e| panic!("Failed to build the OTLP exporter: {}", e));
+
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(
+            Resource::builder()
+                .with_service_name(config.otel_service_name.clone())
+                .build(),
+        )
+        .build();
+
+    let filter = EnvFilter::try_from_env("OTEL_TRACES_FILTER")
+        .unwrap_or_else(|_| EnvFilter::new(DEFAULT_TRACE_FILTER));
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("choir")))
+        .init();
+
+    info!("Exporting traces to {}", endpoint);
+    Telemetry(Some(provider))
+}
+
+// Middleware giving every request an ID and a span to hang the rest of its work on.
+// The caller's `X-Request-Id` is kept when it sends one, and the ID is echoed back.
+pub async fn trace_requests(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let request_id = req
+        .headers()
+        .get(&REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty() && v.len() <= 128)
+        .map(str::to_string)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    let route = req.match_pattern().unwrap_or_else(|| "unmatched".to_string());
+
+    let span = tracing::info_span!(
+        "http_request",
+        otel.name = format!("{} {}", req.method(), route),
+        request_id = %request_id,
+        http.method = %req.method(),
+        http.route = %route,
+        http.status_code = Empty,
+    );
+
+    let mut res = next.call(req).instrument(span.clone()).await?;
+    span.record("http.status_code", res.status().as_u16());
+
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        res.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    Ok(res)
+}
@@ -0,0 +1,303 @@
+use crate::config::EnvConfig;
+use crate::modules::provider::ChatMessage;
+use crate::Error;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tiktoken_rs::tokenizer::{get_tokenizer, Tokenizer as Encoding};
+use tiktoken_rs::CoreBPE;
+
+// Tokens every chat message costs on top of its content, and what priming the reply costs.
+// Same accounting as OpenAI's cookbook.
+const TOKENS_PER_MESSAGE: u64 = 3;
+const TOKENS_PER_REPLY: u64 = 3;
+// Bytes per token `BpeTokenizer::prefix` looks at.
+const MAX_TOKEN_BYTES: usize = 32;
+
+// Context windows of OpenAI's chat models. Dated names match by prefix.
+const KNOWN_WINDOWS: &[(&str, u64)] = &[
+    ("gpt-4o", 128_000),
+    ("chatgpt-4o", 128_000),
+    ("gpt-4.1", 1_047_576),
+    ("gpt-4-turbo", 128_000),
+    ("gpt-4", 8_192),
+    ("gpt-3.5-turbo", 16_385),
+    ("o1", 200_000),
+    ("o3", 200_000),
+    ("o4-mini", 200_000),
+];
+
+// Counts text the way a model will.
+pub trait Tokenizer: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn count(&self, text: &str) -> u64;
+    // The longest start of `text` that's at most `tokens` tokens, never splitting a character.
+    fn prefix<'a>(&self, text: &'a str, tokens: u64) -> &'a str;
+}
+
+// One of OpenAI's byte pair encodings, the same tiktoken uses.
+pub struct BpeTokenizer {
+    name: &'static str,
+    bpe: &'static CoreBPE,
+}
+
+impl Tokenizer for BpeTokenizer {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn count(&self, text: &str) -> u64 {
+        self.bpe.encode_ordinary(text).len() as u64
+    }
+
+    fn prefix<'a>(&self, text: &'a str, tokens: u64) -> &'a str {
+        // Tokens are rarely this long, so there's no need to encode the rest of a long text.
+        let mut window = text.len().min((tokens as usize + 1).saturating_mul(MAX_TOKEN_BYTES));
+        while !text.is_char_boundary(window) {
+            window -= 1;
+        }
+        let encoded = self.bpe.encode_ordinary(&text[..window]);
+        if encoded.len() as u64 <= tokens {
+            return &text[..window];
+        }
+        // A character can be spread over several tokens, cutting between them doesn't decode.
+        let mut end = tokens as usize;
+        loop {
+            if let Ok(decoded) = self.bpe.decode(encoded[..end].to_vec()) {
+                return &text[..decoded.len()];
+            }
+            end -= 1;
+        }
+    }
+}
+
+// About four characters per token, for models nobody told us the tokenizer of.
+pub struct ApproxTokenizer;
+
+impl Tokenizer for ApproxTokenizer {
+    fn name(&self) -> &'static str {
+        "approx"
+    }
+
+    fn count(&self, text: &str) -> u64 {
+        (text.chars().count() as u64).div_ceil(4)
+    }
+
+    fn prefix<'a>(&self, text: &'a str, tokens: u64) -> &'a str {
+        let end = text
+            .char_indices()
+            .nth((tokens * 4) as usize)
+            .map_or(text.len(), |(i, _)| i);
+        &text[..end]
+    }
+}
+
+// The tokenizer for each model. OpenAI models are recognised by name, others are set with
+// `MODEL_TOKENIZERS` or `register`, and anything else is counted with `ApproxTokenizer`.
+#[derive(Default)]
+pub struct Tokenizers {
+    /// By model name prefix, the longest matching prefix wins.
+    models: HashMap<String, Arc<dyn Tokenizer>>,
+    /// Context windows by model name prefix, like `models`.
+    windows: HashMap<String, u64>,
+}
+
+impl Tokenizers {
+    pub fn from_config(config: &EnvConfig) -> Result<Self, Error> {
+        let mut tokenizers = Self::default();
+        for (model, name) in &config.model_tokenizers {
+            let tokenizer = by_name(name).ok_or_else(|| {
+                format!(
+                    "Unknown tokenizer '{}' for {}, expected o200k_base, cl100k_base, p50k_base, r50k_base or approx",
+                    name, model
+                )
+            })?;
+            tokenizers.register(model, tokenizer);
+        }
+        tokenizers.windows.extend(config.model_context_windows.clone());
+        Ok(tokenizers)
+    }
+
+    pub fn register(&mut self, model_prefix: &str, tokenizer: Arc<dyn Tokenizer>) {
+        self.models.insert(model_prefix.to_string(), tokenizer);
+    }
+
+    pub fn for_model(&self, model: &str) -> Arc<dyn Tokenizer> {
+        if let Some(tokenizer) = longest_prefix(&self.models, model) {
+            return tokenizer.clone();
+        }
+        get_tokenizer(base_name(model))
+            .map(bpe)
+            .unwrap_or_else(|| Arc::new(ApproxTokenizer))
+    }
+
+    // Most tokens a prompt and its reply may take on `model`. None for models we don't know.
+    pub fn context_window(&self, model: &str) -> Option<u64> {
+        longest_prefix(&self.windows, model)
+            .copied()
+            .or_else(|| longest_prefix(KNOWN_WINDOWS.iter().copied(), base_name(model)))
+    }
+
+    // Prompt tokens of a chat request, counted the way the API bills them.
+    pub fn count_messages(&self, model: &str, messages: &[ChatMessage]) -> u64 {
+        let tokenizer = self.for_model(model);
+        let content: u64 = messages
+            .iter()
+            .map(|message| TOKENS_PER_MESSAGE + message_tokens(tokenizer.as_ref(), message))
+            .sum();
+        content + TOKENS_PER_REPLY
+    }
+}
+
+pub fn message_tokens(tokenizer: &dyn Tokenizer, message: &ChatMessage) -> u64 {
+    // The role is about one token.
+    1 + match message {
+        ChatMessage::System(content) | ChatMessage::User(content) => tokenizer.count(content),
+        ChatMessage::Assistant {
+            content,
+            tool_calls,
+        } => {
+            content.as_deref().map_or(0, |c| tokenizer.count(c))
+                + tool_calls
+                    .iter()
+                    .map(|call| tokenizer.count(&call.name) + tokenizer.count(&call.arguments))
+                    .sum::<u64>()
+        }
+        ChatMessage::Tool { content, .. } => tokenizer.count(content),
+    }
+}
+
+fn by_name(name: &str) -> Option<Arc<dyn Tokenizer>> {
+    match name {
+        "approx" => Some(Arc::new(ApproxTokenizer)),
+        "o200k_base" => Some(bpe(Encoding::O200kBase)),
+        "cl100k_base" => Some(bpe(Encoding::Cl100kBase)),
+        "p50k_base" => Some(bpe(Encoding::P50kBase)),
+        "r50k_base" => Some(bpe(Encoding::R50kBase)),
+        _ => None,
+    }
+}
+
+// The encodings are built once and shared, building one takes a while.
+fn bpe(encoding: Encoding) -> Arc<dyn Tokenizer> {
+    let (name, bpe) = match encoding {
+        Encoding::O200kBase => ("o200k_base", tiktoken_rs::o200k_base_singleton()),
+        Encoding::Cl100kBase => ("cl100k_base", tiktoken_rs::cl100k_base_singleton()),
+        Encoding::P50kBase => ("p50k_base", tiktoken_rs::p50k_base_singleton()),
+        Encoding::R50kBase | Encoding::Gpt2 => ("r50k_base", tiktoken_rs::r50k_base_singleton()),
+        Encoding::P50kEdit => ("p50k_edit", tiktoken_rs::p50k_edit_singleton()),
+    };
+    Arc::new(BpeTokenizer { name, bpe })
+}
+
+// Routers name models like `openai/gpt-4o`.
+fn base_name(model: &str) -> &str {
+    model.rsplit('/').next().unwrap_or(model)
+}
+
+fn longest_prefix<K: AsRef<str>, T>(entries: impl IntoIterator<Item = (K, T)>, model: &str) -> Option<T> {
+    entries
+        .into_iter()
+        .filter(|(prefix, _)| model.starts_with(prefix.as_ref()))
+        .max_by_key(|(prefix, _)| prefix.as_ref().len())
+        .map(|(_, value)| value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::provider::ToolCall;
+
+    #[test]
+    fn models_get_their_encoding_by_prefix() {
+        let tokenizers = Tokenizers::default();
+        assert_eq!(tokenizers.for_model("gpt-4o").name(), "o200k_base");
+        assert_eq!(tokenizers.for_model("gpt-4o-2024-08-06").name(), "o200k_base");
+        assert_eq!(tokenizers.for_model("openai/gpt-4o-mini").name(), "o200k_base");
+        assert_eq!(tokenizers.for_model("gpt-4-0613").name(), "cl100k_base");
+        assert_eq!(tokenizers.for_model("gpt-3.5-turbo").name(), "cl100k_base");
+    }
+
+    #[test]
+    fn unknown_models_are_approximated() {
+        let tokenizers = Tokenizers::default();
+        assert_eq!(tokenizers.for_model("llama3.1:8b").name(), "approx");
+        assert_eq!(tokenizers.for_model("").name(), "approx");
+        assert_eq!(tokenizers.context_window("llama3.1:8b"), None);
+    }
+
+    #[test]
+    fn registered_tokenizers_win_and_the_longest_prefix_counts() {
+        let mut tokenizers = Tokenizers::default();
+        tokenizers.register("gpt-4", by_name("approx").unwrap());
+        tokenizers.register("gpt-4o", by_name("r50k_base").unwrap());
+        tokenizers.register("mistral", by_name("p50k_base").unwrap());
+        assert_eq!(tokenizers.for_model("gpt-4-turbo").name(), "approx");
+        assert_eq!(tokenizers.for_model("gpt-4o-mini").name(), "r50k_base");
+        assert_eq!(tokenizers.for_model("mistral-large").name(), "p50k_base");
+        assert!(by_name("gpt2").is_none());
+    }
+
+    #[test]
+    fn context_windows_by_prefix() {
+        let mut tokenizers = Tokenizers::default();
+        assert_eq!(tokenizers.context_window("gpt-4o-2024-08-06"), Some(128_000));
+        assert_eq!(tokenizers.context_window("openai/gpt-4.1-mini"), Some(1_047_576));
+        assert_eq!(tokenizers.context_window("gpt-4-0613"), Some(8_192));
+        assert_eq!(tokenizers.context_window("gpt-4-turbo-preview"), Some(128_000));
+
+        // Configured windows come first, for any model.
+        tokenizers.windows.insert("gpt-4o".to_string(), 32_000);
+        tokenizers.windows.insert("llama".to_string(), 8_000);
+        assert_eq!(tokenizers.context_window("gpt-4o-mini"), Some(32_000));
+        assert_eq!(tokenizers.context_window("llama3.1:8b"), Some(8_000));
+        assert_eq!(tokenizers.context_window("gpt-4"), Some(8_192));
+    }
+
+    #[test]
+    fn messages_are_counted_like_the_api_bills_them() {
+        let tokenizers = Tokenizers::default();
+        // "hello world" is two tokens in cl100k_base.
+        assert_eq!(tokenizers.for_model("gpt-4").count("hello world"), 2);
+        assert_eq!(tokenizers.count_messages("gpt-4", &[]), TOKENS_PER_REPLY);
+
+        let messages = [ChatMessage::system("hello world"), ChatMessage::user("hello world")];
+        // Per message its overhead, the role and the content, then the reply priming.
+        assert_eq!(tokenizers.count_messages("gpt-4", &messages), 2 * (3 + 1 + 2) + 3);
+
+        let call = ChatMessage::Assistant {
+            content: None,
+            tool_calls: vec![ToolCall {
+                id: "call_1".to_string(),
+                name: "hello".to_string(),
+                arguments: "world".to_string(),
+            }],
+        };
+        assert_eq!(tokenizers.count_messages("gpt-4", &[call]), 3 + 1 + 2 + 3);
+    }
+
+    #[test]
+    fn prefixes_stop_at_the_token_limit() {
+        let tokenizer = Tokenizers::default().for_model("gpt-4o");
+        assert_eq!(tokenizer.prefix("hello world", 1), "hello");
+        assert_eq!(tokenizer.prefix("hello world", 5), "hello world");
+        assert_eq!(tokenizer.prefix("hello world", 0), "");
+
+        // Never half a character, even when it takes several tokens.
+        let text = "🦀🦀🦀";
+        for tokens in 0..=tokenizer.count(text) {
+            let prefix = tokenizer.prefix(text, tokens);
+            assert!(tokenizer.count(prefix) <= tokens);
+            assert!(text.starts_with(prefix));
+        }
+    }
+
+    #[test]
+    fn approx_is_four_characters_a_token() {
+        assert_eq!(ApproxTokenizer.count(""), 0);
+        assert_eq!(ApproxTokenizer.count("abcd"), 1);
+        assert_eq!(ApproxTokenizer.count("abcde"), 2);
+        assert_eq!(ApproxTokenizer.count("ééééé"), 2);
+        assert_eq!(ApproxTokenizer.prefix("ééééé", 1), "éééé");
+        assert_eq!(ApproxTokenizer.prefix("abc", 1), "abc");
+    }
+}
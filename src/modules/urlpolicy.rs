@@ -0,0 +1,425 @@
+use crate::config::EnvConfig;
+use crate::types::terror::ChoirError;
+use log::warn;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::redirect;
+use std::error::Error as StdError;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use url::{Host, Url};
+
+// Which URLs we're willing to fetch. Callers type whatever they like into a query, so
+// without this anyone could have us fetch our own internal services.
+pub struct UrlPolicy {
+    /// Fetch private, loopback and link-local addresses too. For local testing only.
+    allow_private: bool,
+    schemes: Vec<String>,
+    /// Any port when empty.
+    ports: Vec<u16>,
+    /// Only these domains (and their subdomains) when not empty.
+    allowed_domains: Vec<String>,
+    /// Never these domains or their subdomains, allowed or not.
+    denied_domains: Vec<String>,
+    max_redirects: usize,
+}
+
+impl UrlPolicy {
+    pub fn from_config(config: &EnvConfig) -> Self {
+        let domains = |list: &[String]| {
+            list.iter()
+                .map(|d| d.trim_start_matches("*.").trim_start_matches('.').to_ascii_lowercase())
+                .collect()
+        };
+        Self {
+            allow_private: config.fetch_allow_private,
+            schemes: config.fetch_allowed_schemes.clone(),
+            ports: config.fetch_allowed_ports.clone(),
+            allowed_domains: domains(&config.fetch_allowed_domains),
+            denied_domains: domains(&config.fetch_denied_domains),
+            max_redirects: config.fetch_max_redirects,
+        }
+    }
+
+    // Any URL at all, private addresses included, for tests against local servers.
+    #[cfg(test)]
+    pub fn allow_all() -> Self {
+        Self {
+            allow_private: true,
+            schemes: vec!["http".to_string(), "https".to_string()],
+            ports: Vec::new(),
+            allowed_domains: Vec::new(),
+            denied_domains: Vec::new(),
+            max_redirects: 5,
+        }
+    }
+
+    // Check a URL before fetching it, resolving its host to make sure none of its
+    // addresses are private. The resolver and redirect policy below check again while
+    // fetching, as DNS answers and redirects can change between now and then.
+    pub async fn check(&self, url: &str) -> Result<(), ChoirError> {
+        let blocked = |reason: String| ChoirError::UrlBlocked {
+            url: url.to_string(),
+            reason,
+        };
+
+        let parsed = Url::parse(url).map_err(|e| blocked(format!("invalid URL ({})", e)))?;
+        self.check_url(&parsed).map_err(blocked)?;
+
+        if let Some(Host::Domain(domain)) = parsed.host() {
+            let port = parsed.port_or_known_default().unwrap_or(0);
+            let addrs = tokio::net::lookup_host((domain, port))
+                .await
+                .map_err(|e| ChoirError::FetchFailed {
+                    url: url.to_string(),
+                    message: format!("could not resolve {} ({})", domain, e),
+                })?;
+            for addr in addrs {
+                self.check_ip(addr.ip()).map_err(blocked)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Everything that can be checked without DNS: scheme, port, domain lists and IP
+    // literals. Returns why the URL is blocked.
+    pub fn check_url(&self, url: &Url) -> Result<(), String> {
+        if !self.schemes.iter().any(|s| s == url.scheme()) {
+            return Err(format!("scheme {} is not allowed", url.scheme()));
+        }
+        let Some(host) = url.host() else {
+            return Err("URL has no host".to_string());
+        };
+        if let Some(port) = url.port_or_known_default() {
+            if !self.ports.is_empty() && !self.ports.contains(&port) {
+                return Err(format!("port {} is not allowed", port));
+            }
+        }
+
+        let name = match &host {
+            Host::Domain(domain) => domain.trim_end_matches('.').to_ascii_lowercase(),
+            Host::Ipv4(ip) => ip.to_string(),
+            Host::Ipv6(ip) => ip.to_string(),
+        };
+        if self.denied_domains.iter().any(|d| matches_domain(&name, d)) {
+            return Err(format!("{} is on the deny list", name));
+        }
+        if !self.allowed_domains.is_empty()
+            && !self.allowed_domains.iter().any(|d| matches_domain(&name, d))
+        {
+            return Err(format!("{} is not on the allow list", name));
+        }
+
+        match host {
+            Host::Ipv4(ip) => self.check_ip(IpAddr::V4(ip)),
+            Host::Ipv6(ip) => self.check_ip(IpAddr::V6(ip)),
+            Host::Domain(_) => Ok(()),
+        }
+    }
+
+    fn check_ip(&self, ip: IpAddr) -> Result<(), String> {
+        if !self.allow_private && is_private(ip) {
+            return Err(format!("{} is a private address", ip));
+        }
+        Ok(())
+    }
+
+    // Follows redirects as long as every hop passes `check_url`, up to `max_redirects`.
+    pub fn redirect_policy(self: &Arc<Self>) -> redirect::Policy {
+        let policy = self.clone();
+        redirect::Policy::custom(move |attempt| {
+            if attempt.previous().len() > policy.max_redirects {
+                let max = policy.max_redirects;
+                return attempt.error(format!("more than {} redirects", max));
+            }
+            match policy.check_url(attempt.url()) {
+                Ok(()) => attempt.follow(),
+                Err(reason) => {
+                    let url = attempt.url().to_string();
+                    attempt.error(ChoirError::UrlBlocked { url, reason })
+                }
+            }
+        })
+    }
+}
+
+// Resolves hosts for the native fetcher, refusing to hand out private addresses. Checking
+// at connect time is what stops a host that resolves somewhere else on the second lookup.
+pub struct PolicyResolver(pub Arc<UrlPolicy>);
+
+impl Resolve for PolicyResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let policy = self.0.clone();
+        Box::pin(async move {
+            let host = name.as_str().to_string();
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0)).await?.collect();
+            for addr in &addrs {
+                if let Err(reason) = policy.check_ip(addr.ip()) {
+                    warn!("Refusing to connect to {}: {}", host, reason);
+                    return Err(ChoirError::UrlBlocked { url: host, reason }.into());
+                }
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+// The policy's verdict, when that's what made a request fail.
+pub fn blocked_cause(error: &reqwest::Error) -> Option<ChoirError> {
+    let mut source = error.source();
+    while let Some(cause) = source {
+        if let Some(ChoirError::UrlBlocked { url, reason }) = cause.downcast_ref::<ChoirError>() {
+            return Some(ChoirError::UrlBlocked {
+                url: url.clone(),
+                reason: reason.clone(),
+            });
+        }
+        source = cause.source();
+    }
+    None
+}
+
+fn matches_domain(host: &str, domain: &str) -> bool {
+    host == domain || host.strip_suffix(domain).is_some_and(|rest| rest.ends_with('.'))
+}
+
+// Loopback, private, link-local, shared, reserved and the like: anything that isn't the
+// public internet.
+fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_private_v4(ip),
+        IpAddr::V6(ip) => is_private_v6(ip),
+    }
+}
+
+fn is_private_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        || a == 0
+        // Carrier-grade NAT, 100.64.0.0/10.
+        || (a == 100 && (b & 0xc0) == 64)
+        // IETF protocol assignments, 192.0.0.0/24.
+        || (a == 192 && b == 0 && c == 0)
+        // Benchmarking, 198.18.0.0/15.
+        || (a == 198 && (b & 0xfe) == 18)
+        // Reserved, 240.0.0.0/4.
+        || a >= 240
+}
+
+fn is_private_v6(ip: Ipv6Addr) -> bool {
+    if let Some(v4) = ip.to_ipv4_mapped() {
+        return is_private_v4(v4);
+    }
+    let segments = ip.segments();
+    // NAT64, 64:ff9b::/96, reaches whatever IPv4 address it embeds.
+    if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+        let [.., a, b, c, d] = ip.octets();
+        return is_private_v4(Ipv4Addr::new(a, b, c, d));
+    }
+    // 6to4, 2002::/16, with the IPv4 address right after the prefix.
+    if segments[0] == 0x2002 {
+        let [_, _, a, b, c, d, ..] = ip.octets();
+        return is_private_v4(Ipv4Addr::new(a, b, c, d));
+    }
+    // Teredo, 2001::/32, with the client's IPv4 address inverted in the last 32 bits.
+    if segments[0] == 0x2001 && segments[1] == 0 {
+        let [.., a, b, c, d] = ip.octets();
+        return is_private_v4(Ipv4Addr::new(!a, !b, !c, !d));
+    }
+    ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        || ip.is_unique_local()
+        || ip.is_unicast_link_local()
+        // Documentation, 2001:db8::/32.
+        || (segments[0] == 0x2001 && segments[1] == 0xdb8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    fn policy() -> UrlPolicy {
+        UrlPolicy {
+            allow_private: false,
+            schemes: vec!["http".to_string(), "https".to_string()],
+            ports: vec![80, 443],
+            allowed_domains: Vec::new(),
+            denied_domains: vec!["internal.example.com".to_string()],
+            max_redirects: 5,
+        }
+    }
+
+    // Answers every request on a local port with a redirect to `location`.
+    fn redirect_server(location: String) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut buf = [0; 4096];
+                let _ = stream.read(&mut buf);
+                let location = location.replace("{port}", &port.to_string());
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    location
+                );
+            }
+        });
+        port
+    }
+
+    #[test]
+    fn private_addresses() {
+        let cases = [
+            ("127.0.0.1", true),
+            ("127.1.2.3", true),
+            ("10.0.0.1", true),
+            ("172.16.5.4", true),
+            ("172.31.255.255", true),
+            ("192.168.1.1", true),
+            ("169.254.169.254", true),
+            ("100.64.0.1", true),
+            ("0.0.0.0", true),
+            ("255.255.255.255", true),
+            ("8.8.8.8", false),
+            ("172.32.0.1", false),
+            ("93.184.216.34", false),
+            ("::1", true),
+            ("::", true),
+            ("fe80::1", true),
+            ("fd00::1", true),
+            ("::ffff:127.0.0.1", true),
+            ("::ffff:10.0.0.1", true),
+            ("::ffff:169.254.169.254", true),
+            ("::ffff:8.8.8.8", false),
+            ("64:ff9b::a00:1", true),
+            ("64:ff9b::808:808", false),
+            // 6to4 of 127.0.0.1, 192.168.1.1 and 8.8.8.8.
+            ("2002:7f00:1::1", true),
+            ("2002:c0a8:101::", true),
+            ("2002:808:808::1", false),
+            // Teredo with clients 127.0.0.1, 10.0.0.1 and 8.8.8.8.
+            ("2001:0:4136:e378:8000:63bf:80ff:fffe", true),
+            ("2001:0:4136:e378:8000:63bf:f5ff:fffe", true),
+            ("2001:0:4136:e378:8000:63bf:f7f7:f7f7", false),
+            ("2001:db8::1", true),
+            ("2606:4700:4700::1111", false),
+        ];
+        for (ip, private) in cases {
+            assert_eq!(is_private(ip.parse().unwrap()), private, "{}", ip);
+        }
+    }
+
+    #[test]
+    fn urls() {
+        let cases = [
+            ("https://example.com/page", true),
+            ("http://example.com:80/", true),
+            ("http://93.184.216.34/", true),
+            ("ftp://example.com/file", false),
+            ("file:///etc/passwd", false),
+            ("http://example.com:8080/", false),
+            ("http://127.0.0.1/", false),
+            ("http://10.0.0.1/admin", false),
+            ("http://192.168.0.1/", false),
+            ("http://169.254.169.254/latest/meta-data/", false),
+            ("http://[::1]/", false),
+            ("http://[fe80::1]/", false),
+            ("http://[::ffff:127.0.0.1]/", false),
+            ("http://[2002:7f00:1::1]/", false),
+            ("http://internal.example.com/", false),
+            ("http://api.internal.example.com/", false),
+            ("http://notinternal.example.com/", true),
+        ];
+        let policy = policy();
+        for (url, allowed) in cases {
+            let result = policy.check_url(&Url::parse(url).unwrap());
+            assert_eq!(result.is_ok(), allowed, "{}: {:?}", url, result);
+        }
+    }
+
+    #[test]
+    fn allow_list_and_private_opt_in() {
+        let policy = UrlPolicy {
+            allow_private: true,
+            ports: Vec::new(),
+            allowed_domains: vec!["example.com".to_string()],
+            ..policy()
+        };
+        assert!(policy.check_url(&Url::parse("http://docs.example.com:8080/").unwrap()).is_ok());
+        assert!(policy.check_url(&Url::parse("http://example.org/").unwrap()).is_err());
+        assert!(policy.check_ip("127.0.0.1".parse().unwrap()).is_ok());
+    }
+
+    #[actix_web::test]
+    async fn names_resolving_to_private_addresses() {
+        let policy = policy();
+        for url in ["http://localhost/", "https://localhost/admin"] {
+            match policy.check(url).await {
+                Err(ChoirError::UrlBlocked { reason, .. }) => {
+                    assert!(reason.contains("private address"), "{}: {}", url, reason)
+                }
+                other => panic!("{} was not blocked: {:?}", url, other),
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn redirects_to_private_hosts() {
+        let policy = Arc::new(UrlPolicy {
+            ports: Vec::new(),
+            ..policy()
+        });
+        let client = reqwest::Client::builder()
+            .redirect(policy.redirect_policy())
+            .dns_resolver(Arc::new(PolicyResolver(policy.clone())))
+            .build()
+            .unwrap();
+
+        // The first hop is dialled directly, the redirect is what the policy has to catch.
+        for location in ["http://127.0.0.1:{port}/secret", "http://[::1]:{port}/", "http://localhost:{port}/"] {
+            let port = redirect_server(location.to_string());
+            let error = client
+                .get(format!("http://127.0.0.1:{}/", port))
+                .send()
+                .await
+                .expect_err(location);
+            assert!(
+                matches!(blocked_cause(&error), Some(ChoirError::UrlBlocked { .. })),
+                "{}: {:?}",
+                location,
+                error
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn too_many_redirects() {
+        let policy = Arc::new(UrlPolicy {
+            allow_private: true,
+            ports: Vec::new(),
+            max_redirects: 2,
+            ..policy()
+        });
+        let client = reqwest::Client::builder()
+            .redirect(policy.redirect_policy())
+            .build()
+            .unwrap();
+        let port = redirect_server("http://127.0.0.1:{port}/again".to_string());
+        let error = client
+            .get(format!("http://127.0.0.1:{}/", port))
+            .send()
+            .await
+            .unwrap_err();
+        assert!(error.is_redirect(), "{:?}", error);
+        assert!(blocked_cause(&error).is_none());
+    }
+}
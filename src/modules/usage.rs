@@ -0,0 +1,101 @@
+use crate::modules::provider::TokenUsage;
+use crate::types::trun::{RunUsage, UsageTotals};
+use crate::Error;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+// USD per million tokens.
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct ModelPrice {
+    pub input: f64,
+    pub output: f64,
+}
+
+// Prices used to turn token counts into cost. Dated model names like
+// `gpt-4o-2024-08-06` are priced by the longest matching prefix.
+#[derive(Debug, Clone)]
+pub struct PriceTable {
+    prices: HashMap<String, ModelPrice>,
+}
+
+impl PriceTable {
+    // Read prices from a JSON file shaped like `{ "gpt-4o": { "input": 2.5, "output": 10.0 } }`.
+    // Entries override the built-in prices, anything not listed keeps its default.
+    pub fn load(path: &str) -> Result<Self, Error> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| Error::from(format!("Failed to read price table {}: {}", path, e)))?;
+        let prices: HashMap<String, ModelPrice> = serde_json::from_str(&contents)
+            .map_err(|e| Error::from(format!("Invalid price table {}: {}", path, e)))?;
+
+        let mut table = Self::default();
+        table.prices.extend(prices);
+        Ok(table)
+    }
+
+    pub fn price(&self, model: &str) -> Option<ModelPrice> {
+        self.prices
+            .iter()
+            .filter(|(name, _)| model.starts_with(name.as_str()))
+            .max_by_key(|(name, _)| name.len())
+            .map(|(_, price)| *price)
+    }
+
+    // Models without a price (local models, mostly) cost nothing.
+    pub fn cost(&self, model: &str, usage: &TokenUsage) -> f64 {
+        self.estimate(model, usage.prompt_tokens as u64, usage.completion_tokens as u64)
+    }
+
+    pub fn estimate(&self, model: &str, prompt_tokens: u64, completion_tokens: u64) -> f64 {
+        self.price(model).map_or(0.0, |price| {
+            (prompt_tokens as f64 * price.input + completion_tokens as f64 * price.output)
+                / 1_000_000.0
+        })
+    }
+}
+
+impl Default for PriceTable {
+    fn default() -> Self {
+        let prices = [
+            ("gpt-4o", 2.50, 10.00),
+            ("gpt-4o-mini", 0.15, 0.60),
+            ("gpt-4.1", 2.00, 8.00),
+            ("gpt-4.1-mini", 0.40, 1.60),
+            ("gpt-4.1-nano", 0.10, 0.40),
+        ];
+
+        Self {
+            prices: prices
+                .into_iter()
+                .map(|(model, input, output)| (model.to_string(), ModelPrice { input, output }))
+                .collect(),
+        }
+    }
+}
+
+// Collects the usage of every completion made during a run.
+#[derive(Default)]
+pub struct UsageMeter {
+    usage: Mutex<RunUsage>,
+}
+
+impl UsageMeter {
+    pub fn record(&self, stage: &str, usage: &TokenUsage, estimated_prompt_tokens: u64, cost_usd: f64) {
+        let call = UsageTotals {
+            calls: 1,
+            estimated_prompt_tokens,
+            prompt_tokens: usage.prompt_tokens as u64,
+            completion_tokens: usage.completion_tokens as u64,
+            total_tokens: usage.total_tokens as u64,
+            cost_usd,
+        };
+
+        let mut run = self.usage.lock().unwrap();
+        run.stages.entry(stage.to_string()).or_default().add(&call);
+        run.total.add(&call);
+    }
+
+    pub fn snapshot(&self) -> RunUsage {
+        self.usage.lock().unwrap().clone()
+    }
+}
@@ -1,4 +1,6 @@
+use crate::types::terror::{ChoirError, ErrorCode};
 use serde::Serialize;
+use serde_json::Value;
 
 #[derive(Serialize)]
 pub struct QueryResponse<'a, T: Serialize + 'a> {
@@ -6,6 +8,41 @@ pub struct QueryResponse<'a, T: Serialize + 'a> {
     pub data: Option<&'a T>,
     pub error: Option<String>,
     pub message: Option<String>,
+    /// Stable identifier of what went wrong.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<ErrorCode>,
+    /// Whether sending the same request again later might succeed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retryable: Option<bool>,
+    /// Extra information about how the data was produced, e.g. token usage.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub meta: Option<Value>,
+}
+
+impl<'a, T: Serialize + 'a> QueryResponse<'a, T> {
+    pub fn with_meta(mut self, meta: &impl Serialize) -> Self {
+        self.meta = serde_json::to_value(meta).ok();
+        self
+    }
+
+    pub fn with_code(mut self, code: ErrorCode) -> Self {
+        self.code = Some(code);
+        self.retryable = Some(code.is_retryable());
+        self
+    }
+}
+
+// Failure response for an error, with its code and public message.
+pub fn make_error_response(error: &ChoirError) -> QueryResponse<'static, ()> {
+    QueryResponse {
+        success: false,
+        data: None,
+        error: Some(error.public_message()),
+        message: None,
+        code: Some(error.code()),
+        retryable: Some(error.is_retryable()),
+        meta: None,
+    }
 }
 
 pub fn make_query_response<'a, T: Serialize + 'a>(
@@ -19,5 +56,8 @@ pub fn make_query_response<'a, T: Serialize + 'a>(
         data,
         error: error.map(String::from),
         message: message.map(String::from),
+        code: None,
+        retryable: None,
+        meta: None,
     }
 }
@@ -0,0 +1,161 @@
+use crate::modules::history::RunHistory;
+use crate::modules::keys::KeyStore;
+use crate::require_api_key;
+use crate::response;
+use crate::types::terror::{ChoirError, ErrorCode};
+use crate::types::tkey::{CreateKeyRequest, Scope, UpdateKeyRequest};
+use crate::types::trun::UsageQuery;
+use crate::utils::webutils::WebUtils;
+use actix_web::{delete, get, patch, post, web, HttpResponse};
+use log::error;
+
+// Token usage and cost per API key, for charging back.
+#[get("/usage")]
+async fn usage(
+    req: actix_web::HttpRequest,
+    query: web::Query<UsageQuery>,
+    history: web::Data<RunHistory>,
+) -> HttpResponse {
+    require_api_key!(&req, Scope::Admin);
+
+    match history.usage_by_key(&query) {
+        Ok(usage) => HttpResponse::Ok().json(response::make_query_response(
+            true,
+            Some(&usage),
+            None,
+            None,
+        )),
+        Err(e) => {
+            error!("Failed to aggregate usage: {}", e);
+            WebUtils::error_response(&ChoirError::Internal(e.to_string()))
+        }
+    }
+}
+
+#[post("/keys")]
+async fn create_key(
+    req: actix_web::HttpRequest,
+    body: web::Json<CreateKeyRequest>,
+    keys: web::Data<KeyStore>,
+) -> HttpResponse {
+    require_api_key!(&req, Scope::Admin);
+
+    if let Err(e) = body.validate() {
+        return HttpResponse::BadRequest().json(
+            response::make_query_response::<()>(
+                false,
+                None,
+                Some(&e),
+                None,
+            )
+            .with_code(ErrorCode::BadRequest),
+        );
+    }
+
+    match keys.create(&body) {
+        Ok(created) => HttpResponse::Created().json(response::make_query_response(
+            true,
+            Some(&created),
+            None,
+            Some("Key created. The token is only shown once."),
+        )),
+        Err(e) => {
+            error!("Failed to create key: {}", e);
+            WebUtils::error_response(&ChoirError::Internal(e.to_string()))
+        }
+    }
+}
+
+#[get("/keys")]
+async fn list_keys(req: actix_web::HttpRequest, keys: web::Data<KeyStore>) -> HttpResponse {
+    require_api_key!(&req, Scope::Admin);
+
+    match keys.list() {
+        Ok(keys) => HttpResponse::Ok().json(response::make_query_response(
+            true,
+            Some(&keys),
+            None,
+            None,
+        )),
+        Err(e) => {
+            error!("Failed to list keys: {}", e);
+            WebUtils::error_response(&ChoirError::Internal(e.to_string()))
+        }
+    }
+}
+
+// Enable or disable a key without losing it, or change its limits.
+#[patch("/keys/{id}")]
+async fn update_key(
+    req: actix_web::HttpRequest,
+    path: web::Path<String>,
+    body: web::Json<UpdateKeyRequest>,
+    keys: web::Data<KeyStore>,
+) -> HttpResponse {
+    require_api_key!(&req, Scope::Admin);
+
+    if let Err(e) = body.validate() {
+        return HttpResponse::BadRequest().json(
+            response::make_query_response::<()>(
+                false,
+                None,
+                Some(&e),
+                None,
+            )
+            .with_code(ErrorCode::BadRequest),
+        );
+    }
+
+    match keys.update(&path, &body) {
+        Ok(Some(key)) => HttpResponse::Ok().json(response::make_query_response(
+            true,
+            Some(&key),
+            None,
+            None,
+        )),
+        Ok(None) => HttpResponse::NotFound().json(
+            response::make_query_response::<()>(
+                false,
+                None,
+                Some("Key not found."),
+                None,
+            )
+            .with_code(ErrorCode::NotFound),
+        ),
+        Err(e) => {
+            error!("Failed to update key: {}", e);
+            WebUtils::error_response(&ChoirError::Internal(e.to_string()))
+        }
+    }
+}
+
+#[delete("/keys/{id}")]
+async fn revoke_key(
+    req: actix_web::HttpRequest,
+    path: web::Path<String>,
+    keys: web::Data<KeyStore>,
+) -> HttpResponse {
+    require_api_key!(&req, Scope::Admin);
+
+    match keys.revoke(&path) {
+        Ok(true) => HttpResponse::Ok().json(response::make_query_response::<()>(
+            true,
+            None,
+            None,
+            Some("Key revoked."),
+        )),
+        Ok(false) => HttpResponse::NotFound().json(
+            response::make_query_response::<()>(
+                false,
+                None,
+                Some("Key not found."),
+                None,
+            )
+            .with_code(ErrorCode::NotFound),
+        ),
+        Err(e) => {
+            error!("Failed to revoke key: {}", e);
+            WebUtils::error_response(&ChoirError::Internal(e.to_string()))
+        }
+    }
+}
@@ -1,36 +1,124 @@
-use crate::modules::choir::ChoirService;
+use crate::modules::choir::{ChoirEvents, ChoirService};
+use crate::modules::limits::KeyLimiter;
 use crate::require_api_key;
 use crate::response;
-use crate::types::tchoir;
+use crate::types::tchoir::{self, ChoirEvent};
+use crate::types::tkey::Scope;
+use crate::utils::webutils::WebUtils;
 use actix_web::{post, web, HttpResponse};
+use futures::StreamExt;
 use log::{error, info};
+use tracing::Instrument;
+use uuid::Uuid;
 
 #[post("")]
 async fn choir(
     req: actix_web::HttpRequest,
     body: web::Json<tchoir::ChoirRequest>,
     service: web::Data<ChoirService>,
+    limiter: web::Data<KeyLimiter>,
 ) -> HttpResponse {
-    require_api_key!(&req);
+    let key = require_api_key!(&req, Scope::ChoirRun);
 
-    match service.run_choir(&body).await {
+    let mut body = body.into_inner();
+    if let Err(e) = WebUtils::apply_timeout_header(&req, &mut body) {
+        return WebUtils::error_response(&e);
+    }
+    if let Err(e) = service.validate_request(&body) {
+        return WebUtils::error_response(&e);
+    }
+
+    let permit = match limiter.start_run(&key) {
+        Ok(permit) => permit,
+        Err(limited) => return WebUtils::too_many_requests(&limited),
+    };
+
+    let result = service.run_choir(&body, Some(key.id)).await;
+    drop(permit);
+
+    match result {
         Ok(r) => {
             info!("Assessment successful, returning response.");
-            HttpResponse::Ok().json(response::make_query_response(
-                true,
-                Some(&r),
-                None,
-                Some("Model returned a valid response!"),
-            ))
+            HttpResponse::Ok().json(
+                response::make_query_response(
+                    true,
+                    Some(&r.answer),
+                    None,
+                    Some("Model returned a valid response!"),
+                )
+                .with_meta(&r.meta),
+            )
         }
         Err(e) => {
             error!("Choir service failed: {}", e);
-            HttpResponse::InternalServerError().json(response::make_query_response::<()>(
-                false,
-                None,
-                Some("An internal error occurred."),
-                None,
-            ))
+            WebUtils::error_response(&e)
         }
     }
 }
+
+// Same pipeline as `choir`, but each stage is pushed to the client as a Server-Sent Event.
+#[post("/stream")]
+async fn choir_stream(
+    req: actix_web::HttpRequest,
+    body: web::Json<tchoir::ChoirRequest>,
+    service: web::Data<ChoirService>,
+    limiter: web::Data<KeyLimiter>,
+) -> HttpResponse {
+    let key = require_api_key!(&req, Scope::ChoirRun);
+
+    let mut request = body.into_inner();
+    if let Err(e) = WebUtils::apply_timeout_header(&req, &mut request) {
+        return WebUtils::error_response(&e);
+    }
+    if let Err(e) = service.validate_request(&request) {
+        return WebUtils::error_response(&e);
+    }
+
+    let permit = match limiter.start_run(&key) {
+        Ok(permit) => permit,
+        Err(limited) => return WebUtils::too_many_requests(&limited),
+    };
+
+    let (tx, rx) = futures::channel::mpsc::unbounded();
+    let events = ChoirEvents::new(tx);
+
+    actix_web::rt::spawn(async move {
+        let _permit = permit;
+        match service
+            .run_choir_with_events(Uuid::new_v4(), &request, Some(key.id), &events)
+            .await
+        {
+            Ok(result) => {
+                info!("Streamed choir run finished.");
+                events.emit(ChoirEvent::Done {
+                    result: result.answer,
+                    meta: result.meta,
+                });
+            }
+            Err(e) => {
+                error!("Streamed choir run failed: {}", e);
+                events.emit(ChoirEvent::Error {
+                    message: e.public_message(),
+                    code: e.code(),
+                    retryable: e.is_retryable(),
+                });
+            }
+        }
+    }
+    // Keep the run in the request's trace even though it outlives the handler.
+    .in_current_span());
+
+    let stream = rx.map(|event| {
+        let data = serde_json::to_string(&event).unwrap_or_default();
+        Ok::<_, actix_web::Error>(web::Bytes::from(format!(
+            "event: {}\ndata: {}\n\n",
+            event.name(),
+            data
+        )))
+    });
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(stream)
+}
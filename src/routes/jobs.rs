@@ -0,0 +1,110 @@
+use crate::modules::choir::ChoirService;
+use crate::modules::jobs::JobStore;
+use crate::modules::limits::KeyLimiter;
+use crate::require_api_key;
+use crate::response;
+use crate::types::tchoir;
+use crate::types::terror::ErrorCode;
+use crate::types::tjob::JobStatus;
+use crate::types::tkey::Scope;
+use crate::utils::webutils::WebUtils;
+use actix_web::{delete, get, post, web, HttpResponse};
+use uuid::Uuid;
+
+#[post("/jobs")]
+async fn create_job(
+    req: actix_web::HttpRequest,
+    body: web::Json<tchoir::ChoirRequest>,
+    service: web::Data<ChoirService>,
+    jobs: web::Data<JobStore>,
+    limiter: web::Data<KeyLimiter>,
+) -> HttpResponse {
+    let key = require_api_key!(&req, Scope::ChoirRun);
+
+    let mut body = body.into_inner();
+    if let Err(e) = WebUtils::apply_timeout_header(&req, &mut body) {
+        return WebUtils::error_response(&e);
+    }
+    if let Err(e) = service.validate_request(&body) {
+        return WebUtils::error_response(&e);
+    }
+
+    let permit = match limiter.start_run(&key) {
+        Ok(permit) => permit,
+        Err(limited) => return WebUtils::too_many_requests(&limited),
+    };
+
+    let job = jobs
+        .into_inner()
+        .start(service.into_inner(), body, Some(key.id), permit);
+
+    HttpResponse::Accepted().json(response::make_query_response(
+        true,
+        Some(&job),
+        None,
+        Some("Job queued."),
+    ))
+}
+
+#[get("/jobs/{id}")]
+async fn get_job(
+    req: actix_web::HttpRequest,
+    path: web::Path<Uuid>,
+    jobs: web::Data<JobStore>,
+) -> HttpResponse {
+    let key = require_api_key!(&req, Scope::ChoirRun);
+
+    // Someone else's job is reported as missing, not forbidden, so ids can't be probed.
+    match jobs.get(path.into_inner()) {
+        Some(job) if job.is_visible_to(&key) => HttpResponse::Ok().json(
+            response::make_query_response(true, Some(&job), None, None),
+        ),
+        _ => job_not_found(),
+    }
+}
+
+#[delete("/jobs/{id}")]
+async fn cancel_job(
+    req: actix_web::HttpRequest,
+    path: web::Path<Uuid>,
+    jobs: web::Data<JobStore>,
+) -> HttpResponse {
+    let key = require_api_key!(&req, Scope::ChoirRun);
+
+    let id = path.into_inner();
+    if !jobs.get(id).is_some_and(|job| job.is_visible_to(&key)) {
+        return job_not_found();
+    }
+    match jobs.cancel(id) {
+        Some(job) if job.status == JobStatus::Cancelled => {
+            HttpResponse::Ok().json(response::make_query_response(
+                true,
+                Some(&job),
+                None,
+                Some("Job cancelled."),
+            ))
+        }
+        Some(job) => HttpResponse::Conflict().json(
+            response::make_query_response(
+                false,
+                Some(&job),
+                Some("Job already finished."),
+                None,
+            )
+            .with_code(ErrorCode::Conflict),
+        ),
+        None => job_not_found(),
+    }
+}
+
+fn job_not_found() -> HttpResponse {
+    HttpResponse::NotFound().json(
+        response::make_query_response::<()>(
+            false,
+            None,
+            Some("Job not found."),
+            None,
+        )
+        .with_code(ErrorCode::NotFound),
+    )
+}
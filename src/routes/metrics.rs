@@ -0,0 +1,10 @@
+use crate::modules::metrics::METRICS;
+use actix_web::{get, HttpResponse};
+
+// Prometheus scrape target. Unauthenticated like the health checks, keep it off the public internet.
+#[get("/metrics")]
+async fn metrics() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(METRICS.render())
+}
@@ -1,9 +1,40 @@
+use crate::utils::webutils::WebUtils;
 use actix_web::web;
 
+pub mod admin;
 pub mod choir;
 pub mod health;
+pub mod jobs;
+pub mod metrics;
+pub mod runs;
 
 pub fn configure_routes(cfg: &mut web::ServiceConfig) {
-    cfg.service(web::scope("/health").service(health::health))
-        .service(web::scope("/choir").service(choir::choir));
+    // Malformed bodies, query strings and ids get the usual envelope and a `bad_request` code.
+    cfg.app_data(web::JsonConfig::default().error_handler(|e, _| WebUtils::bad_request(e)))
+        .app_data(web::QueryConfig::default().error_handler(|e, _| WebUtils::bad_request(e)))
+        .app_data(web::PathConfig::default().error_handler(|e, _| WebUtils::bad_request(e)))
+        .service(metrics::metrics)
+        .service(
+            web::scope("/health")
+                .service(health::live)
+                .service(health::ready),
+        )
+        .service(
+            web::scope("/admin")
+                .service(admin::usage)
+                .service(admin::create_key)
+                .service(admin::list_keys)
+                .service(admin::update_key)
+                .service(admin::revoke_key),
+        )
+        .service(
+            web::scope("/choir")
+                .service(choir::choir_stream)
+                .service(jobs::create_job)
+                .service(jobs::get_job)
+                .service(jobs::cancel_job)
+                .service(runs::list_runs)
+                .service(runs::get_run)
+                .service(choir::choir),
+        );
 }
@@ -0,0 +1,63 @@
+use crate::modules::history::RunHistory;
+use crate::require_api_key;
+use crate::response;
+use crate::types::terror::{ChoirError, ErrorCode};
+use crate::types::tkey::Scope;
+use crate::types::trun::RunListQuery;
+use crate::utils::webutils::WebUtils;
+use actix_web::{get, web, HttpResponse};
+use log::error;
+use uuid::Uuid;
+
+#[get("/runs")]
+async fn list_runs(
+    req: actix_web::HttpRequest,
+    query: web::Query<RunListQuery>,
+    history: web::Data<RunHistory>,
+) -> HttpResponse {
+    require_api_key!(&req, Scope::RunsRead);
+
+    match history.list(&query) {
+        Ok(runs) => HttpResponse::Ok().json(response::make_query_response(
+            true,
+            Some(&runs),
+            None,
+            None,
+        )),
+        Err(e) => {
+            error!("Failed to list runs: {}", e);
+            WebUtils::error_response(&ChoirError::Internal(e.to_string()))
+        }
+    }
+}
+
+#[get("/runs/{id}")]
+async fn get_run(
+    req: actix_web::HttpRequest,
+    path: web::Path<Uuid>,
+    history: web::Data<RunHistory>,
+) -> HttpResponse {
+    require_api_key!(&req, Scope::RunsRead);
+
+    match history.get(path.into_inner()) {
+        Ok(Some(run)) => HttpResponse::Ok().json(response::make_query_response(
+            true,
+            Some(&run),
+            None,
+            None,
+        )),
+        Ok(None) => HttpResponse::NotFound().json(
+            response::make_query_response::<()>(
+                false,
+                None,
+                Some("Run not found."),
+                None,
+            )
+            .with_code(ErrorCode::NotFound),
+        ),
+        Err(e) => {
+            error!("Failed to load run: {}", e);
+            WebUtils::error_response(&ChoirError::Internal(e.to_string()))
+        }
+    }
+}
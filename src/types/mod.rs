@@ -1 +1,7 @@
 pub mod tchoir;
+pub mod terror;
+pub mod tfetch;
+pub mod thealth;
+pub mod tjob;
+pub mod tkey;
+pub mod trun;
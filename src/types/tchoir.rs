@@ -1,15 +1,192 @@
+use crate::types::terror::ErrorCode;
+use crate::types::trun::RunMetadata;
 use schemars::{schema_for, JsonSchema};
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{json, Value};
+use std::time::Duration;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ChoirRequest {
     pub query: String,
     pub json_schema: Option<Value>,
+    /// Per stage model overrides.
+    #[serde(default)]
+    pub models: StageModels,
+    /// Per stage models to fall back to, in order, when a stage's model keeps failing.
+    #[serde(default)]
+    pub fallback_models: StageFallbacks,
+    /// Roster agents to run by name and/or inline agent definitions. Defaults to the whole roster.
+    pub agents: Option<Vec<AgentSelection>>,
+    pub temperature: Option<f32>,
+    pub max_tokens: Option<u32>,
+    /// Spending limit for the whole run. Can only tighten the server's own limit.
+    pub budget: Option<BudgetLimits>,
+    /// How long the whole run may take, in milliseconds. Can only tighten `RUN_TIMEOUT_SECS`.
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+    /// How many agents have to succeed, overriding the server's defaults.
+    #[serde(default)]
+    pub quorum: Option<QuorumPolicy>,
+    /// Fetch every URL again instead of using cached copies.
+    #[serde(default)]
+    pub no_cache: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct BudgetLimits {
+    /// Prompt plus completion tokens across every stage.
+    pub max_tokens: Option<u64>,
+    pub max_cost_usd: Option<f64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct QuorumPolicy {
+    /// Agents that have to answer for the run to go on. Capped at the number of sub-tasks.
+    pub min_successful: Option<usize>,
+    /// Times a failed agent is run again before it's given up on.
+    pub reruns: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct StageModels {
+    /// Condenses fetched pages that are too long for the other stages.
+    pub digest: Option<String>,
+    pub task_master: Option<String>,
+    pub agents: Option<String>,
+    pub assessment: Option<String>,
+    pub summary: Option<String>,
+}
+
+// An empty list turns fallback off for the stage, a missing one keeps the server's chain.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct StageFallbacks {
+    pub digest: Option<Vec<String>>,
+    pub task_master: Option<Vec<String>>,
+    pub agents: Option<Vec<String>>,
+    pub assessment: Option<Vec<String>>,
+    pub summary: Option<Vec<String>>,
+}
+
+impl StageFallbacks {
+    pub fn stage(&self, stage: &str) -> Option<&Vec<String>> {
+        match stage {
+            "digest" => self.digest.as_ref(),
+            "task_master" => self.task_master.as_ref(),
+            "agents" => self.agents.as_ref(),
+            "assessment" => self.assessment.as_ref(),
+            "summary" => self.summary.as_ref(),
+            _ => None,
+        }
+    }
+
+    pub fn models(&self) -> impl Iterator<Item = &String> {
+        [&self.digest, &self.task_master, &self.agents, &self.assessment, &self.summary]
+            .into_iter()
+            .flatten()
+            .flatten()
+    }
+
+    // Stages this one leaves unset take `defaults`.
+    pub fn or(&self, defaults: &StageFallbacks) -> StageFallbacks {
+        StageFallbacks {
+            digest: self.digest.clone().or_else(|| defaults.digest.clone()),
+            task_master: self.task_master.clone().or_else(|| defaults.task_master.clone()),
+            agents: self.agents.clone().or_else(|| defaults.agents.clone()),
+            assessment: self.assessment.clone().or_else(|| defaults.assessment.clone()),
+            summary: self.summary.clone().or_else(|| defaults.summary.clone()),
+        }
+    }
+}
+
+// Longest each stage may take. Every stage is held to the run's deadline as well.
+#[derive(Debug, Clone, Copy)]
+pub struct StageTimeouts {
+    pub enrichment: Duration,
+    pub digest: Duration,
+    pub task_master: Duration,
+    pub agents: Duration,
+    pub assessment: Duration,
+    pub summary: Duration,
+}
+
+impl StageTimeouts {
+    pub fn stage(&self, stage: &str) -> Duration {
+        match stage {
+            "enrichment" => self.enrichment,
+            "digest" => self.digest,
+            "task_master" => self.task_master,
+            "agents" => self.agents,
+            "assessment" => self.assessment,
+            _ => self.summary,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum AgentSelection {
+    Named(String),
+    Inline(AgentDefinition),
+}
+
+// One member of the agent roster.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AgentDefinition {
+    pub name: String,
+    /// Persona and focus, added to the agent's system prompt.
+    pub role: String,
+    /// Falls back to the server default model.
+    pub model: Option<String>,
+    pub temperature: Option<f32>,
+    /// Custom output schema. Defaults to the `ChoirAgentResponse` schema.
+    pub output_schema: Option<Value>,
+    /// AI functions the agent may call while working on its sub-task, by name.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tools: Vec<String>,
+}
+
+// The task master's breakdown of the query.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TaskPlan {
+    pub subtasks: Vec<SubTask>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SubTask {
+    pub title: String,
+    pub instructions: String,
+    /// Name of the roster agent best suited to the sub-task.
+    pub suggested_role: String,
+}
+
+// Built per run since the allowed roles depend on which agents the request selected.
+pub fn get_task_plan_schema(roles: &[&str], max_subtasks: usize) -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "subtasks": {
+                "type": "array",
+                "minItems": 1,
+                "maxItems": max_subtasks,
+                "items": {
+                    "type": "object",
+                    "properties": {
+                        "title": { "type": "string" },
+                        "instructions": { "type": "string" },
+                        "suggested_role": { "type": "string", "enum": roles }
+                    },
+                    "required": ["title", "instructions", "suggested_role"],
+                    "additionalProperties": false
+                }
+            }
+        },
+        "required": ["subtasks"],
+        "additionalProperties": false
+    })
 }
 
 // Agent response.
-#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
 pub struct ChoirAgentResponse {
     /// The agent's response in detail. Extremely detailed.
     pub detailed_response: String,
@@ -24,14 +201,58 @@ pub fn get_choir_agent_response_schema() -> Value {
     serde_json::to_value(schema_for!(ChoirAgentResponse)).unwrap()
 }
 
-impl ChoirAgentResponse {
-    pub fn empty() -> Self {
-        Self {
-            detailed_response: "Agent failed while generating. Discount them from processing."
-                .to_string(),
-            short_overview: "Agent failed while generating. Discount them from processing."
-                .to_string(),
-            thoughts: "Agent failed while generating. Discount them from processing.".to_string(),
+// Progress events emitted by `run_choir`, streamed to clients as SSE.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ChoirEvent {
+    UrlFetched {
+        url: String,
+        success: bool,
+        /// Not fetched at all, under the URL policy.
+        #[serde(default)]
+        blocked: bool,
+        /// Served from the page cache.
+        #[serde(default)]
+        cached: bool,
+        error: Option<String>,
+    },
+    Plan {
+        plan: TaskPlan,
+    },
+    AgentFinished {
+        agent: usize,
+        name: String,
+        task: String,
+        success: bool,
+        short_overview: Option<String>,
+    },
+    Assessment {
+        assessment: String,
+    },
+    SummaryDelta {
+        delta: String,
+    },
+    Done {
+        result: Value,
+        meta: RunMetadata,
+    },
+    Error {
+        message: String,
+        code: ErrorCode,
+        retryable: bool,
+    },
+}
+
+impl ChoirEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChoirEvent::UrlFetched { .. } => "url_fetched",
+            ChoirEvent::Plan { .. } => "plan",
+            ChoirEvent::AgentFinished { .. } => "agent_finished",
+            ChoirEvent::Assessment { .. } => "assessment",
+            ChoirEvent::SummaryDelta { .. } => "summary_delta",
+            ChoirEvent::Done { .. } => "done",
+            ChoirEvent::Error { .. } => "error",
         }
     }
 }
@@ -0,0 +1,176 @@
+use crate::Error;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::Duration;
+
+// Stable, machine-readable error codes. Clients branch on these, so never rename one.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    BadRequest,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    Conflict,
+    RateLimited,
+    BudgetExceeded,
+    ContextOverflow,
+    SchemaInvalid,
+    FetchFailed,
+    UrlBlocked,
+    ProviderError,
+    UpstreamRateLimited,
+    CircuitOpen,
+    QuorumNotMet,
+    Timeout,
+    InternalError,
+}
+
+impl ErrorCode {
+    // Whether the same request might succeed if sent again later.
+    pub fn is_retryable(self) -> bool {
+        matches!(
+            self,
+            ErrorCode::RateLimited
+                | ErrorCode::FetchFailed
+                | ErrorCode::ProviderError
+                | ErrorCode::UpstreamRateLimited
+                | ErrorCode::CircuitOpen
+                | ErrorCode::QuorumNotMet
+                | ErrorCode::Timeout
+        )
+    }
+}
+
+// Why a run failed. Travels through the pipeline boxed as a `crate::Error` and is
+// recovered at the route with `ChoirError::from`, anything else becoming `Internal`.
+#[derive(Debug)]
+pub enum ChoirError {
+    /// The caller asked for something we won't run.
+    BadRequest(String),
+    /// The LLM provider failed or returned something unusable.
+    Provider {
+        message: String,
+        retryable: bool,
+        /// How long the provider asked us to wait, from its `Retry-After` header.
+        retry_after: Option<Duration>,
+    },
+    /// The LLM provider is rate limiting us.
+    UpstreamRateLimited {
+        message: String,
+        retry_after: Option<Duration>,
+    },
+    /// An upstream kept failing, so we're not calling it until `retry_after` has passed.
+    CircuitOpen {
+        upstream: String,
+        retry_after: Duration,
+    },
+    /// A URL in the query couldn't be fetched.
+    FetchFailed { url: String, message: String },
+    /// A URL in the query, or a redirect from it, is off limits under the URL policy.
+    UrlBlocked { url: String, reason: String },
+    /// Too few agents answered for the run to go on.
+    QuorumNotMet(String),
+    /// The final answer never matched the caller's `json_schema`.
+    SchemaInvalid(String),
+    /// The run's token or cost budget ran out before it could answer.
+    BudgetExceeded(String),
+    /// A prompt doesn't fit the model's context window, even cut down.
+    ContextOverflow(String),
+    Timeout(String),
+    Internal(String),
+}
+
+impl ChoirError {
+    pub fn provider(message: impl Into<String>, retryable: bool) -> Self {
+        ChoirError::Provider {
+            message: message.into(),
+            retryable,
+            retry_after: None,
+        }
+    }
+
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            ChoirError::BadRequest(_) => ErrorCode::BadRequest,
+            ChoirError::Provider { .. } => ErrorCode::ProviderError,
+            ChoirError::UpstreamRateLimited { .. } => ErrorCode::UpstreamRateLimited,
+            ChoirError::CircuitOpen { .. } => ErrorCode::CircuitOpen,
+            ChoirError::FetchFailed { .. } => ErrorCode::FetchFailed,
+            ChoirError::UrlBlocked { .. } => ErrorCode::UrlBlocked,
+            ChoirError::QuorumNotMet(_) => ErrorCode::QuorumNotMet,
+            ChoirError::SchemaInvalid(_) => ErrorCode::SchemaInvalid,
+            ChoirError::BudgetExceeded(_) => ErrorCode::BudgetExceeded,
+            ChoirError::ContextOverflow(_) => ErrorCode::ContextOverflow,
+            ChoirError::Timeout(_) => ErrorCode::Timeout,
+            ChoirError::Internal(_) => ErrorCode::InternalError,
+        }
+    }
+
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            ChoirError::Provider { retryable, .. } => *retryable,
+            _ => self.code().is_retryable(),
+        }
+    }
+
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            ChoirError::Provider { retry_after, .. }
+            | ChoirError::UpstreamRateLimited { retry_after, .. } => *retry_after,
+            ChoirError::CircuitOpen { retry_after, .. } => Some(*retry_after),
+            _ => None,
+        }
+    }
+
+    // What the caller gets to see. Provider and internal details only go to the logs,
+    // as they can echo credentials or endpoints back.
+    pub fn public_message(&self) -> String {
+        match self {
+            ChoirError::Provider { .. } => "The LLM provider failed to respond.".to_string(),
+            ChoirError::UpstreamRateLimited { .. } => {
+                "The LLM provider is rate limiting requests. Try again later.".to_string()
+            }
+            ChoirError::Internal(_) => "An internal error occurred.".to_string(),
+            _ => self.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ChoirError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChoirError::BadRequest(message)
+            | ChoirError::QuorumNotMet(message)
+            | ChoirError::SchemaInvalid(message)
+            | ChoirError::BudgetExceeded(message)
+            | ChoirError::ContextOverflow(message)
+            | ChoirError::Timeout(message)
+            | ChoirError::Internal(message) => write!(f, "{}", message),
+            ChoirError::Provider { message, .. } => write!(f, "LLM provider error: {}", message),
+            ChoirError::UpstreamRateLimited { message, .. } => {
+                write!(f, "LLM provider rate limit: {}", message)
+            }
+            ChoirError::CircuitOpen { upstream, .. } => {
+                write!(f, "The {} upstream is failing, so calls to it are paused.", upstream)
+            }
+            ChoirError::FetchFailed { url, message } => {
+                write!(f, "Failed to fetch {}: {}", url, message)
+            }
+            ChoirError::UrlBlocked { url, reason } => {
+                write!(f, "Not fetching {}: {}", url, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ChoirError {}
+
+impl From<Error> for ChoirError {
+    fn from(error: Error) -> Self {
+        match error.downcast::<ChoirError>() {
+            Ok(error) => *error,
+            Err(error) => ChoirError::Internal(error.to_string()),
+        }
+    }
+}
@@ -0,0 +1,49 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+// A fetched page, converted to Markdown for the prompts.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FetchedPage {
+    /// Where the page ended up, after redirects.
+    pub url: String,
+    pub markdown: String,
+    pub title: Option<String>,
+    pub metadata: PageMetadata,
+    /// Set when the page came out of the cache.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cached: Option<CacheHit>,
+    /// For revalidating a cached copy later. Never sent to the model.
+    #[serde(skip)]
+    pub validators: Validators,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PageMetadata {
+    pub description: Option<String>,
+    pub author: Option<String>,
+    pub site_name: Option<String>,
+    pub published: Option<String>,
+    pub language: Option<String>,
+    pub canonical_url: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct CacheHit {
+    /// When the cached copy was fetched, or last confirmed unchanged.
+    pub fetched_at: DateTime<Utc>,
+    /// The copy was stale and the site confirmed it hadn't changed.
+    pub revalidated: bool,
+}
+
+// What the site gave us to ask "has this changed?" with.
+#[derive(Debug, Clone, Default)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    pub fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+}
@@ -0,0 +1,44 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ComponentStatus {
+    Up,
+    Down,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ComponentHealth {
+    pub name: String,
+    pub status: ComponentStatus,
+    pub latency_ms: u64,
+    /// The breaker in front of this upstream, for those that have one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub circuit: Option<CircuitHealth>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct CircuitHealth {
+    pub state: CircuitState,
+    pub consecutive_failures: u32,
+    /// While open, how long until a probe call is let through.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_after_secs: Option<u64>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct HealthReport {
+    /// Up only when every component is.
+    pub status: ComponentStatus,
+    pub components: Vec<ComponentHealth>,
+    pub checked_at: DateTime<Utc>,
+}
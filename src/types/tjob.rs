@@ -0,0 +1,146 @@
+use crate::types::tchoir::{ChoirEvent, ChoirRequest, TaskPlan};
+use crate::types::terror::ErrorCode;
+use crate::types::tkey::{ApiKey, Scope};
+use crate::types::trun::RunMetadata;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use uuid::Uuid;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+impl JobStatus {
+    pub fn is_finished(self) -> bool {
+        matches!(
+            self,
+            JobStatus::Succeeded | JobStatus::Failed | JobStatus::Cancelled
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FetchedUrl {
+    pub url: String,
+    pub success: bool,
+    #[serde(default)]
+    pub blocked: bool,
+    #[serde(default)]
+    pub cached: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AgentProgress {
+    pub agent: usize,
+    pub name: String,
+    pub task: String,
+    pub success: bool,
+    pub short_overview: Option<String>,
+}
+
+// Partial results, filled in as the pipeline reports progress.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct JobStages {
+    /// Name of the last stage that reported progress.
+    pub current: Option<String>,
+    pub urls: Vec<FetchedUrl>,
+    pub plan: Option<TaskPlan>,
+    pub agents: Vec<AgentProgress>,
+    pub assessment: Option<String>,
+    /// Final summary so far, grows while it is being generated.
+    pub summary: String,
+}
+
+impl JobStages {
+    pub fn apply(&mut self, event: &ChoirEvent) {
+        self.current = Some(event.name().to_string());
+
+        match event {
+            ChoirEvent::UrlFetched {
+                url,
+                success,
+                blocked,
+                cached,
+                error,
+            } => self.urls.push(FetchedUrl {
+                url: url.clone(),
+                success: *success,
+                blocked: *blocked,
+                cached: *cached,
+                error: error.clone(),
+            }),
+            ChoirEvent::Plan { plan } => self.plan = Some(plan.clone()),
+            ChoirEvent::AgentFinished {
+                agent,
+                name,
+                task,
+                success,
+                short_overview,
+            } => self.agents.push(AgentProgress {
+                agent: *agent,
+                name: name.clone(),
+                task: task.clone(),
+                success: *success,
+                short_overview: short_overview.clone(),
+            }),
+            ChoirEvent::Assessment { assessment } => self.assessment = Some(assessment.clone()),
+            ChoirEvent::SummaryDelta { delta } => self.summary.push_str(delta),
+            ChoirEvent::Done { .. } | ChoirEvent::Error { .. } => {}
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChoirJob {
+    pub id: Uuid,
+    pub status: JobStatus,
+    /// The key that queued the job. Only it, or an admin key, gets to see or cancel the job.
+    #[serde(default)]
+    pub api_key_id: Option<String>,
+    pub request: ChoirRequest,
+    pub stages: JobStages,
+    /// The final answer, a string or the caller's `json_schema` shaped value.
+    pub result: Option<Value>,
+    /// Run id and token usage, once the job has succeeded.
+    pub meta: Option<RunMetadata>,
+    pub error: Option<String>,
+    /// Set along with `error`, telling retryable failures from permanent ones.
+    #[serde(default)]
+    pub error_code: Option<ErrorCode>,
+    #[serde(default)]
+    pub retryable: Option<bool>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl ChoirJob {
+    pub fn new(request: ChoirRequest, api_key_id: Option<String>) -> Self {
+        let now = Utc::now();
+        Self {
+            id: Uuid::new_v4(),
+            status: JobStatus::Queued,
+            api_key_id,
+            request,
+            stages: JobStages::default(),
+            result: None,
+            meta: None,
+            error: None,
+            error_code: None,
+            retryable: None,
+            created_at: now,
+            updated_at: now,
+        }
+    }
+
+    pub fn is_visible_to(&self, key: &ApiKey) -> bool {
+        key.scopes.contains(&Scope::Admin) || self.api_key_id.as_deref() == Some(key.id.as_str())
+    }
+}
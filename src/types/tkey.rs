@@ -0,0 +1,116 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    #[serde(rename = "choir:run")]
+    ChoirRun,
+    #[serde(rename = "runs:read")]
+    RunsRead,
+    /// Implies every other scope.
+    #[serde(rename = "admin")]
+    Admin,
+}
+
+// A stored API key. The secret itself is never kept, only its hash.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub enabled: bool,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Overrides of the server's default limits.
+    #[serde(default)]
+    pub limits: KeyLimits,
+}
+
+// Unset limits fall back to the server defaults, which may themselves be unlimited.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct KeyLimits {
+    pub requests_per_minute: Option<u32>,
+    pub max_concurrent_runs: Option<u32>,
+    pub daily_runs: Option<u32>,
+    pub daily_tokens: Option<u64>,
+}
+
+impl KeyLimits {
+    // A limit of 0 would turn every run away, disabling the key does that.
+    pub fn validate(&self) -> Result<(), String> {
+        let limits = [
+            ("requests_per_minute", self.requests_per_minute.map(u64::from)),
+            ("max_concurrent_runs", self.max_concurrent_runs.map(u64::from)),
+            ("daily_runs", self.daily_runs.map(u64::from)),
+            ("daily_tokens", self.daily_tokens),
+        ];
+        match limits.iter().find(|(_, limit)| *limit == Some(0)) {
+            Some((name, _)) => Err(format!("limits.{} must be greater than 0", name)),
+            None => Ok(()),
+        }
+    }
+
+    // These limits, with anything unset taken from `defaults`.
+    pub fn or(self, defaults: KeyLimits) -> KeyLimits {
+        KeyLimits {
+            requests_per_minute: self.requests_per_minute.or(defaults.requests_per_minute),
+            max_concurrent_runs: self.max_concurrent_runs.or(defaults.max_concurrent_runs),
+            daily_runs: self.daily_runs.or(defaults.daily_runs),
+            daily_tokens: self.daily_tokens.or(defaults.daily_tokens),
+        }
+    }
+}
+
+impl ApiKey {
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope) || self.scopes.contains(&Scope::Admin)
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.enabled && self.expires_at.is_none_or(|expires| expires > Utc::now())
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CreateKeyRequest {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub expires_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub limits: KeyLimits,
+}
+
+impl CreateKeyRequest {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.name.trim().is_empty() {
+            return Err("name must not be empty".to_string());
+        }
+        if self.scopes.is_empty() {
+            return Err("At least one scope is required".to_string());
+        }
+        if self.expires_at.is_some_and(|expires| expires <= Utc::now()) {
+            return Err("expires_at must be in the future".to_string());
+        }
+        self.limits.validate()
+    }
+}
+
+// Only the fields present are changed.
+#[derive(Deserialize, Debug)]
+pub struct UpdateKeyRequest {
+    pub enabled: Option<bool>,
+    pub limits: Option<KeyLimits>,
+}
+
+impl UpdateKeyRequest {
+    pub fn validate(&self) -> Result<(), String> {
+        self.limits.as_ref().map_or(Ok(()), KeyLimits::validate)
+    }
+}
+
+// Returned once, when a key is created. The token can't be recovered later.
+#[derive(Serialize, Debug)]
+pub struct CreatedKey {
+    pub key: ApiKey,
+    pub token: String,
+}
@@ -0,0 +1,313 @@
+use crate::types::tchoir::{ChoirAgentResponse, ChoirRequest, TaskPlan};
+use crate::types::terror::ErrorCode;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use uuid::Uuid;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RunStatus {
+    Running,
+    Succeeded,
+    /// Ran out of time and answered with what it had.
+    Partial,
+    Failed,
+    Cancelled,
+}
+
+impl RunStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RunStatus::Running => "running",
+            RunStatus::Succeeded => "succeeded",
+            RunStatus::Partial => "partial",
+            RunStatus::Failed => "failed",
+            RunStatus::Cancelled => "cancelled",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AgentRun {
+    pub agent: usize,
+    pub name: String,
+    pub task: String,
+    pub model: String,
+    pub response: Option<ChoirAgentResponse>,
+    pub error: Option<String>,
+    /// Runs of this sub-task, reruns included. 0 for runs recorded before reruns existed.
+    #[serde(default)]
+    pub attempts: u32,
+}
+
+// An agent left out of the answer, and why.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FailedAgent {
+    pub agent: usize,
+    pub name: String,
+    pub task: String,
+    pub error: String,
+    pub attempts: u32,
+}
+
+// A URL in the query the URL policy wouldn't let us fetch.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BlockedUrl {
+    pub url: String,
+    pub reason: String,
+}
+
+// A URL in the query that was served from the page cache.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CachedUrl {
+    pub url: String,
+    /// When the cached copy was fetched, or last confirmed unchanged.
+    pub fetched_at: DateTime<Utc>,
+    /// The copy was stale and the site confirmed it hadn't changed.
+    pub revalidated: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StageTiming {
+    pub stage: String,
+    pub duration_ms: u64,
+}
+
+// Everything a choir run produced, kept so past answers can be revisited.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RunRecord {
+    pub id: Uuid,
+    pub status: RunStatus,
+    pub request: ChoirRequest,
+    pub enriched_query: Option<String>,
+    #[serde(default)]
+    pub blocked_urls: Vec<BlockedUrl>,
+    #[serde(default)]
+    pub cached_urls: Vec<CachedUrl>,
+    pub plan: Option<TaskPlan>,
+    pub agents: Vec<AgentRun>,
+    pub assessment: Option<String>,
+    pub answer: Option<Value>,
+    pub timings: Vec<StageTiming>,
+    #[serde(default)]
+    pub usage: RunUsage,
+    #[serde(default)]
+    pub degraded: Vec<Degradation>,
+    #[serde(default)]
+    pub retries: Vec<CallRetry>,
+    /// Fingerprint of the API key that started the run.
+    #[serde(default)]
+    pub api_key_id: Option<String>,
+    pub error: Option<String>,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub duration_ms: u64,
+}
+
+impl RunRecord {
+    pub fn new(id: Uuid, request: ChoirRequest) -> Self {
+        Self {
+            id,
+            status: RunStatus::Running,
+            request,
+            enriched_query: None,
+            blocked_urls: Vec::new(),
+            cached_urls: Vec::new(),
+            plan: None,
+            agents: Vec::new(),
+            assessment: None,
+            answer: None,
+            timings: Vec::new(),
+            usage: RunUsage::default(),
+            degraded: Vec::new(),
+            retries: Vec::new(),
+            api_key_id: None,
+            error: None,
+            started_at: Utc::now(),
+            finished_at: None,
+            duration_ms: 0,
+        }
+    }
+
+    pub fn add_timing(&mut self, stage: &str, duration: Duration) {
+        self.timings.push(StageTiming {
+            stage: stage.to_string(),
+            duration_ms: duration.as_millis() as u64,
+        });
+    }
+
+    pub fn failed_agents(&self) -> Vec<FailedAgent> {
+        self.agents
+            .iter()
+            .filter_map(|run| {
+                Some(FailedAgent {
+                    agent: run.agent,
+                    name: run.name.clone(),
+                    task: run.task.clone(),
+                    error: run.error.clone()?,
+                    attempts: run.attempts,
+                })
+            })
+            .collect()
+    }
+
+    pub fn is_partial(&self) -> bool {
+        self.degraded
+            .iter()
+            .any(|d| d.kind == DegradationKind::PartialAnswer)
+    }
+
+    pub fn degrade(&mut self, kind: DegradationKind, detail: impl Into<String>) {
+        self.degraded.push(Degradation {
+            kind,
+            detail: detail.into(),
+        });
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct UsageTotals {
+    /// Number of completions these totals cover.
+    pub calls: u32,
+    /// Prompt tokens as counted before sending, to compare with what the provider billed.
+    #[serde(default)]
+    pub estimated_prompt_tokens: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+    pub cost_usd: f64,
+}
+
+impl UsageTotals {
+    pub fn add(&mut self, other: &UsageTotals) {
+        self.calls += other.calls;
+        self.estimated_prompt_tokens += other.estimated_prompt_tokens;
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.total_tokens += other.total_tokens;
+        self.cost_usd += other.cost_usd;
+    }
+}
+
+// Token usage of a run, per stage and overall.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RunUsage {
+    pub stages: BTreeMap<String, UsageTotals>,
+    pub total: UsageTotals,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DegradationKind {
+    ContextTruncated,
+    /// Fetched pages were condensed to what's relevant to the query.
+    ContextDigested,
+    PlanningSkipped,
+    AgentsReduced,
+    AssessmentSkipped,
+    SummarySkipped,
+    StageTimedOut,
+    /// The run ran out of time and answered with what it had, see `RunMetadata::partial`.
+    PartialAnswer,
+}
+
+// Something the run left out or cut short to stay within its budget or time limits.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Degradation {
+    pub kind: DegradationKind,
+    pub detail: String,
+}
+
+// A failed LLM call and what was done about it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CallRetry {
+    pub stage: String,
+    /// The model whose call failed.
+    pub model: String,
+    pub code: ErrorCode,
+    pub error: String,
+    #[serde(flatten)]
+    pub action: RetryAction,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum RetryAction {
+    /// Called the same model again after waiting.
+    Retry { delay_ms: u64 },
+    /// Moved on to the next model in the stage's fallback chain.
+    Fallback { to: String },
+}
+
+// Returned alongside a run's answer.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RunMetadata {
+    pub run_id: Uuid,
+    pub usage: RunUsage,
+    pub degraded: Vec<Degradation>,
+    pub retries: Vec<CallRetry>,
+    /// Agents whose answers were left out of the assessment and summary.
+    #[serde(default)]
+    pub failed_agents: Vec<FailedAgent>,
+    /// The run ran out of time and the answer was pieced together from what had finished.
+    #[serde(default)]
+    pub partial: bool,
+    /// URLs in the query that were left out under the URL policy.
+    #[serde(default)]
+    pub blocked_urls: Vec<BlockedUrl>,
+    /// URLs in the query whose content came from the page cache.
+    #[serde(default)]
+    pub cached_urls: Vec<CachedUrl>,
+}
+
+// The list view of a run, without the bulky stage outputs.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RunSummary {
+    pub id: Uuid,
+    pub status: RunStatus,
+    pub query: String,
+    pub error: Option<String>,
+    pub started_at: DateTime<Utc>,
+    pub duration_ms: u64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RunListQuery {
+    pub page: Option<u32>,
+    pub per_page: Option<u32>,
+    pub status: Option<RunStatus>,
+    /// Only runs started at or after this time.
+    pub from: Option<DateTime<Utc>>,
+    /// Only runs started before this time.
+    pub to: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct RunList {
+    pub runs: Vec<RunSummary>,
+    pub page: u32,
+    pub per_page: u32,
+    pub total: u64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct UsageQuery {
+    /// Only runs started at or after this time.
+    pub from: Option<DateTime<Utc>>,
+    /// Only runs started before this time.
+    pub to: Option<DateTime<Utc>>,
+}
+
+// Cumulative usage of one API key.
+#[derive(Serialize, Debug)]
+pub struct KeyUsage {
+    pub api_key_id: String,
+    pub runs: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+    pub cost_usd: f64,
+}
@@ -0,0 +1,482 @@
+use crate::types::tfetch::{FetchedPage, PageMetadata, Validators};
+use ego_tree::NodeId;
+use regex::Regex;
+use scraper::{ElementRef, Html, Node, Selector};
+use std::collections::HashMap;
+use std::sync::LazyLock;
+use url::Url;
+
+// Never readable content, wherever they are.
+const SKIPPED_TAGS: &[&str] = &[
+    "script", "style", "noscript", "template", "svg", "canvas", "iframe", "object", "embed",
+    "form", "button", "input", "select", "textarea", "dialog", "nav", "aside", "footer",
+];
+
+// Below this much text the best candidate is probably a teaser, so the whole body is used.
+const MIN_CONTENT_CHARS: usize = 250;
+
+// Elements nested deeper than this are flattened to their text. Converting recurses, and a
+// page nested thousands of levels deep would otherwise overflow the stack.
+const MAX_DEPTH: usize = 128;
+
+// Class and id hints, as used by Readability.
+static UNLIKELY: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)comment|sidebar|footer|footnote|masthead|menu|\bnav|related|share|social|sponsor|promo|advert|\bads?\b|cookie|banner|popup|modal|newsletter|subscribe|breadcrumb|pagination|widget").unwrap()
+});
+static LIKELY: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)article|body|content|entry|main|page|post|text|blog|story").unwrap()
+});
+
+pub struct HtmlUtils;
+
+impl HtmlUtils {
+    // The main content of a page as Markdown, with its title and metadata. Navigation, ads,
+    // comments and the like are left out. Links and images are made absolute against `url`.
+
+    pub fn extract(url: &str, html: &str) -> FetchedPage {
+        let document = Html::parse_document(html);
+        let base = Url::parse(url).ok();
+
+        let content = main_content(&document)
+            .or_else(|| document.select(&selector("body")).next())
+            .unwrap_or_else(|| document.root_element());
+        let markdown = Markdown { base: base.as_ref() }.convert(content);
+
+        FetchedPage {
+            url: url.to_string(),
+            markdown,
+            title: meta(&document, "og:title")
+                .or_else(|| meta(&document, "twitter:title"))
+                .or_else(|| first_text(&document, "title"))
+                .or_else(|| first_text(&document, "h1")),
+            metadata: PageMetadata {
+                description: meta(&document, "description")
+                    .or_else(|| meta(&document, "og:description")),
+                author: meta(&document, "author").or_else(|| meta(&document, "article:author")),
+                site_name: meta(&document, "og:site_name"),
+                published: meta(&document, "article:published_time")
+                    .or_else(|| meta(&document, "date")),
+                language: document
+                    .root_element()
+                    .attr("lang")
+                    .map(|l| l.trim().to_string())
+                    .filter(|l| !l.is_empty()),
+                canonical_url: document
+                    .select(&selector(r#"link[rel="canonical"]"#))
+                    .next()
+                    .and_then(|link| link.attr("href"))
+                    .and_then(|href| absolute(base.as_ref(), href)),
+            },
+            cached: None,
+            validators: Validators::default(),
+        }
+    }
+}
+
+fn selector(css: &str) -> Selector {
+    Selector::parse(css).unwrap()
+}
+
+// A `<meta>` tag's content, looked up by `name` or `property`.
+fn meta(document: &Html, name: &str) -> Option<String> {
+    let css = format!(r#"meta[name="{0}"], meta[property="{0}"]"#, name);
+    document
+        .select(&selector(&css))
+        .filter_map(|m| m.attr("content"))
+        .map(|c| collapse_whitespace(c).trim().to_string())
+        .find(|c| !c.is_empty())
+}
+
+fn first_text(document: &Html, css: &str) -> Option<String> {
+    document
+        .select(&selector(css))
+        .map(|e| collapse_whitespace(&e.text().collect::<String>()).trim().to_string())
+        .find(|t| !t.is_empty())
+}
+
+fn absolute(base: Option<&Url>, href: &str) -> Option<String> {
+    let href = href.trim();
+    if href.is_empty() || href.starts_with('#') || href.starts_with("javascript:") {
+        return None;
+    }
+    match base {
+        Some(base) => base.join(href).ok().map(String::from),
+        None => Url::parse(href).ok().map(String::from),
+    }
+}
+
+// Readability style scoring: every paragraph scores its parent in full and its grandparent
+// by half, for its length and commas. The best scoring element, discounted for how much of
+// it is links, is taken to be the content.
+fn main_content(document: &Html) -> Option<ElementRef<'_>> {
+    let mut scores: HashMap<NodeId, f64> = HashMap::new();
+
+    for paragraph in document.select(&selector("p, pre, td")) {
+        if is_skipped(paragraph) {
+            continue;
+        }
+        let text = paragraph.text().collect::<String>();
+        let text = text.trim();
+        if text.chars().count() < 25 {
+            continue;
+        }
+        let score = 1.0 + text.matches(',').count() as f64 + (text.len() as f64 / 100.0).min(3.0);
+
+        let ancestors = paragraph.ancestors().filter_map(ElementRef::wrap).take(2);
+        for (level, ancestor) in ancestors.enumerate() {
+            let entry = scores
+                .entry(ancestor.id())
+                .or_insert_with(|| initial_score(ancestor));
+            *entry += if level == 0 { score } else { score / 2.0 };
+        }
+    }
+
+    let best = scores
+        .into_iter()
+        .filter_map(|(id, score)| {
+            let element = ElementRef::wrap(document.tree.get(id)?)?;
+            Some((element, score * (1.0 - link_density(element))))
+        })
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(element, _)| element)?;
+
+    let text_len = best.text().map(str::len).sum::<usize>();
+    (text_len >= MIN_CONTENT_CHARS).then_some(best)
+}
+
+fn initial_score(element: ElementRef<'_>) -> f64 {
+    let tag = match element.value().name() {
+        "article" | "main" => 10.0,
+        "div" => 5.0,
+        "pre" | "td" | "blockquote" => 3.0,
+        "address" | "ol" | "ul" | "dl" | "dd" | "dt" | "li" | "form" => -3.0,
+        "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "th" => -5.0,
+        _ => 0.0,
+    };
+    tag + class_weight(element)
+}
+
+fn class_weight(element: ElementRef<'_>) -> f64 {
+    let hints = format!(
+        "{} {}",
+        element.value().attr("class").unwrap_or_default(),
+        element.value().id().unwrap_or_default()
+    );
+    let mut weight = 0.0;
+    if UNLIKELY.is_match(&hints) {
+        weight -= 25.0;
+    }
+    if LIKELY.is_match(&hints) {
+        weight += 25.0;
+    }
+    weight
+}
+
+// Share of an element's text that sits inside links.
+fn link_density(element: ElementRef<'_>) -> f64 {
+    let total = element.text().map(str::len).sum::<usize>();
+    if total == 0 {
+        return 0.0;
+    }
+    let links = element
+        .select(&selector("a"))
+        .flat_map(|a| a.text())
+        .map(str::len)
+        .sum::<usize>();
+    links as f64 / total as f64
+}
+
+// Whether an element, and everything in it, stays out of the content.
+fn is_skipped(element: ElementRef<'_>) -> bool {
+    let value = element.value();
+    if SKIPPED_TAGS.contains(&value.name())
+        || value.attr("hidden").is_some()
+        || value.attr("aria-hidden") == Some("true")
+        || value
+            .attr("style")
+            .is_some_and(|s| s.replace(' ', "").contains("display:none"))
+    {
+        return true;
+    }
+    if matches!(value.name(), "html" | "body" | "article" | "main") {
+        return false;
+    }
+    let hints = format!(
+        "{} {}",
+        value.attr("class").unwrap_or_default(),
+        value.id().unwrap_or_default()
+    );
+    UNLIKELY.is_match(&hints) && !LIKELY.is_match(&hints)
+}
+
+fn collapse_whitespace(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut in_space = false;
+    for c in text.chars() {
+        if c.is_whitespace() {
+            if !in_space {
+                out.push(' ');
+            }
+            in_space = true;
+        } else {
+            out.push(c);
+            in_space = false;
+        }
+    }
+    out
+}
+
+fn one_line(text: &str) -> String {
+    collapse_whitespace(text).trim().to_string()
+}
+
+// Blocks are surrounded by blank lines, squeezed down to one when the document is finished.
+fn block(content: &str) -> String {
+    let content = content.trim_matches('\n');
+    if content.trim().is_empty() {
+        String::new()
+    } else {
+        format!("\n\n{}\n\n", content)
+    }
+}
+
+// Paragraph text with the whitespace around `<br>` line breaks tidied up.
+fn trim_lines(text: &str) -> String {
+    text.lines()
+        .map(str::trim)
+        .collect::<Vec<_>>()
+        .join("\n")
+        .trim()
+        .to_string()
+}
+
+// Emphasis hugs its text, Markdown won't render `** bold **`.
+fn wrap_inline(text: &str, marker: &str) -> String {
+    let inner = text.trim();
+    if inner.is_empty() {
+        return text.to_string();
+    }
+    let lead = if text.starts_with(' ') { " " } else { "" };
+    let trail = if text.ends_with(' ') { " " } else { "" };
+    format!("{}{}{}{}{}", lead, marker, inner, marker, trail)
+}
+
+struct Markdown<'a> {
+    base: Option<&'a Url>,
+}
+
+impl Markdown<'_> {
+    fn convert(&self, root: ElementRef<'_>) -> String {
+        let raw = self.children(root, 0);
+
+        // Squeeze blank lines and stray spaces, except inside code blocks.
+        let mut out: Vec<&str> = Vec::new();
+        let mut in_code = false;
+        for line in raw.lines() {
+            if line.starts_with("```") {
+                in_code = !in_code;
+            }
+            let line = if in_code { line } else { line.trim_end() };
+            if !in_code && line.is_empty() && out.last().is_none_or(|l| l.is_empty()) {
+                continue;
+            }
+            out.push(line);
+        }
+        out.join("\n").trim().to_string()
+    }
+
+    fn children(&self, element: ElementRef<'_>, depth: usize) -> String {
+        let mut out = String::new();
+        for child in element.children() {
+            match child.value() {
+                Node::Text(text) => {
+                    let text = collapse_whitespace(text);
+                    // Whitespace at the start of a line is only indentation from the HTML source.
+                    if out.is_empty() || out.ends_with('\n') {
+                        out.push_str(text.trim_start());
+                    } else {
+                        out.push_str(&text);
+                    }
+                }
+                Node::Element(_) => {
+                    if let Some(child) = ElementRef::wrap(child) {
+                        out.push_str(&self.element(child, depth + 1));
+                    }
+                }
+                _ => {}
+            }
+        }
+        out
+    }
+
+    fn element(&self, element: ElementRef<'_>, depth: usize) -> String {
+        if is_skipped(element) {
+            return String::new();
+        }
+        if depth > MAX_DEPTH {
+            return collapse_whitespace(&element.text().collect::<String>());
+        }
+
+        let tag = element.value().name();
+        match tag {
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                let level = tag[1..].parse().unwrap_or(1);
+                let text = one_line(&self.children(element, depth));
+                if text.is_empty() {
+                    String::new()
+                } else {
+                    block(&format!("{} {}", "#".repeat(level), text))
+                }
+            }
+            "p" => block(&trim_lines(&self.children(element, depth))),
+            "br" => "\n".to_string(),
+            "hr" => block("---"),
+            "strong" | "b" => wrap_inline(&self.children(element, depth), "**"),
+            "em" | "i" => wrap_inline(&self.children(element, depth), "*"),
+            "code" | "kbd" | "samp" => {
+                let code = element.text().collect::<String>();
+                if code.trim().is_empty() {
+                    String::new()
+                } else {
+                    format!("`{}`", one_line(&code))
+                }
+            }
+            "pre" => {
+                let code = element.text().collect::<String>();
+                block(&format!("```\n{}\n```", code.trim_matches('\n')))
+            }
+            "a" => {
+                let text = self.children(element, depth);
+                match element.attr("href").and_then(|href| absolute(self.base, href)) {
+                    Some(href) if !text.trim().is_empty() => {
+                        format!("[{}]({})", one_line(&text), href)
+                    }
+                    _ => text,
+                }
+            }
+            "img" => {
+                let alt = one_line(element.attr("alt").unwrap_or_default());
+                match element.attr("src").and_then(|src| absolute(self.base, src)) {
+                    Some(src) if !alt.is_empty() => format!("![{}]({})", alt, src),
+                    _ => String::new(),
+                }
+            }
+            "ul" | "ol" => block(&self.list(element, tag == "ol", depth)),
+            "blockquote" => {
+                let quoted = self.children(element, depth);
+                let quoted = self.tidy(&quoted);
+                block(
+                    &quoted
+                        .lines()
+                        .map(|line| format!("> {}", line).trim_end().to_string())
+                        .collect::<Vec<_>>()
+                        .join("\n"),
+                )
+            }
+            "table" => block(&self.table(element, depth)),
+            "div" | "section" | "article" | "main" | "header" | "figure" | "figcaption"
+            | "address" | "details" | "summary" | "dl" | "dt" | "dd" | "li" | "tr" => {
+                block(&self.children(element, depth))
+            }
+            _ => self.children(element, depth),
+        }
+    }
+
+    // Converted content with blank lines squeezed, for nesting inside quotes and list items.
+    fn tidy(&self, text: &str) -> String {
+        let mut out: Vec<&str> = Vec::new();
+        for line in text.trim().lines() {
+            let line = line.trim_end();
+            if line.is_empty() && out.last().is_none_or(|l| l.is_empty()) {
+                continue;
+            }
+            out.push(line);
+        }
+        out.join("\n")
+    }
+
+    fn list(&self, list: ElementRef<'_>, ordered: bool, depth: usize) -> String {
+        let start: u32 = list.attr("start").and_then(|s| s.parse().ok()).unwrap_or(1);
+        let list_items = list.child_elements().filter(|e| e.value().name() == "li");
+        let mut items = Vec::new();
+
+        for (number, item) in (start..).zip(list_items) {
+            let marker = if ordered {
+                format!("{}. ", number)
+            } else {
+                "- ".to_string()
+            };
+
+            // Tight lists: nested blocks go on the following lines, indented under the marker.
+            let body = self.children(item, depth + 1);
+            let body = self.tidy(&body);
+            let mut lines = body.lines().filter(|l| !l.is_empty());
+            let Some(first) = lines.next() else {
+                continue;
+            };
+            let indent = " ".repeat(marker.len());
+            let mut rendered = format!("{}{}", marker, first.trim_start());
+            for line in lines {
+                rendered.push('\n');
+                rendered.push_str(&indent);
+                rendered.push_str(line);
+            }
+            items.push(rendered);
+        }
+        items.join("\n")
+    }
+
+    fn table(&self, table: ElementRef<'_>, depth: usize) -> String {
+        let rows: Vec<Vec<String>> = table
+            .select(&selector("tr"))
+            .map(|row| {
+                row.child_elements()
+                    .filter(|cell| matches!(cell.value().name(), "td" | "th"))
+                    .map(|cell| one_line(&self.children(cell, depth + 1)).replace('|', "\\|"))
+                    .collect::<Vec<_>>()
+            })
+            .filter(|cells| cells.iter().any(|c| !c.is_empty()))
+            .collect();
+
+        let width = rows.iter().map(Vec::len).max().unwrap_or(0);
+        if width == 0 {
+            return String::new();
+        }
+        // A single column table is layout, not data.
+        if width == 1 {
+            return rows.into_iter().flatten().collect::<Vec<_>>().join("\n\n");
+        }
+
+        let line = |cells: &[String]| {
+            let mut padded = cells.to_vec();
+            padded.resize(width, String::new());
+            format!("| {} |", padded.join(" | "))
+        };
+        let mut out = vec![line(&rows[0]), format!("|{}", " --- |".repeat(width))];
+        out.extend(rows[1..].iter().map(|row| line(row)));
+        out.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deep_nesting_is_flattened() {
+        let depth = 2_000;
+        let html = format!(
+            "<html><body>{}<p>Deep text</p>{}</body></html>",
+            "<div><span>".repeat(depth),
+            "</span></div>".repeat(depth)
+        );
+        // Worker threads get 2 MiB of stack, which unbounded recursion runs through well before this.
+        let page = std::thread::Builder::new()
+            .stack_size(2 * 1024 * 1024)
+            .spawn(move || HtmlUtils::extract("http://example.com/", &html))
+            .unwrap()
+            .join()
+            .unwrap();
+        assert_eq!(page.markdown, "Deep text");
+    }
+}
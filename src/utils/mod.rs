@@ -1 +1,3 @@
+pub mod htmlutils;
+pub mod schemautils;
 pub mod webutils;
@@ -0,0 +1,32 @@
+use jsonschema::Validator;
+use serde_json::Value;
+
+pub struct SchemaUtils;
+
+impl SchemaUtils {
+    // Compile a caller supplied schema. Structured output needs an object at the root.
+
+    pub fn compile(schema: &Value) -> Result<Validator, String> {
+        if schema.get("type").and_then(Value::as_str) != Some("object") {
+            return Err("Invalid json_schema: the root must be an object schema".to_string());
+        }
+
+        jsonschema::validator_for(schema).map_err(|e| format!("Invalid json_schema: {}", e))
+    }
+
+    // Every way the instance fails the schema, in a form we can hand back to a model.
+
+    pub fn errors(validator: &Validator, instance: &Value) -> Vec<String> {
+        validator
+            .iter_errors(instance)
+            .map(|e| {
+                let path = e.instance_path.to_string();
+                if path.is_empty() {
+                    e.to_string()
+                } else {
+                    format!("{} (at {})", e, path)
+                }
+            })
+            .collect()
+    }
+}
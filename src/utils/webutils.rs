@@ -1,14 +1,20 @@
-use crate::config::EnvConfig;
+use crate::modules::keys::KeyStore;
+use crate::modules::limits::Limited;
 use crate::response;
+use crate::types::tchoir::ChoirRequest;
+use crate::types::terror::{ChoirError, ErrorCode};
+use crate::types::tkey::{ApiKey, Scope};
+use actix_web::http::StatusCode;
 use actix_web::{web, HttpRequest, HttpResponse};
-use std::sync::Arc;
-use urlencoding;
+use log::error;
+
+// Lets callers that can't change the body set the run's deadline.
+const TIMEOUT_HEADER: &str = "X-Timeout-Ms";
 
 pub struct WebUtils;
 
 impl WebUtils {
     // Extract the API key from the request headers
-
     pub fn extract_api_key(req: &HttpRequest) -> Option<String> {
         req.headers()
             .get("Authorization")
@@ -21,35 +27,103 @@ impl WebUtils {
             .map(|s| s.to_string())
     }
 
-    // Check the api key against the configured api key.
+    // Look the bearer token up in the key store. None unless it belongs to an active key.
+    pub fn check_api_key(req: &HttpRequest) -> Option<ApiKey> {
+        let store = req.app_data::<web::Data<KeyStore>>()?;
+        let token = Self::extract_api_key(req)?;
 
-    pub fn check_api_key(req: &HttpRequest) -> bool {
-        let cfg = match req.app_data::<web::Data<Arc<EnvConfig>>>() {
-            Some(cfg) => cfg.get_ref().clone(),
-            None => return false,
-        };
+        match store.authenticate(&token) {
+            Ok(key) => key.filter(ApiKey::is_active),
+            Err(e) => {
+                error!("Failed to check API key: {}", e);
+                None
+            }
+        }
+    }
 
-        let token = match Self::extract_api_key(req) {
-            Some(t) => t,
-            None => return false,
+    // 401 without an active key, 403 when the key lacks `scope`.
+    pub fn require_api_key(req: &HttpRequest, scope: Option<Scope>) -> Result<ApiKey, HttpResponse> {
+        let Some(key) = WebUtils::check_api_key(req) else {
+            return Err(
+                HttpResponse::Unauthorized().json(
+                    response::make_query_response::<()>(false, None, Some("Unauthorized"), None)
+                        .with_code(ErrorCode::Unauthorized),
+                ),
+            );
         };
 
-        token == cfg.api_key
+        if let Some(scope) = scope {
+            if !key.allows(scope) {
+                return Err(HttpResponse::Forbidden().json(
+                    response::make_query_response::<()>(false, None, Some("Forbidden"), None)
+                        .with_code(ErrorCode::Forbidden),
+                ));
+            }
+        }
+        Ok(key)
     }
 
-    pub fn require_api_key(req: &HttpRequest) -> Option<HttpResponse> {
-        if WebUtils::check_api_key(req) {
-            None
-        } else {
-            Some(
-                HttpResponse::Unauthorized().json(response::make_query_response::<()>(
-                    false,
-                    None,
-                    Some("Unauthorized"),
-                    None,
-                )),
+    pub fn too_many_requests(limited: &Limited) -> HttpResponse {
+        HttpResponse::TooManyRequests()
+            .insert_header(("Retry-After", limited.retry_after_secs.to_string()))
+            .json(
+                response::make_query_response::<()>(false, None, Some(&limited.message), None)
+                    .with_code(ErrorCode::RateLimited),
             )
+    }
+
+    // Fill in the run's `timeout_ms` from the `X-Timeout-Ms` header when the body doesn't set one.
+    pub fn apply_timeout_header(
+        req: &HttpRequest,
+        request: &mut ChoirRequest,
+    ) -> Result<(), ChoirError> {
+        let Some(value) = req.headers().get(TIMEOUT_HEADER) else {
+            return Ok(());
+        };
+        let timeout_ms = value
+            .to_str()
+            .ok()
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .ok_or_else(|| {
+                ChoirError::BadRequest(format!("{} must be a number of milliseconds", TIMEOUT_HEADER))
+            })?;
+        request.timeout_ms = request.timeout_ms.or(Some(timeout_ms));
+        Ok(())
+    }
+
+    // The status, code and public message for a failed run.
+    pub fn error_response(error: &ChoirError) -> HttpResponse {
+        let status = match error {
+            ChoirError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ChoirError::UrlBlocked { .. } => StatusCode::FORBIDDEN,
+            ChoirError::SchemaInvalid(_)
+            | ChoirError::BudgetExceeded(_)
+            | ChoirError::ContextOverflow(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ChoirError::Provider { .. }
+            | ChoirError::FetchFailed { .. }
+            | ChoirError::QuorumNotMet(_) => StatusCode::BAD_GATEWAY,
+            ChoirError::UpstreamRateLimited { .. } | ChoirError::CircuitOpen { .. } => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            ChoirError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            ChoirError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+
+        let mut response = HttpResponse::build(status);
+        // The provider's own hint, or when the circuit half-opens, rounded up to whole seconds.
+        if let Some(retry_after) = error.retry_after() {
+            let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            response.insert_header(("Retry-After", secs.to_string()));
         }
+        response.json(response::make_error_response(error))
+    }
+
+    // Turn an extractor failure into a 400 carrying our envelope instead of actix's plain text.
+    pub fn bad_request(
+        error: impl std::fmt::Display + std::fmt::Debug + 'static,
+    ) -> actix_web::Error {
+        let response = Self::error_response(&ChoirError::BadRequest(error.to_string()));
+        actix_web::error::InternalError::from_response(error, response).into()
     }
 
     #[allow(dead_code)]